@@ -0,0 +1,108 @@
+#ifndef JEPROF_H
+#define JEPROF_H
+
+/* Generated by cbindgen from src/ffi.rs. Do not edit by hand. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * A parsed profile.
+ */
+typedef struct JeprofProfile JeprofProfile;
+
+/**
+ * The counters of a `t*` row.
+ */
+typedef struct JeprofMetrics {
+  uint64_t inuse_count;
+  uint64_t inuse_space;
+  uint64_t alloc_count;
+  uint64_t alloc_space;
+} JeprofMetrics;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Parses the `len` bytes at `data` as [`Profile::parse_bytes`] does,
+ * returning null when they do not hold a profile.
+ *
+ * # Safety
+ *
+ * `data` must point to `len` readable bytes, or may be null when `len` is
+ * zero.
+ */
+struct JeprofProfile *jeprof_parse(const uint8_t *data, uintptr_t len);
+
+/**
+ * Frees a profile returned by [`jeprof_parse`]. Null is ignored.
+ *
+ * # Safety
+ *
+ * `profile` must be null or a handle from [`jeprof_parse`] that has not
+ * been freed yet.
+ */
+void jeprof_free(struct JeprofProfile *profile);
+
+/**
+ * The sampling rate from the header.
+ *
+ * # Safety
+ *
+ * `profile` must be a live handle from [`jeprof_parse`].
+ */
+uint64_t jeprof_sampling_rate(const struct JeprofProfile *profile);
+
+/**
+ * Writes the `t*` totals to `out`, returning false, and leaving `out`
+ * untouched, when the totals block has no `t*` row.
+ *
+ * # Safety
+ *
+ * `profile` must be a live handle from [`jeprof_parse`] and `out` must be
+ * valid for writes.
+ */
+bool jeprof_totals(const struct JeprofProfile *profile, struct JeprofMetrics *out);
+
+/**
+ * The number of stacks.
+ *
+ * # Safety
+ *
+ * `profile` must be a live handle from [`jeprof_parse`].
+ */
+uintptr_t jeprof_stack_count(const struct JeprofProfile *profile);
+
+/**
+ * The addresses of stack `index`, leaf first, with their number written to
+ * `len`. Returns null, with `len` set to zero, when `index` is out of
+ * range. The addresses live as long as the profile.
+ *
+ * # Safety
+ *
+ * `profile` must be a live handle from [`jeprof_parse`] and `len` must be
+ * valid for writes.
+ */
+const uint64_t *jeprof_stack_addrs(const struct JeprofProfile *profile, uintptr_t index, uintptr_t *len);
+
+/**
+ * Writes the `t*` row of stack `index` to `out`, returning false, and
+ * leaving `out` untouched, when `index` is out of range or the stack has no
+ * `t*` row.
+ *
+ * # Safety
+ *
+ * `profile` must be a live handle from [`jeprof_parse`] and `out` must be
+ * valid for writes.
+ */
+bool jeprof_stack_metrics(const struct JeprofProfile *profile, uintptr_t index, struct JeprofMetrics *out);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* JEPROF_H */
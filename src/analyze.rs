@@ -0,0 +1,160 @@
+//! Analyses spanning several profiles.
+
+use std::time::Duration;
+
+use crate::diff::summed_stacks;
+use crate::{Profile, ThreadId};
+
+/// Allocation rates between consecutive profiles, see [`allocation_rate`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct RateReport {
+    /// One entry per pair of consecutive profiles, in order.
+    pub intervals: Vec<IntervalRate>,
+}
+
+/// Allocation rates over one interval between two dumps.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IntervalRate {
+    pub duration: Duration,
+    /// Bytes allocated per second across the whole process, from the `t*`
+    /// totals rows.
+    pub bytes_per_sec: f64,
+    /// Per-stack rates, highest first. Stacks that allocated nothing during
+    /// the interval are left out.
+    pub stacks: Vec<StackRate>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StackRate {
+    pub addrs: Vec<u64>,
+    pub bytes_per_sec: f64,
+}
+
+/// Turns periodic snapshots into allocation rates.
+///
+/// `alloc_space` is cumulative, so the bytes a stack allocated between two
+/// consecutive dumps is the growth of its `t*` `alloc_space`; dividing by
+/// the matching entry of `intervals` gives bytes per second. Stacks are
+/// matched by their addresses, and a stack missing from the earlier dump
+/// counts as having started from zero. `intervals[i]` is the time between
+/// `profiles[i]` and `profiles[i + 1]`; extra entries on either side are
+/// ignored. An interval of zero length reports zero rates.
+///
+/// Rates are only meaningful for dumps taken with `prof_accum` enabled,
+/// since `alloc_space` is otherwise always zero.
+pub fn allocation_rate(profiles: &[Profile], intervals: &[Duration]) -> RateReport {
+    let intervals = profiles
+        .windows(2)
+        .zip(intervals)
+        .map(|(pair, duration)| interval_rate(&pair[0], &pair[1], *duration))
+        .collect();
+
+    RateReport { intervals }
+}
+
+fn interval_rate(before: &Profile, after: &Profile, duration: Duration) -> IntervalRate {
+    let seconds = duration.as_secs_f64();
+    let rate = |bytes: u64| if seconds > 0.0 { bytes as f64 / seconds } else { 0.0 };
+
+    let alloc_space = |profile: &Profile| profile.totals_for(ThreadId::Total).map_or(0, |thread| thread.alloc_space);
+    let bytes_per_sec = rate(alloc_space(after).saturating_sub(alloc_space(before)));
+
+    // Repeated stacks are summed on both sides, as in `Profile::diff`.
+    let (_, previous) = summed_stacks(before);
+    let (_, current) = summed_stacks(after);
+
+    let mut stacks: Vec<StackRate> = current
+        .into_iter()
+        .filter_map(|(key, metrics)| {
+            let before = previous.get(&key).map_or(0, |metrics| metrics.alloc_space);
+            let allocated = metrics.alloc_space.saturating_sub(before);
+            (allocated > 0).then(|| StackRate {
+                addrs: key.0.to_vec(),
+                bytes_per_sec: rate(allocated),
+            })
+        })
+        .collect();
+    stacks.sort_by(|a, b| b.bytes_per_sec.total_cmp(&a.bytes_per_sec).then_with(|| a.addrs.cmp(&b.addrs)));
+
+    IntervalRate {
+        duration,
+        bytes_per_sec,
+        stacks,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allocation_rate() {
+        let data = "heap_v2/524288
+  t*: 1: 16 [10: 1000]
+@ 0x1
+  t*: 1: 16 [10: 1000]
+heap_v2/524288
+  t*: 1: 16 [30: 3000]
+@ 0x1
+  t*: 1: 16 [15: 1500]
+@ 0x2
+  t*: 0: 0 [15: 1500]
+heap_v2/524288
+  t*: 1: 16 [30: 3000]
+@ 0x1
+  t*: 1: 16 [15: 1500]
+@ 0x2
+  t*: 0: 0 [15: 1500]
+";
+        let profiles = Profile::parse_all(data).unwrap();
+        let report = allocation_rate(&profiles, &[Duration::from_secs(10), Duration::from_secs(10)]);
+        assert_eq!(2, report.intervals.len());
+
+        let first = &report.intervals[0];
+        assert_eq!(200.0, first.bytes_per_sec);
+        assert_eq!(
+            vec![
+                StackRate { addrs: vec![2], bytes_per_sec: 150.0 },
+                StackRate { addrs: vec![1], bytes_per_sec: 50.0 },
+            ],
+            first.stacks
+        );
+
+        let second = &report.intervals[1];
+        assert_eq!(0.0, second.bytes_per_sec);
+        assert!(second.stacks.is_empty());
+
+        let report = allocation_rate(&profiles, &[Duration::ZERO]);
+        assert_eq!(1, report.intervals.len());
+        assert!(report.intervals[0].stacks.iter().all(|stack| stack.bytes_per_sec == 0.0));
+    }
+
+    #[test]
+    fn test_allocation_rate_repeated_stack() {
+        let data = "heap_v2/524288
+  t*: 1: 16 [20: 2000]
+@ 0x1
+  t*: 1: 16 [10: 1000]
+@ 0x1
+  t*: 0: 0 [10: 1000]
+heap_v2/524288
+  t*: 1: 16 [35: 3500]
+@ 0x1
+  t*: 1: 16 [15: 1500]
+@ 0x2
+  t*: 0: 0 [5: 500]
+@ 0x1
+  t*: 0: 0 [15: 1500]
+";
+        let profiles = Profile::parse_all(data).unwrap();
+        let report = allocation_rate(&profiles, &[Duration::from_secs(10)]);
+
+        assert_eq!(
+            vec![
+                StackRate { addrs: vec![1], bytes_per_sec: 100.0 },
+                StackRate { addrs: vec![2], bytes_per_sec: 50.0 },
+            ],
+            report.intervals[0].stacks
+        );
+    }
+}
@@ -0,0 +1,73 @@
+//! Reading a profile from an async source, behind the `async` feature.
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::{OwnedProfile, ParseError, Profile};
+
+impl Profile<'static> {
+    /// Reads `reader` to the end without blocking, then parses what it
+    /// returned.
+    ///
+    /// Only the reading is asynchronous; parsing runs synchronously once the
+    /// whole body has arrived, with [`Profile::parse_bytes`]. Read failures
+    /// are reported as [`ParseError::Io`].
+    pub async fn from_async_reader<R: AsyncRead + Unpin>(mut reader: R) -> Result<OwnedProfile, ParseError> {
+        let mut buffer = Vec::new();
+        reader.read_to_end(&mut buffer).await.map_err(|err| ParseError::Io(err.kind()))?;
+
+        Profile::parse_bytes(&buffer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::Future;
+    use std::pin::pin;
+    use std::sync::Arc;
+    use std::task::{Context, Poll, Wake, Waker};
+
+    use super::*;
+    use crate::ThreadId;
+
+    struct NoopWaker;
+
+    impl Wake for NoopWaker {
+        fn wake(self: Arc<Self>) {}
+    }
+
+    /// Drives a future whose I/O is always ready, such as reading a slice.
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let waker = Waker::from(Arc::new(NoopWaker));
+        let mut context = Context::from_waker(&waker);
+        let mut future = pin!(future);
+
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+                return output;
+            }
+        }
+    }
+
+    #[test]
+    fn test_from_async_reader() {
+        let data = b"heap_v2/524288
+  t*: 1: 16 [0: 0]
+  tworker: 1: 16 [0: 0]
+@ 0x1
+  t*: 1: 16 [0: 0]
+
+MAPPED_LIBRARIES:
+00001000-00002000 r-xp 00000000 103:02 5000 /usr/lib/liba.so
+";
+        let profile = block_on(Profile::from_async_reader(&data[..])).unwrap();
+        assert_eq!(524288, profile.sampling_rate);
+        assert_eq!(ThreadId::Named("worker".into()), profile.totals[1].id);
+        assert_eq!("/usr/lib/liba.so", profile.mapped_libraries[0].path);
+
+        let invalid = b"heap_v2/524288\n  t*: 1: 16 [0: 0]\n@ 0x1\xff\n";
+        assert_eq!(
+            Err(ParseError::Malformed { line: 3 }),
+            block_on(Profile::from_async_reader(&invalid[..])).map(|profile| profile.sampling_rate)
+        );
+    }
+}
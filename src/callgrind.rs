@@ -0,0 +1,203 @@
+//! The callgrind format read by KCachegrind and QCachegrind.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::Write;
+
+use crate::folded::basename;
+use crate::{CallNode, CallTree, FrameKey, Metric, Profile, RangeIndex, SymbolizedProfile};
+
+impl<'a> Profile<'a> {
+    /// Renders the [`CallTree`] in the callgrind format, with `metric` as the
+    /// cost, for browsing callers and callees in KCachegrind.
+    ///
+    /// Functions are named `library+offset`, the basename of the mapped
+    /// library followed by the file offset of the address, or the full path
+    /// where two libraries share a basename; addresses outside
+    /// every library are written in hex. Use
+    /// [`SymbolizedProfile::to_callgrind`] for function names.
+    pub fn to_callgrind(&self, metric: Metric) -> String {
+        let namer = LibraryOffsetNamer::new(self);
+        callgrind(&self.call_tree(), metric, |node| namer.name(node.addr))
+    }
+}
+
+/// Names the [`FrameKey`] of an address as `library+offset`, or in hex
+/// outside every library. Libraries are named by basename, unless a library
+/// at another path shares it, so that distinct keys always get distinct
+/// names.
+///
+/// Built once per export, so that each frame costs a binary search rather
+/// than a pass over the mapped libraries.
+pub(crate) struct LibraryOffsetNamer<'p, 'a> {
+    profile: &'p Profile<'a>,
+    libraries: RangeIndex<usize>,
+    /// Basenames shared by libraries at different paths.
+    ambiguous: HashSet<&'p str>,
+}
+
+impl<'p, 'a> LibraryOffsetNamer<'p, 'a> {
+    pub(crate) fn new(profile: &'p Profile<'a>) -> Self {
+        let mut paths: HashMap<&str, &str> = HashMap::new();
+        let mut ambiguous = HashSet::new();
+        for lib in &profile.mapped_libraries {
+            let name = basename(&lib.path);
+            if *paths.entry(name).or_insert(&lib.path) != lib.path {
+                ambiguous.insert(name);
+            }
+        }
+
+        LibraryOffsetNamer {
+            profile,
+            libraries: profile.library_ranges(),
+            ambiguous,
+        }
+    }
+
+    pub(crate) fn name(&self, addr: u64) -> String {
+        match self.profile.frame_key_in(&self.libraries, addr) {
+            FrameKey::Library { library, offset } => {
+                let path = &self.profile.mapped_libraries[library].path;
+                let name = basename(path);
+                format!("{}+{:#x}", if self.ambiguous.contains(name) { path } else { name }, offset)
+            }
+            FrameKey::Absolute(addr) => format!("{:#x}", addr),
+        }
+    }
+}
+
+impl<'p, 'a> SymbolizedProfile<'p, 'a> {
+    /// Like [`Profile::to_callgrind`], naming functions by symbol where the
+    /// address resolved and by `library+offset` otherwise.
+    pub fn to_callgrind(&self, metric: Metric) -> String {
+        let profile = self.profile();
+        let namer = LibraryOffsetNamer::new(profile);
+        callgrind(&profile.call_tree(), metric, |node| match self.function_at(node.addr, node.children.is_empty()) {
+            Some(function) => function.to_string(),
+            None => namer.name(node.addr),
+        })
+    }
+}
+
+#[derive(Default)]
+struct Function {
+    self_cost: u64,
+    /// Number of call paths and inclusive cost per callee.
+    calls: BTreeMap<String, (u64, u64)>,
+}
+
+/// Aggregates the nodes of `tree` per function name, as callgrind records
+/// costs per function rather than per call path. The `calls` count of an
+/// edge is the number of distinct call paths through it; heap profiles do
+/// not record how often a call was made.
+fn callgrind(tree: &CallTree, metric: Metric, name: impl Fn(&CallNode) -> String) -> String {
+    let names: Vec<String> = tree.nodes().iter().map(&name).collect();
+    let mut functions: BTreeMap<&str, Function> = BTreeMap::new();
+
+    for (node, caller) in tree.nodes().iter().zip(&names) {
+        let function = functions.entry(caller).or_default();
+        function.self_cost = function.self_cost.saturating_add(node.self_metrics.get(metric));
+
+        for child in &node.children {
+            let edge = function.calls.entry(names[*child].clone()).or_default();
+            edge.0 = edge.0.saturating_add(1);
+            edge.1 = edge.1.saturating_add(tree.node(*child).total_metrics.get(metric));
+        }
+    }
+
+    let mut out = format!("# callgrind format\nversion: 1\ncreator: jeprof-rs\nevents: {}\n", metric);
+    for (name, function) in &functions {
+        let _ = write!(out, "\nfn={}\n0 {}\n", name, function.self_cost);
+        for (callee, (calls, cost)) in &function.calls {
+            let _ = write!(out, "cfn={}\ncalls={} 0\n0 {}\n", callee, calls, cost);
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_callgrind() {
+        let data = "heap_v2/524288
+  t*: 3: 48 [0: 0]
+@ 0x1010 0x2010 0x9000
+  t*: 1: 16 [0: 0]
+@ 0x1020 0x2010 0x9000
+  t*: 2: 32 [0: 0]
+
+MAPPED_LIBRARIES:
+00001000-00002000 r-xp 00000000 103:02 5000 /usr/lib/liba.so
+00002000-00003000 r-xp 00004000 103:02 5001 /usr/bin/server
+";
+        let profile = Profile::parse(data).unwrap();
+
+        assert_eq!(
+            "# callgrind format
+version: 1
+creator: jeprof-rs
+events: inuse_space
+
+fn=0x9000
+0 0
+cfn=server+0x4010
+calls=1 0
+0 48
+
+fn=liba.so+0x10
+0 16
+
+fn=liba.so+0x20
+0 32
+
+fn=server+0x4010
+0 0
+cfn=liba.so+0x10
+calls=1 0
+0 16
+cfn=liba.so+0x20
+calls=1 0
+0 32
+",
+            profile.to_callgrind(Metric::InuseSpace)
+        );
+        assert!(profile.to_callgrind(Metric::InuseCount).contains("events: inuse_count\n"));
+        assert!(profile.to_callgrind(Metric::InuseCount).contains("\nfn=liba.so+0x20\n0 2\n"));
+    }
+
+    #[test]
+    fn test_library_offset_namer() {
+        let data = "heap_v2/524288
+  t*: 1: 16 [0: 0]
+@ 0x1010
+  t*: 1: 16 [0: 0]
+
+MAPPED_LIBRARIES:
+00001000-00002000 r-xp 00000000 103:02 5000 /usr/lib/libfoo.so
+00002000-00003000 r-xp 00000000 103:02 5001 /opt/app/libfoo.so
+00003000-00004000 r-xp 00001000 103:02 5001 /opt/app/libfoo.so
+00004000-00005000 r-xp 00000000 103:02 5002 /usr/lib/libbar.so
+";
+        let profile = Profile::parse(data).unwrap();
+        let namer = LibraryOffsetNamer::new(&profile);
+        assert_eq!("/usr/lib/libfoo.so+0x10", namer.name(0x1010));
+        assert_eq!("/opt/app/libfoo.so+0x1010", namer.name(0x3010));
+        assert_eq!("libbar.so+0x10", namer.name(0x4010));
+        assert_eq!("0x9000", namer.name(0x9000));
+    }
+
+    #[test]
+    fn test_to_callgrind_saturates() {
+        let data = "heap_v2/524288
+  t*: 2: 18446744073709551615 [0: 0]
+@ 0x1 0x9
+  t*: 1: 18446744073709551615 [0: 0]
+@ 0x1 0x8 0x9
+  t*: 1: 18446744073709551615 [0: 0]
+";
+        let callgrind = Profile::parse(data).unwrap().to_callgrind(Metric::InuseSpace);
+        assert!(callgrind.contains("\nfn=0x1\n0 18446744073709551615\n"), "{}", callgrind);
+    }
+}
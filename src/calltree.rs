@@ -0,0 +1,196 @@
+//! The stacks of a profile merged into a tree of call paths.
+
+use std::collections::{BTreeMap, HashMap};
+
+use crate::{Metrics, Profile, ThreadId};
+
+/// The stacks of a profile merged root first, so that stacks sharing a
+/// chain of callers share the nodes for it.
+///
+/// Every node holds the `t*` metrics of the stacks ending at it and of all
+/// the stacks passing through it, which makes this the basis for exporters
+/// that need inclusive as well as exclusive costs.
+#[derive(Debug, Clone, Default)]
+pub struct CallTree {
+    nodes: Vec<CallNode>,
+    roots: Vec<usize>,
+}
+
+/// One frame of a [`CallTree`], reached through a unique chain of callers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CallNode {
+    pub addr: u64,
+    /// Index of the calling node, `None` for the outermost frames.
+    pub parent: Option<usize>,
+    /// Indices of the nodes called from this one, in order of first
+    /// appearance.
+    pub children: Vec<usize>,
+    /// Metrics of the stacks whose leaf is this node.
+    pub self_metrics: Metrics,
+    /// Metrics of every stack passing through this node, itself included.
+    pub total_metrics: Metrics,
+}
+
+impl CallTree {
+    /// Builds the tree from the `t*` row of every stack.
+    pub fn new(profile: &Profile) -> Self {
+        let mut tree = CallTree::default();
+        let mut index: HashMap<(Option<usize>, u64), usize> = HashMap::new();
+
+        for stack in &profile.stacks {
+            let Some(thread) = stack.thread(ThreadId::Total) else {
+                continue;
+            };
+            let metrics = thread.metrics();
+
+            let mut parent = None;
+            for addr in stack.addrs.iter().rev() {
+                let id = *index.entry((parent, *addr)).or_insert_with(|| {
+                    let id = tree.nodes.len();
+                    tree.nodes.push(CallNode {
+                        addr: *addr,
+                        parent,
+                        children: Vec::new(),
+                        self_metrics: Metrics::default(),
+                        total_metrics: Metrics::default(),
+                    });
+                    match parent {
+                        Some(parent) => tree.nodes[parent].children.push(id),
+                        None => tree.roots.push(id),
+                    }
+                    id
+                });
+
+                let node = &mut tree.nodes[id];
+                node.total_metrics = node.total_metrics + metrics;
+                parent = Some(id);
+            }
+
+            if let Some(leaf) = parent {
+                let node = &mut tree.nodes[leaf];
+                node.self_metrics = node.self_metrics + metrics;
+            }
+        }
+
+        tree
+    }
+
+    /// Every node, indexed by the ids used in [`CallNode::parent`] and
+    /// [`CallNode::children`].
+    pub fn nodes(&self) -> &[CallNode] {
+        &self.nodes
+    }
+
+    pub fn node(&self, id: usize) -> &CallNode {
+        &self.nodes[id]
+    }
+
+    /// Ids of the outermost frames, in order of first appearance.
+    pub fn roots(&self) -> &[usize] {
+        &self.roots
+    }
+
+    /// The frames that called `frame`, each with the metrics of the stacks
+    /// passing through that call, summed over every node of `frame`.
+    /// Sorted by address.
+    pub fn callers(&self, frame: u64) -> Vec<(u64, Metrics)> {
+        self.edges(frame, |node| match node.parent {
+            Some(parent) => vec![(self.node(parent).addr, node.total_metrics)],
+            None => Vec::new(),
+        })
+    }
+
+    /// The frames called from `frame`, each with the metrics of the stacks
+    /// passing through that call, summed over every node of `frame`.
+    /// Sorted by address.
+    pub fn callees(&self, frame: u64) -> Vec<(u64, Metrics)> {
+        self.edges(frame, |node| {
+            let children = node.children.iter().map(|child| self.node(*child));
+            children.map(|child| (child.addr, child.total_metrics)).collect()
+        })
+    }
+
+    fn edges(&self, frame: u64, adjacent: impl Fn(&CallNode) -> Vec<(u64, Metrics)>) -> Vec<(u64, Metrics)> {
+        let mut edges: BTreeMap<u64, Metrics> = BTreeMap::new();
+        for node in self.nodes.iter().filter(|node| node.addr == frame) {
+            for (addr, metrics) in adjacent(node) {
+                let edge = edges.entry(addr).or_default();
+                *edge = *edge + metrics;
+            }
+        }
+
+        edges.into_iter().collect()
+    }
+}
+
+impl<'a> Profile<'a> {
+    /// Merges the stacks into a [`CallTree`].
+    pub fn call_tree(&self) -> CallTree {
+        CallTree::new(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_call_tree() {
+        let data = "heap_v2/524288
+  t*: 4: 64 [0: 0]
+@ 0x1 0x2 0x3
+  t*: 1: 16 [0: 0]
+@ 0x4 0x2 0x3
+  t*: 2: 32 [0: 0]
+@ 0x2 0x3
+  t*: 1: 16 [0: 0]
+@ 0x9
+  t*: 0: 0 [0: 0]
+";
+        let profile = Profile::parse(data).unwrap();
+        let tree = profile.call_tree();
+
+        assert_eq!(5, tree.nodes().len());
+        assert_eq!(2, tree.roots().len());
+
+        let root = tree.node(tree.roots()[0]);
+        assert_eq!(0x3, root.addr);
+        assert_eq!(None, root.parent);
+        assert_eq!(64, root.total_metrics.inuse_space);
+        assert_eq!(0, root.self_metrics.inuse_space);
+
+        let middle = tree.node(root.children[0]);
+        assert_eq!(0x2, middle.addr);
+        assert_eq!(64, middle.total_metrics.inuse_space);
+        assert_eq!(16, middle.self_metrics.inuse_space);
+
+        let leaves: Vec<(u64, u64)> = middle
+            .children
+            .iter()
+            .map(|id| (tree.node(*id).addr, tree.node(*id).self_metrics.inuse_space))
+            .collect();
+        assert_eq!(vec![(0x1, 16), (0x4, 32)], leaves);
+        assert_eq!(Some(root.children[0]), tree.node(middle.children[0]).parent);
+    }
+
+    #[test]
+    fn test_callers_and_callees() {
+        let data = "heap_v2/524288
+  t*: 4: 64 [0: 0]
+@ 0x1 0x2 0x3
+  t*: 1: 16 [0: 0]
+@ 0x1 0x2 0x4
+  t*: 2: 32 [0: 0]
+@ 0x5 0x2 0x3
+  t*: 1: 16 [0: 0]
+";
+        let tree = Profile::parse(data).unwrap().call_tree();
+        let space = |edges: Vec<(u64, Metrics)>| edges.into_iter().map(|(addr, metrics)| (addr, metrics.inuse_space)).collect::<Vec<_>>();
+
+        assert_eq!(vec![(0x3, 32), (0x4, 32)], space(tree.callers(0x2)));
+        assert_eq!(vec![(0x1, 48), (0x5, 16)], space(tree.callees(0x2)));
+        assert_eq!(vec![(0x2, 48)], space(tree.callers(0x1)));
+        assert!(tree.callees(0x1).is_empty());
+        assert!(tree.callers(0x3).is_empty());
+    }
+}
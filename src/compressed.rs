@@ -0,0 +1,109 @@
+//! Compressed dumps, recognised by their magic bytes.
+
+use crate::{OwnedProfile, ParseError, Profile};
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+impl Profile<'static> {
+    /// Parses a dump that may be gzip or zstd compressed, telling them apart
+    /// from plain text by their magic bytes rather than by file name.
+    ///
+    /// Each codec is behind a feature, `gzip` and `zstd`; compressed input
+    /// whose feature is disabled fails with [`ParseError::UnsupportedFormat`],
+    /// and input that fails to decompress with [`ParseError::Io`]. Anything
+    /// else is handed to [`Profile::parse_bytes`]. Streams of several
+    /// concatenated members or frames are decompressed in full.
+    pub fn from_compressed(bytes: &[u8]) -> Result<OwnedProfile, ParseError> {
+        if bytes.starts_with(GZIP_MAGIC) {
+            return Profile::parse_bytes(&gunzip(bytes)?);
+        }
+
+        if bytes.starts_with(ZSTD_MAGIC) {
+            return Profile::parse_bytes(&unzstd(bytes)?);
+        }
+
+        Profile::parse_bytes(bytes)
+    }
+}
+
+#[cfg(feature = "gzip")]
+fn gunzip(bytes: &[u8]) -> Result<Vec<u8>, ParseError> {
+    use std::io::Read;
+
+    let mut out = Vec::new();
+    flate2::read::MultiGzDecoder::new(bytes)
+        .read_to_end(&mut out)
+        .map_err(|err| ParseError::Io(err.kind()))?;
+    Ok(out)
+}
+
+#[cfg(not(feature = "gzip"))]
+fn gunzip(_: &[u8]) -> Result<Vec<u8>, ParseError> {
+    Err(ParseError::UnsupportedFormat)
+}
+
+#[cfg(feature = "zstd")]
+fn unzstd(mut bytes: &[u8]) -> Result<Vec<u8>, ParseError> {
+    use std::io::{ErrorKind, Read};
+
+    let mut out = Vec::new();
+    while !bytes.is_empty() {
+        let mut decoder =
+            ruzstd::decoding::StreamingDecoder::new(&mut bytes).map_err(|_| ParseError::Io(ErrorKind::InvalidData))?;
+        decoder.read_to_end(&mut out).map_err(|err| ParseError::Io(err.kind()))?;
+    }
+    Ok(out)
+}
+
+#[cfg(not(feature = "zstd"))]
+fn unzstd(_: &[u8]) -> Result<Vec<u8>, ParseError> {
+    Err(ParseError::UnsupportedFormat)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DATA: &str = "heap_v2/524288\n  t*: 1: 16 [0: 0]\n@ 0x1\n  t*: 1: 16 [0: 0]\n";
+
+    #[test]
+    fn test_from_compressed_plain() {
+        assert_eq!(1, Profile::from_compressed(DATA.as_bytes()).unwrap().stacks.len());
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn test_from_compressed_gzip() {
+        use std::io::Write;
+
+        let (head, tail) = DATA.split_at(30);
+        let mut compressed = Vec::new();
+        for part in [head, tail] {
+            let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(part.as_bytes()).unwrap();
+            compressed.extend(encoder.finish().unwrap());
+        }
+
+        assert_eq!(1, Profile::from_compressed(&compressed).unwrap().stacks.len());
+        assert!(matches!(Profile::from_compressed(&compressed[..12]), Err(ParseError::Io(_))));
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_from_compressed_zstd() {
+        use ruzstd::encoding::{compress_to_vec, CompressionLevel};
+
+        let (head, tail) = DATA.split_at(30);
+        let mut compressed = compress_to_vec(head.as_bytes(), CompressionLevel::Fastest);
+        compressed.extend(compress_to_vec(tail.as_bytes(), CompressionLevel::Fastest));
+
+        assert_eq!(1, Profile::from_compressed(&compressed).unwrap().stacks.len());
+    }
+
+    #[cfg(not(feature = "zstd"))]
+    #[test]
+    fn test_from_compressed_without_codec() {
+        assert_eq!(Err(ParseError::UnsupportedFormat), Profile::from_compressed(ZSTD_MAGIC).map(|_| ()));
+    }
+}
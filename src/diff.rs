@@ -0,0 +1,378 @@
+//! Comparing two captures of the same process.
+
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use crate::{Metric, Metrics, Profile, StackKey, SymbolizedProfile, ThreadId};
+
+/// The stacks of two profiles matched by their addresses, see
+/// [`Profile::diff`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProfileDiff {
+    /// Every stack of the current profile in order, followed by the stacks
+    /// only found in the base profile.
+    pub stacks: Vec<StackDiff>,
+}
+
+/// The `t*` metrics of one call path in the base and current profiles,
+/// `None` where the path is missing from that profile.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StackDiff {
+    pub addrs: Vec<u64>,
+    pub base: Option<Metrics>,
+    pub current: Option<Metrics>,
+}
+
+/// How a stack changed between the base and current profile.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StackChange {
+    /// Only found in the current profile.
+    New,
+    /// Only found in the base profile.
+    Removed,
+    /// Found in both, and grew by this much.
+    Grew(u64),
+    /// Found in both, and shrank by this much.
+    Shrank(u64),
+    Unchanged,
+}
+
+/// The stacks of a [`ProfileDiff`] bucketed by [`StackChange`], as printed in
+/// a leak-triage summary.
+///
+/// Every bucket is sorted by magnitude, largest first: `new` by the current
+/// value, `removed` by the base value, `grew` and `shrank` by the size of the
+/// change, and `unchanged` by the value. Ties keep address order.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct DiffReport<'d> {
+    pub new: Vec<&'d StackDiff>,
+    pub removed: Vec<&'d StackDiff>,
+    pub grew: Vec<&'d StackDiff>,
+    pub shrank: Vec<&'d StackDiff>,
+    pub unchanged: Vec<&'d StackDiff>,
+}
+
+impl StackDiff {
+    /// How `metric` changed, saturating at the bounds of `i64`.
+    pub fn delta(&self, metric: Metric) -> i64 {
+        let value = |metrics: Option<Metrics>| metrics.map_or(0, |metrics| metrics.get(metric)) as i128;
+        (value(self.current) - value(self.base)).clamp(i64::MIN as i128, i64::MAX as i128) as i64
+    }
+
+    /// Classifies the change of `metric`.
+    pub fn change(&self, metric: Metric) -> StackChange {
+        match (self.base, self.current) {
+            (None, _) => StackChange::New,
+            (_, None) => StackChange::Removed,
+            (Some(base), Some(current)) => {
+                let (base, current) = (base.get(metric), current.get(metric));
+                if current > base {
+                    StackChange::Grew(current - base)
+                } else if current < base {
+                    StackChange::Shrank(base - current)
+                } else {
+                    StackChange::Unchanged
+                }
+            }
+        }
+    }
+}
+
+impl ProfileDiff {
+    /// Buckets the stacks by how their `metric` changed.
+    pub fn classify(&self, metric: Metric) -> DiffReport<'_> {
+        let mut report = DiffReport::default();
+
+        for stack in &self.stacks {
+            match stack.change(metric) {
+                StackChange::New => report.new.push(stack),
+                StackChange::Removed => report.removed.push(stack),
+                StackChange::Grew(_) => report.grew.push(stack),
+                StackChange::Shrank(_) => report.shrank.push(stack),
+                StackChange::Unchanged => report.unchanged.push(stack),
+            }
+        }
+
+        let by_magnitude = |bucket: &mut Vec<&StackDiff>| {
+            bucket.sort_by(|a, b| {
+                let magnitude = |stack: &StackDiff| match stack.change(metric) {
+                    StackChange::Grew(delta) | StackChange::Shrank(delta) => delta,
+                    _ => stack.current.or(stack.base).map_or(0, |metrics| metrics.get(metric)),
+                };
+                magnitude(b).cmp(&magnitude(a)).then_with(|| a.addrs.cmp(&b.addrs))
+            })
+        };
+        by_magnitude(&mut report.new);
+        by_magnitude(&mut report.removed);
+        by_magnitude(&mut report.grew);
+        by_magnitude(&mut report.shrank);
+        by_magnitude(&mut report.unchanged);
+
+        report
+    }
+
+    /// Renders the change in `inuse_space` of every stack that changed as
+    /// one line of a unified-diff-like changelog, largest change first.
+    ///
+    /// Each line holds the signed byte delta and the leaf address in hex,
+    /// marked `new` or `removed` where the stack is missing from one of the
+    /// profiles, and the [average size](Metrics::avg_alloc_size) of the
+    /// objects it holds live, in the current profile or in the base for a
+    /// removed stack. That average tells a stack dominated by one large
+    /// allocation from one making many small ones. Use
+    /// [`ProfileDiff::to_text_symbolized`] for function names.
+    pub fn to_text(&self) -> String {
+        self.text(|addr| format!("{:#x}", addr))
+    }
+
+    /// Like [`ProfileDiff::to_text`], naming the leaf frames by symbol
+    /// where they resolved in `symbols`, usually the symbolized current
+    /// profile. Leaves of removed stacks only resolve if they also appear in
+    /// it.
+    pub fn to_text_symbolized(&self, symbols: &SymbolizedProfile) -> String {
+        self.text(|addr| match symbols.function_at(addr, true) {
+            Some(function) => function.to_string(),
+            None => format!("{:#x}", addr),
+        })
+    }
+
+    fn text(&self, leaf_name: impl Fn(u64) -> String) -> String {
+        let mut changed: Vec<(&StackDiff, i64)> = self
+            .stacks
+            .iter()
+            .map(|stack| (stack, stack.delta(Metric::InuseSpace)))
+            .filter(|(stack, delta)| *delta != 0 || stack.base.is_none() || stack.current.is_none())
+            .collect();
+        changed.sort_by(|(a, a_delta), (b, b_delta)| {
+            b_delta.unsigned_abs().cmp(&a_delta.unsigned_abs()).then_with(|| a.addrs.cmp(&b.addrs))
+        });
+
+        let mut out = String::from("--- base\n+++ current\n");
+        for (stack, delta) in changed {
+            let sign = if delta < 0 { '-' } else { '+' };
+            let leaf = stack.addrs.first().map_or_else(String::new, |addr| leaf_name(*addr));
+            let _ = write!(out, "{}{} {}", sign, delta.unsigned_abs(), leaf);
+
+            let mut notes = Vec::new();
+            match stack.change(Metric::InuseSpace) {
+                StackChange::New => notes.push("new".to_string()),
+                StackChange::Removed => notes.push("removed".to_string()),
+                _ => {}
+            }
+            if let Some(avg) = stack.current.or(stack.base).and_then(|metrics| metrics.avg_alloc_size()) {
+                notes.push(format!("avg {}", avg));
+            }
+            if !notes.is_empty() {
+                let _ = write!(out, " ({})", notes.join(", "));
+            }
+            out.push('\n');
+        }
+
+        out
+    }
+}
+
+/// The `t*` metrics of the stacks of `profile` summed per [`StackKey`],
+/// with the keys in order of first occurrence.
+pub(crate) fn summed_stacks<'p>(profile: &'p Profile) -> (Vec<StackKey<'p>>, HashMap<StackKey<'p>, Metrics>) {
+    let mut order = Vec::new();
+    let mut sums: HashMap<StackKey, Metrics> = HashMap::new();
+    for stack in &profile.stacks {
+        let Some(thread) = stack.thread(ThreadId::Total) else {
+            continue;
+        };
+
+        let sum = sums.entry(stack.key()).or_insert_with(|| {
+            order.push(stack.key());
+            Metrics::default()
+        });
+        *sum = *sum + thread.metrics();
+    }
+
+    (order, sums)
+}
+
+impl<'a> Profile<'a> {
+    /// Matches the stacks of this profile against those of an earlier
+    /// capture, `base`, by their addresses, comparing `t*` rows.
+    ///
+    /// Stacks without a `t*` row are treated as missing from their profile.
+    /// A stack listed several times in one profile is compared by the sum
+    /// of its rows, in the place of its first occurrence.
+    pub fn diff(&self, base: &Profile) -> ProfileDiff {
+        let (base_order, mut unmatched) = summed_stacks(base);
+        let (current_order, mut current) = summed_stacks(self);
+
+        let mut stacks: Vec<StackDiff> = current_order
+            .into_iter()
+            .map(|key| StackDiff {
+                addrs: key.0.to_vec(),
+                base: unmatched.remove(&key),
+                current: current.remove(&key),
+            })
+            .collect();
+
+        for key in base_order {
+            if let Some(metrics) = unmatched.remove(&key) {
+                stacks.push(StackDiff {
+                    addrs: key.0.to_vec(),
+                    base: Some(metrics),
+                    current: None,
+                });
+            }
+        }
+
+        ProfileDiff { stacks }
+    }
+
+    /// Renders the change of `metric` between `base` and this profile in
+    /// the differential folded format of `inferno-diff-folded`, for a
+    /// flame graph of what grew and shrank: frames root to leaf in hex,
+    /// followed by the base and the current `t*` value.
+    ///
+    /// Stacks are matched as in [`Profile::diff`], and a stack missing from
+    /// one side counts as zero there. Lines are sorted by the size of the
+    /// change, largest first, then by address. Stacks that are zero on both
+    /// sides are omitted.
+    pub fn to_diff_folded(&self, base: &Profile, metric: Metric) -> String {
+        let value = |metrics: Option<Metrics>| metrics.map_or(0, |metrics| metrics.get(metric));
+
+        let diff = self.diff(base);
+        let mut stacks: Vec<&StackDiff> =
+            diff.stacks.iter().filter(|stack| value(stack.base) > 0 || value(stack.current) > 0).collect();
+        stacks.sort_by(|a, b| {
+            b.delta(metric).unsigned_abs().cmp(&a.delta(metric).unsigned_abs()).then_with(|| a.addrs.cmp(&b.addrs))
+        });
+
+        let mut out = String::new();
+        for stack in stacks {
+            let frames: Vec<String> = stack.addrs.iter().rev().map(|addr| format!("{:#x}", addr)).collect();
+            let _ = writeln!(out, "{} {} {}", frames.join(";"), value(stack.base), value(stack.current));
+        }
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diff_classify() {
+        let base = "heap_v2/524288
+  t*: 4: 112 [0: 0]
+@ 0x1
+  t*: 1: 16 [0: 0]
+@ 0x2
+  t*: 1: 32 [0: 0]
+@ 0x3
+  t*: 1: 48 [0: 0]
+@ 0x4
+  t*: 1: 16 [0: 0]
+@ 0x5
+  t*: 1: 64 [0: 0]
+";
+        let current = "heap_v2/524288
+  t*: 5: 240 [0: 0]
+@ 0x1
+  t*: 1: 16 [0: 0]
+@ 0x2
+  t*: 2: 96 [0: 0]
+@ 0x3
+  t*: 1: 8 [0: 0]
+@ 0x4
+  t*: 1: 80 [0: 0]
+@ 0x6
+  t*: 1: 40 [0: 0]
+";
+        let base = Profile::parse(base).unwrap();
+        let current = Profile::parse(current).unwrap();
+        let diff = current.diff(&base);
+
+        assert_eq!(6, diff.stacks.len());
+        assert_eq!(vec![5], diff.stacks[5].addrs);
+        assert_eq!(64, diff.stacks[1].delta(Metric::InuseSpace));
+        assert_eq!(-40, diff.stacks[2].delta(Metric::InuseSpace));
+        assert_eq!(StackChange::Grew(1), diff.stacks[1].change(Metric::InuseCount));
+
+        let addrs = |bucket: &[&StackDiff]| bucket.iter().map(|stack| stack.addrs[0]).collect::<Vec<_>>();
+        let report = diff.classify(Metric::InuseSpace);
+        assert_eq!(vec![6], addrs(&report.new));
+        assert_eq!(vec![5], addrs(&report.removed));
+        assert_eq!(vec![2, 4], addrs(&report.grew));
+        assert_eq!(vec![3], addrs(&report.shrank));
+        assert_eq!(vec![1], addrs(&report.unchanged));
+
+        assert_eq!(
+            "--- base
++++ current
++64 0x2 (avg 48)
++64 0x4 (avg 80)
+-64 0x5 (removed, avg 64)
+-40 0x3 (avg 8)
++40 0x6 (new, avg 40)
+",
+            diff.to_text()
+        );
+    }
+
+    #[test]
+    fn test_diff_repeated_stack() {
+        let base = "heap_v2/524288
+  t*: 3: 64 [0: 0]
+@ 0x1 0x2
+  t*: 1: 16 [0: 0]
+@ 0x3
+  t*: 1: 16 [0: 0]
+@ 0x1 0x2
+  t*: 1: 32 [0: 0]
+";
+        let current = "heap_v2/524288
+  t*: 3: 48 [0: 0]
+@ 0x3
+  t*: 1: 16 [0: 0]
+@ 0x1 0x2
+  t*: 1: 16 [0: 0]
+@ 0x3
+  t*: 1: 16 [0: 0]
+";
+        let base = Profile::parse(base).unwrap();
+        let current = Profile::parse(current).unwrap();
+        let diff = current.diff(&base);
+
+        assert_eq!(2, diff.stacks.len());
+        assert_eq!(vec![3], diff.stacks[0].addrs);
+        assert_eq!(StackChange::Grew(16), diff.stacks[0].change(Metric::InuseSpace));
+        assert_eq!(vec![1, 2], diff.stacks[1].addrs);
+        assert_eq!(-32, diff.stacks[1].delta(Metric::InuseSpace));
+        assert_eq!(StackChange::Shrank(1), diff.stacks[1].change(Metric::InuseCount));
+    }
+
+    #[test]
+    fn test_to_diff_folded() {
+        let base = "heap_v2/524288
+  t*: 3: 64 [0: 0]
+@ 0x1 0x2
+  t*: 1: 16 [0: 0]
+@ 0x3
+  t*: 2: 48 [0: 0]
+@ 0x5
+  t*: 0: 0 [0: 0]
+";
+        let current = "heap_v2/524288
+  t*: 3: 96 [0: 0]
+@ 0x1 0x2
+  t*: 1: 16 [0: 0]
+@ 0x3
+  t*: 1: 8 [0: 0]
+@ 0x4
+  t*: 1: 72 [0: 0]
+";
+        let base = Profile::parse(base).unwrap();
+        let current = Profile::parse(current).unwrap();
+
+        assert_eq!("0x4 0 72\n0x3 48 8\n0x2;0x1 16 16\n", current.to_diff_folded(&base, Metric::InuseSpace));
+        assert_eq!("0x3 2 1\n0x4 0 1\n0x2;0x1 1 1\n", current.to_diff_folded(&base, Metric::InuseCount));
+    }
+}
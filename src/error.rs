@@ -0,0 +1,100 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use crate::parser::{self, Cause};
+
+/// Why a profile could not be parsed. Line numbers start at 1.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    /// The input does not start with a `heap_v2` header.
+    UnsupportedFormat,
+    /// The grammar did not match at `line`.
+    Malformed { line: usize },
+    /// In strict mode, the input ends without a line ending, or with content
+    /// the grammar could not consume, at `line`.
+    Truncated { line: usize },
+    /// The value of `field` on `line` does not fit in a `u64`.
+    FieldOverflow { field: &'static str, line: usize },
+    /// The stack starting on `line` has `depth` addresses, more than the
+    /// [`ParseOptions::max_stack_depth`](crate::ParseOptions::max_stack_depth)
+    /// of `limit`.
+    StackTooDeep { depth: usize, limit: usize, line: usize },
+    /// With
+    /// [`ParseOptions::error_on_duplicate_stacks`](crate::ParseOptions::error_on_duplicate_stacks),
+    /// the stacks at indices `first_index` and `second_index` of
+    /// [`Profile::stacks`](crate::Profile::stacks) have the same `addrs`.
+    DuplicateStack { addrs: Vec<u64>, first_index: usize, second_index: usize },
+    /// The input is larger than the [`ParseOptions`](crate::ParseOptions)
+    /// limit named `option`, `max_input_bytes` or `max_stacks`, which was
+    /// `max`.
+    LimitExceeded { option: &'static str, max: usize },
+    /// Reading the profile failed before it could be parsed.
+    Io(io::ErrorKind),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::UnsupportedFormat => write!(f, "Only HEAP V2 profiles are supported"),
+            ParseError::Malformed { line } => write!(f, "failed to parse heap_v2 profile at line {}", line),
+            ParseError::Truncated { line } => write!(f, "the profile appears truncated at line {}", line),
+            ParseError::FieldOverflow { field, line } => {
+                write!(f, "{} on line {} does not fit in 64 bits", field, line)
+            }
+            ParseError::StackTooDeep { depth, limit, line } => {
+                write!(f, "stack on line {} has {} frames, more than the limit of {}", line, depth, limit)
+            }
+            ParseError::DuplicateStack { addrs, first_index, second_index } => write!(
+                f,
+                "stacks {} and {} have the same {} addresses",
+                first_index,
+                second_index,
+                addrs.len()
+            ),
+            ParseError::LimitExceeded { option, max } => write!(f, "the profile exceeds the {} limit of {}", option, max),
+            ParseError::Io(kind) => write!(f, "failed to read the profile: {}", kind),
+        }
+    }
+}
+
+impl Error for ParseError {}
+
+impl ParseError {
+    /// Converts an error raised while parsing `input` into a `ParseError`.
+    pub(crate) fn from_nom(input: &str, err: nom::Err<parser::Error<'_>>) -> Self {
+        match err {
+            nom::Err::Error(err) | nom::Err::Failure(err) => {
+                let line = line_at(input, err.input);
+                match err.cause {
+                    Some(Cause::Overflow(field)) => ParseError::FieldOverflow { field, line },
+                    Some(Cause::TooDeep { depth, limit }) => ParseError::StackTooDeep { depth, limit, line },
+                    Some(Cause::TooManyStacks(max)) => ParseError::LimitExceeded { option: "max_stacks", max },
+                    None => ParseError::Malformed { line },
+                }
+            }
+            nom::Err::Incomplete(_) => ParseError::Truncated {
+                line: input.matches('\n').count() + 1,
+            },
+        }
+    }
+
+    /// Shifts the line number of an error raised while parsing a fragment
+    /// that starts on `line` of the whole input.
+    pub(crate) fn on_line(self, line: usize) -> Self {
+        let shift = |l: usize| l + line - 1;
+        match self {
+            ParseError::UnsupportedFormat => ParseError::UnsupportedFormat,
+            ParseError::Malformed { line: l } => ParseError::Malformed { line: shift(l) },
+            ParseError::Truncated { line: l } => ParseError::Truncated { line: shift(l) },
+            ParseError::FieldOverflow { field, line: l } => ParseError::FieldOverflow { field, line: shift(l) },
+            ParseError::StackTooDeep { depth, limit, line: l } => ParseError::StackTooDeep { depth, limit, line: shift(l) },
+            ParseError::DuplicateStack { .. } | ParseError::LimitExceeded { .. } | ParseError::Io(_) => self,
+        }
+    }
+}
+
+/// The line of `input` on which its suffix `rest` starts.
+pub(crate) fn line_at(input: &str, rest: &str) -> usize {
+    input[..input.len() - rest.len()].matches('\n').count() + 1
+}
@@ -0,0 +1,189 @@
+//! A C interface to the parser, behind the `cffi` feature.
+//!
+//! `include/jeprof.h` declares these functions; regenerate it with
+//! `cbindgen --config cbindgen.toml --output include/jeprof.h` after changing
+//! them. The crate builds as an rlib only, so link it into a C or C++
+//! program through a library built with, for example,
+//! `cargo rustc --release --lib --features cffi --crate-type staticlib`.
+//!
+//! A parsed profile is an opaque handle owned by the caller until it is
+//! passed to [`jeprof_free`]. Stacks are addressed by index, in dump order.
+
+use std::ptr;
+use std::slice;
+
+use crate::{Metrics, OwnedProfile, Profile, ThreadId};
+
+/// A parsed profile.
+pub struct JeprofProfile(OwnedProfile);
+
+/// The counters of a `t*` row.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct JeprofMetrics {
+    pub inuse_count: u64,
+    pub inuse_space: u64,
+    pub alloc_count: u64,
+    pub alloc_space: u64,
+}
+
+impl From<Metrics> for JeprofMetrics {
+    fn from(metrics: Metrics) -> Self {
+        JeprofMetrics {
+            inuse_count: metrics.inuse_count,
+            inuse_space: metrics.inuse_space,
+            alloc_count: metrics.alloc_count,
+            alloc_space: metrics.alloc_space,
+        }
+    }
+}
+
+/// Parses the `len` bytes at `data` as [`Profile::parse_bytes`] does,
+/// returning null when they do not hold a profile.
+///
+/// # Safety
+///
+/// `data` must point to `len` readable bytes, or may be null when `len` is
+/// zero.
+#[no_mangle]
+pub unsafe extern "C" fn jeprof_parse(data: *const u8, len: usize) -> *mut JeprofProfile {
+    let bytes = if len == 0 { &[][..] } else { slice::from_raw_parts(data, len) };
+    match Profile::parse_bytes(bytes) {
+        Ok(profile) => Box::into_raw(Box::new(JeprofProfile(profile))),
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+/// Frees a profile returned by [`jeprof_parse`]. Null is ignored.
+///
+/// # Safety
+///
+/// `profile` must be null or a handle from [`jeprof_parse`] that has not
+/// been freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn jeprof_free(profile: *mut JeprofProfile) {
+    if !profile.is_null() {
+        drop(Box::from_raw(profile));
+    }
+}
+
+/// The sampling rate from the header.
+///
+/// # Safety
+///
+/// `profile` must be a live handle from [`jeprof_parse`].
+#[no_mangle]
+pub unsafe extern "C" fn jeprof_sampling_rate(profile: *const JeprofProfile) -> u64 {
+    handle(profile).sampling_rate
+}
+
+/// Writes the `t*` totals to `out`, returning false, and leaving `out`
+/// untouched, when the totals block has no `t*` row.
+///
+/// # Safety
+///
+/// `profile` must be a live handle from [`jeprof_parse`] and `out` must be
+/// valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn jeprof_totals(profile: *const JeprofProfile, out: *mut JeprofMetrics) -> bool {
+    match handle(profile).totals_for(ThreadId::Total) {
+        Some(thread) => {
+            *out = thread.metrics().into();
+            true
+        }
+        None => false,
+    }
+}
+
+/// The number of stacks.
+///
+/// # Safety
+///
+/// `profile` must be a live handle from [`jeprof_parse`].
+#[no_mangle]
+pub unsafe extern "C" fn jeprof_stack_count(profile: *const JeprofProfile) -> usize {
+    handle(profile).stacks.len()
+}
+
+/// The addresses of stack `index`, leaf first, with their number written to
+/// `len`. Returns null, with `len` set to zero, when `index` is out of
+/// range. The addresses live as long as the profile.
+///
+/// # Safety
+///
+/// `profile` must be a live handle from [`jeprof_parse`] and `len` must be
+/// valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn jeprof_stack_addrs(profile: *const JeprofProfile, index: usize, len: *mut usize) -> *const u64 {
+    match handle(profile).stacks.get(index) {
+        Some(stack) => {
+            *len = stack.addrs.len();
+            stack.addrs.as_ptr()
+        }
+        None => {
+            *len = 0;
+            ptr::null()
+        }
+    }
+}
+
+/// Writes the `t*` row of stack `index` to `out`, returning false, and
+/// leaving `out` untouched, when `index` is out of range or the stack has no
+/// `t*` row.
+///
+/// # Safety
+///
+/// `profile` must be a live handle from [`jeprof_parse`] and `out` must be
+/// valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn jeprof_stack_metrics(profile: *const JeprofProfile, index: usize, out: *mut JeprofMetrics) -> bool {
+    match handle(profile).stacks.get(index).and_then(|stack| stack.thread(ThreadId::Total)) {
+        Some(thread) => {
+            *out = thread.metrics().into();
+            true
+        }
+        None => false,
+    }
+}
+
+/// The profile behind a handle, for the lifetime the caller keeps it alive.
+unsafe fn handle<'p>(profile: *const JeprofProfile) -> &'p OwnedProfile {
+    &(*profile).0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ffi() {
+        let data = b"heap_v2/524288\n  t*: 2: 32 [0: 0]\n@ 0x1 0x2\n  t*: 1: 16 [0: 0]\n@ 0x3\n  t1: 1: 16 [0: 0]\n";
+
+        unsafe {
+            assert!(jeprof_parse(b"nope".as_ptr(), 4).is_null());
+            assert!(jeprof_parse(ptr::null(), 0).is_null());
+
+            let profile = jeprof_parse(data.as_ptr(), data.len());
+            assert_eq!(524288, jeprof_sampling_rate(profile));
+            assert_eq!(2, jeprof_stack_count(profile));
+
+            let mut metrics = JeprofMetrics::default();
+            assert!(jeprof_totals(profile, &mut metrics));
+            assert_eq!(32, metrics.inuse_space);
+
+            let mut len = 0;
+            let addrs = jeprof_stack_addrs(profile, 0, &mut len);
+            assert_eq!(&[1, 2], slice::from_raw_parts(addrs, len));
+            assert!(jeprof_stack_addrs(profile, 2, &mut len).is_null());
+            assert_eq!(0, len);
+
+            assert!(jeprof_stack_metrics(profile, 0, &mut metrics));
+            assert_eq!(JeprofMetrics { inuse_count: 1, inuse_space: 16, alloc_count: 0, alloc_space: 0 }, metrics);
+            assert!(!jeprof_stack_metrics(profile, 1, &mut metrics));
+            assert!(!jeprof_stack_metrics(profile, 2, &mut metrics));
+
+            jeprof_free(profile);
+            jeprof_free(ptr::null_mut());
+        }
+    }
+}
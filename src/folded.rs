@@ -0,0 +1,259 @@
+//! The collapsed-stack ("folded") format read by `flamegraph.pl` and inferno.
+
+use std::borrow::Cow;
+use std::io::{self, Write};
+
+use crate::callgrind::LibraryOffsetNamer;
+use crate::{Metric, Metrics, Profile, Stack, Thread, ThreadId, ValueMode};
+
+/// What each frame of a folded line is rendered as.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum FoldedFrames {
+    /// The frame's address, in `0x`-prefixed hex.
+    #[default]
+    Addresses,
+    /// The basename of the mapped library containing the frame, with runs
+    /// of frames in the same library collapsed into one. This gives a coarse
+    /// flame of how allocations flow between libraries without needing
+    /// symbols. Addresses outside every library render as `[unknown]`.
+    Libraries,
+    /// `library+offset`, the basename of the mapped library followed by the
+    /// file offset of the frame in hex, which stays stable across runs with
+    /// address space randomization. Addresses outside every library render
+    /// in hex.
+    LibraryOffsets,
+}
+
+/// Trims and corrections applied to stacks before they are folded, see
+/// [`Profile::write_folded_filtered`].
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct FilterOptions {
+    /// Keep at most this many frames of each stack, counted from the leaf,
+    /// and replace the callers beyond them with a single `[truncated]`
+    /// frame, so that deep stacks stay legible in a flame graph while still
+    /// showing that depth was clipped.
+    pub max_frames: Option<usize>,
+    /// Whether lines carry the sampled values or unsampled estimates. The
+    /// folded format has no room to say which, so keep track of it when
+    /// comparing flame graphs.
+    pub values: ValueMode,
+}
+
+impl FilterOptions {
+    /// The leaf-most frames of `stack` to keep, and whether any were cut.
+    pub(crate) fn frames<'s>(&self, stack: &'s Stack) -> (&'s [u64], bool) {
+        match self.max_frames {
+            Some(max) if stack.addrs.len() > max => (&stack.addrs[..max], true),
+            _ => (&stack.addrs, false),
+        }
+    }
+}
+
+/// The frame standing in for the callers removed by
+/// [`FilterOptions::max_frames`].
+pub(crate) const TRUNCATED_FRAME: &str = "[truncated]";
+
+impl<'a> Profile<'a> {
+    /// Writes the profile in the collapsed-stack format understood by
+    /// `flamegraph.pl` and inferno, one line per stack, straight to `w`.
+    ///
+    /// Each line lists the frames root to leaf, separated by `;`, followed by
+    /// the `t*` value of `metric`. Lines are sorted by value, largest first,
+    /// then by address, so two exports of the same profile are identical
+    /// whatever order its stacks were dumped in. Stacks whose value is zero
+    /// are omitted.
+    pub fn write_folded<W: Write>(&self, w: &mut W, metric: Metric) -> io::Result<()> {
+        self.write_folded_with(w, metric, FoldedFrames::Addresses)
+    }
+
+    /// Like [`Profile::write_folded`], rendering frames as `frames` selects.
+    pub fn write_folded_with<W: Write>(&self, w: &mut W, metric: Metric, frames: FoldedFrames) -> io::Result<()> {
+        self.write_folded_filtered(w, metric, frames, &FilterOptions::default())
+    }
+
+    /// Like [`Profile::write_folded_with`], trimming and correcting every
+    /// stack as `filter` says first.
+    pub fn write_folded_filtered<W: Write>(
+        &self,
+        w: &mut W,
+        metric: Metric,
+        frames: FoldedFrames,
+        filter: &FilterOptions,
+    ) -> io::Result<()> {
+        let libraries = self.library_ranges();
+        let namer = LibraryOffsetNamer::new(self);
+        for (stack, metrics) in self.export_order(metric, filter.values) {
+            let Some(value) = folded_value(Some(metrics), metric) else {
+                continue;
+            };
+            let (addrs, truncated) = filter.frames(stack);
+
+            let name = |addr: &u64| -> Cow<'_, str> {
+                match frames {
+                    FoldedFrames::Addresses => Cow::Owned(format!("{:#x}", addr)),
+                    FoldedFrames::Libraries => {
+                        Cow::Borrowed(self.library_in(&libraries, *addr).map_or("[unknown]", |lib| basename(&lib.path)))
+                    }
+                    FoldedFrames::LibraryOffsets => Cow::Owned(namer.name(*addr)),
+                }
+            };
+            let mut names: Vec<Cow<'_, str>> = addrs.iter().rev().map(name).collect();
+            if frames == FoldedFrames::Libraries {
+                names.dedup();
+            }
+            if truncated {
+                names.insert(0, Cow::Borrowed(TRUNCATED_FRAME));
+            }
+
+            writeln!(w, "{} {}", names.join(";"), value)?;
+        }
+
+        Ok(())
+    }
+
+    /// Collects [`Profile::write_folded`] into a `String`.
+    pub fn to_folded(&self, metric: Metric) -> String {
+        self.to_folded_with(metric, FoldedFrames::Addresses)
+    }
+
+    /// Collects [`Profile::write_folded_with`] into a `String`.
+    pub fn to_folded_with(&self, metric: Metric, frames: FoldedFrames) -> String {
+        self.to_folded_filtered(metric, frames, &FilterOptions::default())
+    }
+
+    /// Collects [`Profile::write_folded_filtered`] into a `String`.
+    pub fn to_folded_filtered(&self, metric: Metric, frames: FoldedFrames, filter: &FilterOptions) -> String {
+        let mut out = Vec::new();
+        self.write_folded_filtered(&mut out, metric, frames, filter).expect("writing to a Vec cannot fail");
+        String::from_utf8_lossy(&out).into_owned()
+    }
+}
+
+impl<'a> Stack<'a> {
+    /// Writes this stack as a single collapsed-stack line, see
+    /// [`Profile::write_folded`]. Nothing is written when the stack's `t*`
+    /// value for `metric` is zero.
+    pub fn write_folded<W: Write>(&self, w: &mut W, metric: Metric) -> io::Result<()> {
+        let Some(value) = folded_value(self.thread(ThreadId::Total).map(Thread::metrics), metric) else {
+            return Ok(());
+        };
+
+        for (i, addr) in self.addrs.iter().rev().enumerate() {
+            if i > 0 {
+                w.write_all(b";")?;
+            }
+            write!(w, "{:#x}", addr)?;
+        }
+
+        writeln!(w, " {}", value)
+    }
+}
+
+/// The value a stack with `t*` metrics `metrics` is folded with, or `None`
+/// if it should be left out.
+pub(crate) fn folded_value(metrics: Option<Metrics>, metric: Metric) -> Option<u64> {
+    let value = metrics.map_or(0, |metrics| metrics.get(metric));
+    (value > 0).then_some(value)
+}
+
+pub(crate) fn basename(path: &str) -> &str {
+    path.rsplit('/').next().unwrap_or(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_folded() {
+        let data = "heap_v2/524288
+  t*: 3: 48 [5: 80]
+@ 0x1 0x2 0x3
+  t*: 1: 16 [2: 32]
+@ 0xa 0xb
+  t*: 2: 32 [3: 48]
+@ 0xc
+  t*: 0: 0 [0: 0]
+";
+        let profile = Profile::parse(data).unwrap();
+
+        assert_eq!("0xb;0xa 32\n0x3;0x2;0x1 16\n", profile.to_folded(Metric::InuseSpace));
+        assert_eq!("0xb;0xa 3\n0x3;0x2;0x1 2\n", profile.to_folded(Metric::AllocCount));
+
+        let first = "@ 0x1 0x2 0x3\n  t*: 1: 16 [2: 32]\n";
+        let reordered = data.replace(first, "").replace("@ 0xc", &format!("{}@ 0xc", first));
+        assert_eq!(profile.to_folded(Metric::InuseSpace), Profile::parse(&reordered).unwrap().to_folded(Metric::InuseSpace));
+
+        let mut out = Vec::new();
+        profile.stacks[1].write_folded(&mut out, Metric::InuseCount).unwrap();
+        assert_eq!(b"0xb;0xa 2\n".to_vec(), out);
+    }
+
+    #[test]
+    fn test_to_folded_libraries() {
+        let data = "heap_v2/524288
+  t*: 3: 48 [0: 0]
+@ 0x1010 0x1020 0x2010 0x3010 0x3020 0x9000
+  t*: 1: 16 [0: 0]
+@ 0x1010 0x3010
+  t*: 2: 32 [0: 0]
+
+MAPPED_LIBRARIES:
+00001000-00002000 r-xp 00000000 103:02 5000 /usr/lib/libjemalloc.so.2
+00002000-00003000 r-xp 00000000 103:02 5000 /opt/app/libfoo.so
+00003000-00004000 r-xp 00000000 103:02 5000 /opt/app/my app
+";
+        let profile = Profile::parse(data).unwrap();
+
+        assert_eq!(
+            "my app;libjemalloc.so.2 32\n[unknown];my app;libfoo.so;libjemalloc.so.2 16\n",
+            profile.to_folded_with(Metric::InuseSpace, FoldedFrames::Libraries)
+        );
+        assert_eq!(
+            "my app+0x10;libjemalloc.so.2+0x10 32
+0x9000;my app+0x20;my app+0x10;libfoo.so+0x10;libjemalloc.so.2+0x20;libjemalloc.so.2+0x10 16
+",
+            profile.to_folded_with(Metric::InuseSpace, FoldedFrames::LibraryOffsets)
+        );
+    }
+
+    #[test]
+    fn test_to_folded_max_frames() {
+        let data = "heap_v2/524288
+  t*: 3: 48 [0: 0]
+@ 0x1010 0x1020 0x2010 0x9000
+  t*: 1: 16 [0: 0]
+@ 0x1010 0x2010
+  t*: 2: 32 [0: 0]
+
+MAPPED_LIBRARIES:
+00001000-00002000 r-xp 00000000 103:02 5000 /usr/lib/libjemalloc.so.2
+00002000-00003000 r-xp 00000000 103:02 5000 /opt/app/server
+";
+        let profile = Profile::parse(data).unwrap();
+        let filter = FilterOptions {
+            max_frames: Some(2),
+            ..FilterOptions::default()
+        };
+
+        assert_eq!(
+            "0x2010;0x1010 32\n[truncated];0x1020;0x1010 16\n",
+            profile.to_folded_filtered(Metric::InuseSpace, FoldedFrames::Addresses, &filter)
+        );
+        assert_eq!(
+            "server;libjemalloc.so.2 32\n[truncated];libjemalloc.so.2 16\n",
+            profile.to_folded_filtered(Metric::InuseSpace, FoldedFrames::Libraries, &filter)
+        );
+        let unfiltered = profile.to_folded_filtered(Metric::InuseSpace, FoldedFrames::Addresses, &FilterOptions::default());
+        assert_eq!(profile.to_folded(Metric::InuseSpace), unfiltered);
+
+        let unsampled = FilterOptions {
+            values: ValueMode::Unsampled,
+            ..FilterOptions::default()
+        };
+        assert_eq!(
+            "0x2010;0x1010 1048592\n0x9000;0x2010;0x1020;0x1010 524296\n",
+            profile.to_folded_filtered(Metric::InuseSpace, FoldedFrames::Addresses, &unsampled)
+        );
+    }
+}
@@ -0,0 +1,168 @@
+//! Stable identities of profile content, for use as cache keys.
+
+use crate::{MappedLibrary, Profile, Stack, Thread, ThreadId};
+
+impl<'a> Profile<'a> {
+    /// A 64-bit FNV-1a hash of the sampling rate, the totals, the stacks
+    /// with their metrics and the mapped libraries, for recognising a dump
+    /// that has already been processed.
+    ///
+    /// The hash does not depend on the order of stacks, of the thread rows
+    /// within them, of the totals or of the libraries, nor on
+    /// [`Profile::sequence`] and [`Profile::captured_at`], and it is the same
+    /// on every platform and across releases of this crate. It is not a
+    /// cryptographic digest.
+    pub fn content_hash(&self) -> u64 {
+        let mut hasher = Fnv::default();
+        hasher.u64(self.sampling_rate);
+        hasher.unordered(self.totals.iter().map(thread_hash));
+        hasher.unordered(self.stacks.iter().map(stack_hash));
+        hasher.unordered(self.mapped_libraries.iter().map(library_hash));
+        hasher.0
+    }
+}
+
+fn thread_hash(thread: &Thread) -> u64 {
+    let mut hasher = Fnv::default();
+    match &thread.id {
+        ThreadId::Total => hasher.u64(0),
+        ThreadId::Numeric(n) => {
+            hasher.u64(1);
+            hasher.u64(*n);
+        }
+        ThreadId::Named(name) => {
+            hasher.u64(2);
+            hasher.str(name);
+        }
+    }
+
+    let metrics = thread.metrics();
+    hasher.u64(metrics.inuse_count);
+    hasher.u64(metrics.inuse_space);
+    hasher.u64(metrics.alloc_count);
+    hasher.u64(metrics.alloc_space);
+
+    // Left out when empty, so that hashes of standard dumps stay the same.
+    if !thread.extra.is_empty() {
+        hasher.u64(thread.extra.len() as u64);
+        for (a, b) in &thread.extra {
+            hasher.u64(*a);
+            hasher.u64(*b);
+        }
+    }
+    hasher.0
+}
+
+fn stack_hash(stack: &Stack) -> u64 {
+    let mut hasher = Fnv::default();
+    hasher.u64(stack.addrs.len() as u64);
+    for addr in &stack.addrs {
+        hasher.u64(*addr);
+    }
+    hasher.unordered(stack.threads.iter().map(thread_hash));
+    hasher.0
+}
+
+fn library_hash(library: &MappedLibrary) -> u64 {
+    let mut hasher = Fnv::default();
+    hasher.u64(library.first);
+    hasher.u64(library.last);
+    hasher.u64(library.offset);
+    hasher.str(&library.perms);
+    hasher.str(&library.path);
+    hasher.u64(library.deleted as u64);
+    hasher.0
+}
+
+/// 64-bit FNV-1a, fed integers as little-endian bytes so that the result
+/// is the same on every platform.
+struct Fnv(u64);
+
+impl Default for Fnv {
+    fn default() -> Self {
+        Fnv(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Fnv {
+    fn bytes(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= u64::from(*byte);
+            self.0 = self.0.wrapping_mul(0x100_0000_01b3);
+        }
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn str(&mut self, value: &str) {
+        self.u64(value.len() as u64);
+        self.bytes(value.as_bytes());
+    }
+
+    /// Hashes a collection regardless of its order, by sorting the hashes
+    /// of its items.
+    fn unordered(&mut self, hashes: impl Iterator<Item = u64>) {
+        let mut hashes: Vec<u64> = hashes.collect();
+        hashes.sort_unstable();
+        self.u64(hashes.len() as u64);
+        for hash in hashes {
+            self.u64(hash);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_content_hash() {
+        let data = "heap_v2/524288
+  t*: 3: 48 [0: 0]
+  t1: 3: 48 [0: 0]
+@ 0x1 0x2
+  t*: 1: 16 [0: 0]
+  t1: 1: 16 [0: 0]
+@ 0x3
+  t*: 2: 32 [0: 0]
+  t1: 2: 32 [0: 0]
+
+MAPPED_LIBRARIES:
+00001000-00002000 r-xp 00000000 103:02 5000 /usr/lib/liba.so
+";
+        let reordered = "heap_v2/524288
+  t1: 3: 48 [0: 0]
+  t*: 3: 48 [0: 0]
+@ 0x3
+  t1: 2: 32 [0: 0]
+  t*: 2: 32 [0: 0]
+@ 0x1 0x2
+  t*: 1: 16 [0: 0]
+  t1: 1: 16 [0: 0]
+
+MAPPED_LIBRARIES:
+00001000-00002000 r-xp 00000000 103:02 5000 /usr/lib/liba.so
+";
+        let hash = Profile::parse(data).unwrap().content_hash();
+        assert_eq!(hash, Profile::parse(data).unwrap().content_hash());
+        assert_eq!(hash, Profile::parse(reordered).unwrap().content_hash());
+
+        assert_ne!(hash, Profile::parse(&data.replace("@ 0x3", "@ 0x4")).unwrap().content_hash());
+        assert_ne!(hash, Profile::parse(&data.replace("2: 32 [0: 0]\n  t1", "2: 33 [0: 0]\n  t1")).unwrap().content_hash());
+        assert_ne!(hash, Profile::parse(&data.replace("liba", "libb")).unwrap().content_hash());
+        assert_ne!(hash, Profile::parse(&data.replace("524288", "1")).unwrap().content_hash());
+
+        let extra = data.replace("  t1: 2: 32 [0: 0]\n\n", "  t1: 2: 32 [0: 0] [1: 4096]\n\n");
+        let extra_hash = Profile::parse(&extra).unwrap().content_hash();
+        assert_ne!(hash, extra_hash);
+        assert_ne!(extra_hash, Profile::parse(&extra.replace("4096", "8192")).unwrap().content_hash());
+        assert_ne!(extra_hash, Profile::parse(&extra.replace("[1: 4096]", "[1: 4096] [0: 0]")).unwrap().content_hash());
+        let swapped = extra.replace("[1: 4096]", "[2: 8192] [1: 4096]");
+        assert_ne!(
+            Profile::parse(&swapped).unwrap().content_hash(),
+            Profile::parse(&extra.replace("[1: 4096]", "[1: 4096] [2: 8192]")).unwrap().content_hash()
+        );
+    }
+}
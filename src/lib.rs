@@ -1,269 +1,2975 @@
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::error::Error;
+use std::fmt;
+use std::ops::{Add, Sub};
+use std::str::FromStr;
+use std::time::{Duration, Instant, SystemTime};
 
-use nom::{
-    bytes::complete::{tag, take_while},
-    character::complete::{char, digit1, line_ending, space1, one_of, anychar, not_line_ending},
-    combinator::{map_res, recognize, opt},
-    multi::{many1, many0, many_m_n},
-    sequence::{preceded, terminated},
-    IResult, branch::alt, Parser,
+use regex::Regex;
+use yoke::Yokeable;
+
+use error::line_at;
+use parser::parse_profile;
+
+pub use calltree::{CallNode, CallTree};
+pub use diff::{DiffReport, ProfileDiff, StackChange, StackDiff};
+pub use error::ParseError;
+pub use folded::{FilterOptions, FoldedFrames};
+pub use pprof::PprofOptions;
+pub use range_index::RangeIndex;
+pub use self_contained::SelfContainedProfile;
+pub use stream::{OwnedStackSummary, StreamParser};
+pub use symbolize::{
+    Frame, InternedFrame, Readiness, SymbolResolver, SymbolWarning, SymbolizeOptions, SymbolizedProfile, Symbolizer,
+    WarningReason,
 };
 
-const HEAP_V2_HEADER: &str = "heap_v2";
-const MAPPED_LIBRARIES_HEADER: &str = "MAPPED_LIBRARIES:\n";
+pub mod analyze;
+#[cfg(feature = "async")]
+mod async_reader;
+mod callgrind;
+mod compressed;
+mod calltree;
+mod diff;
+mod error;
+#[cfg(feature = "cffi")]
+mod ffi;
+mod folded;
+mod hash;
+pub mod parser;
+mod pprof;
+mod range_index;
+mod raw;
+mod self_contained;
+mod stream;
+mod symbolize;
+mod text;
+
+pub(crate) const HEAP_V2_HEADER: &str = "heap_v2";
+
+/// How many lines other than blank and `#` lines may precede the header,
+/// such as the `heap profile:` line some wrappers write first.
+pub(crate) const MAX_PREAMBLE_LINES: usize = 4;
+
+/// The jemalloc heap profile formats this crate can parse.
+pub const SUPPORTED_VERSIONS: &[HeapVersion] = &[HeapVersion::V2];
+
+/// A jemalloc heap profile format, named after the tag its header starts
+/// with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum HeapVersion {
+    /// `heap_v2/<sampling rate>`, written by jemalloc 4 and later.
+    V2,
+}
+
+impl HeapVersion {
+    /// The tag the header of a profile in this format starts with.
+    pub fn header(&self) -> &'static str {
+        match self {
+            HeapVersion::V2 => HEAP_V2_HEADER,
+        }
+    }
+}
 
 /// A Jemalloc HeapV2 Profile
-#[derive(Debug)]
+#[derive(Debug, Yokeable)]
 pub struct Profile<'a> {
     pub sampling_rate: u64,
     pub totals: Vec<Thread<'a>>,
     pub stacks: Vec<Stack<'a>>,
-    pub mapped_libraries: Vec<MappedLibrary<'a>>
+    pub mapped_libraries: Vec<MappedLibrary<'a>>,
+    /// Position of this profile within the input given to
+    /// [`Profile::parse_all`]; always `0` for [`Profile::parse`].
+    pub sequence: usize,
+    /// When the dump was taken, if known. jemalloc does not record this
+    /// itself, so the parser leaves it `None` for callers that know the
+    /// capture time (from the file name, mtime, or their own wrapper) to fill
+    /// in.
+    pub captured_at: Option<SystemTime>,
 }
 
-impl<'a> Profile<'a> {
-    pub fn parse(profile: &'a str) -> Result<Self, Box<dyn Error>> {
-        if !profile.starts_with(HEAP_V2_HEADER) {
-            return Err("Only HEAP V2 profiles are supported".into())
+/// A profile that borrows nothing, such as one built from scratch or
+/// returned by [`Profile::into_owned`].
+pub type OwnedProfile = Profile<'static>;
+
+/// Controls how forgiving [`Profile::parse_with`] is, and how much of the
+/// input it keeps.
+#[derive(Debug, Clone)]
+pub struct ParseOptions {
+    /// Reject input that looks truncated: a final line without a line
+    /// ending (such as a stack cut off mid-address), or trailing content the
+    /// grammar could not consume. By default both are accepted silently.
+    pub strict: bool,
+    /// Clamp thread counters too large for a `u64` to `u64::MAX` instead of
+    /// failing with [`ParseError::FieldOverflow`].
+    pub saturate_overflow: bool,
+    /// Collect the maps section into [`Profile::mapped_libraries`]. When
+    /// unset the section is still consumed, and validated, but nothing is
+    /// kept, which saves time and memory on dumps with thousands of mappings
+    /// when only stacks and totals are needed. Set by default.
+    pub parse_libraries: bool,
+    /// The most addresses a single stack may have before parsing fails with
+    /// [`ParseError::StackTooDeep`], guarding against corrupt or hostile
+    /// dumps. Defaults to 4096, well beyond real call depths.
+    pub max_stack_depth: usize,
+    /// The order of the counters in thread lines.
+    pub thread_format: ThreadLineFormat,
+    /// Fail with [`ParseError::DuplicateStack`] when two stacks have the
+    /// same addresses, which a single well-formed dump never contains but
+    /// two concatenated ones do. By default such stacks are kept.
+    pub error_on_duplicate_stacks: bool,
+    /// The no-newline mode, for dumps whose line endings were stripped by
+    /// some pipeline so that everything sits on one line. Lines are rebuilt
+    /// by breaking before headers, thread lines and `@` markers, and before
+    /// each address range in the maps section, then parsed as usual. Line
+    /// numbers in errors count the rebuilt lines, and the result no longer
+    /// borrows the input. Unset by default.
+    pub reconstruct_lines: bool,
+    /// Refuse input longer than this many bytes before parsing any of it,
+    /// with [`ParseError::LimitExceeded`]. Together with
+    /// [`ParseOptions::max_stacks`] this bounds the work spent on untrusted
+    /// uploads. Unlimited by default.
+    pub max_input_bytes: Option<usize>,
+    /// Stop with [`ParseError::LimitExceeded`] as soon as the profile turns
+    /// out to have more stacks than this. Unlimited by default.
+    pub max_stacks: Option<usize>,
+    /// Keep only the rows of these threads, plus the `t*` rows, in the
+    /// totals and in every stack. Other rows are dropped as they are parsed
+    /// rather than afterwards, which saves most of the memory of a dump from
+    /// a process with hundreds of threads when only one of them matters.
+    /// All rows are kept by default.
+    pub keep_threads: Option<Vec<ThreadId<'static>>>,
+    /// Drop consecutive repeats of an address from each stack as it is
+    /// parsed, so the long runs recursive allocators leave behind are kept
+    /// once. [`ParseOptions::max_stack_depth`] still counts the addresses as
+    /// dumped, and two stacks can end up with the same addresses. Unset by
+    /// default, keeping stacks as dumped.
+    pub collapse_recursion: bool,
+}
+
+/// The order in which thread lines list their counters.
+///
+/// The numbers carry no labels, so a dump written in another order parses
+/// without error but with counts and sizes swapped.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadLineFormat {
+    /// `t<id>: <inuse_count>: <inuse_space> [<alloc_count>: <alloc_space>]`,
+    /// as jemalloc writes them.
+    #[default]
+    CountFirst,
+    /// `t<id>: <inuse_space>: <inuse_count> [<alloc_space>: <alloc_count>]`,
+    /// as written by some patched allocators.
+    SpaceFirst,
+}
+
+impl Default for ParseOptions {
+    fn default() -> Self {
+        ParseOptions {
+            strict: false,
+            saturate_overflow: false,
+            parse_libraries: true,
+            max_stack_depth: 4096,
+            thread_format: ThreadLineFormat::CountFirst,
+            error_on_duplicate_stacks: false,
+            reconstruct_lines: false,
+            max_input_bytes: None,
+            max_stacks: None,
+            keep_threads: None,
+            collapse_recursion: false,
         }
+    }
+}
 
-        let (_, profile) = parse_profile(profile).map_err(|_| "failed to parse heap_v2 profile")?;
+/// How [`Profile::allocation_score`] weighs the bytes a stack holds against
+/// those it allocated and freed again.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ScoreWeights {
+    /// The weight of each live byte, `inuse_space`.
+    pub live: f64,
+    /// The weight of each freed byte, `alloc_space - inuse_space`, which
+    /// stands for the CPU spent on churn.
+    pub freed: f64,
+}
 
-        Ok(profile)
+/// Counts a freed byte half as much as a live one.
+impl Default for ScoreWeights {
+    fn default() -> Self {
+        ScoreWeights { live: 1.0, freed: 0.5 }
     }
 }
 
-#[derive(Debug)]
-pub struct Stack<'a> {
-    pub addrs: Vec<i64>,
-    pub threads: Vec<Thread<'a>>,
+/// The header and totals block of a dump, see [`Profile::parse_summary`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProfileSummary<'a> {
+    pub sampling_rate: u64,
+    pub totals: Vec<Thread<'a>>,
 }
 
-#[derive(Debug)]
-pub struct Thread<'a> {
-    pub id: &'a str,
-    pub inuse_count: u64,
-    pub insuse_space: u64,
-    pub alloc_count: u64,
-    pub alloc_space: u64,
+impl<'a> ProfileSummary<'a> {
+    /// The totals row of thread `id`, the `t*` row for [`ThreadId::Total`].
+    pub fn totals_for(&self, id: ThreadId) -> Option<&Thread<'a>> {
+        self.totals.iter().find(|thread| thread.id == id)
+    }
 }
 
-#[derive(Debug)]
-pub struct MappedLibrary<'a> {
-    first: i64,
-    last: i64,
-    path: &'a str,
+/// What a call to [`Profile::parse_with_metrics`] cost.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParseMetrics {
+    /// Bytes of input consumed by the grammar, including any preamble.
+    pub bytes: usize,
+    /// Number of stacks parsed.
+    pub stacks: usize,
+    /// Wall-clock time spent parsing.
+    pub duration: Duration,
 }
 
-fn parse_profile(input: &str) -> IResult<&str, Profile> {
-    let (input, sampling_rate) = parse_header(input)?;
-    let (input, _) = line_ending(input)?;
-    let (input, threads) = many1(terminated(preceded(space1, parse_thread), line_ending))(input)?;
-    let (input, stacks) = many1(parse_stack)(input)?;
+impl<'a> Profile<'a> {
+    pub fn parse(profile: &'a str) -> Result<Self, ParseError> {
+        Self::parse_with(profile, &ParseOptions::default())
+    }
 
-    let (input, _) = many0(line_ending)(input)?;
+    /// Parses a single dump. Blank lines and `#` comment lines before the
+    /// header are skipped, as are up to four descriptive lines wrappers put
+    /// there; line numbers in errors count them. The header may be written
+    /// `@heap_v2/` as well as `heap_v2/`.
+    ///
+    /// Dumps cut short by a crash or written by hand often stop before the
+    /// `MAPPED_LIBRARIES:` section, or without a line ending after the last
+    /// stack or mapping. Both are accepted, leaving
+    /// [`Profile::mapped_libraries`] empty in the first case, unless
+    /// [`ParseOptions::strict`] asks for the final line ending.
+    pub fn parse_with(profile: &'a str, options: &ParseOptions) -> Result<Self, ParseError> {
+        Self::parse_counting(profile, options).map(|(profile, _)| profile)
+    }
 
-    let (input, _) = tag(MAPPED_LIBRARIES_HEADER)(input)?;
+    /// Like [`Profile::parse_with`], also reporting how much input was
+    /// consumed and how long it took, for monitoring parse latency.
+    ///
+    /// The duration is measured with [`Instant`], which is unavailable on
+    /// `wasm32-unknown-unknown`; use [`Profile::parse_with`] there.
+    pub fn parse_with_metrics(profile: &'a str, options: &ParseOptions) -> Result<(Self, ParseMetrics), ParseError> {
+        let start = Instant::now();
+        let (profile, bytes) = Self::parse_counting(profile, options)?;
 
-    let (input, mapped_libraries) = many0(terminated(parse_mapped_library, line_ending))(input)?;
-    let mapped_libraries = mapped_libraries.into_iter().filter(|lib| !lib.path.is_empty()).collect();
+        let metrics = ParseMetrics {
+            bytes,
+            stacks: profile.stacks.len(),
+            duration: start.elapsed(),
+        };
+        Ok((profile, metrics))
+    }
 
-    let profile = Profile {
-        sampling_rate,
-        totals: threads,
-        stacks,
-        mapped_libraries
-    };
+    /// Parses a single dump, returning it with the number of bytes
+    /// consumed.
+    fn parse_counting(profile: &'a str, options: &ParseOptions) -> Result<(Self, usize), ParseError> {
+        if let Some(max) = options.max_input_bytes.filter(|max| profile.len() > *max) {
+            return Err(ParseError::LimitExceeded { option: "max_input_bytes", max });
+        }
 
-    Ok((input, profile))
-}
+        if options.reconstruct_lines {
+            let text = parser::reconstruct_lines(profile);
+            let options = ParseOptions {
+                reconstruct_lines: false,
+                ..options.clone()
+            };
+            let (parsed, _) = Profile::parse_counting(&text, &options)?;
+            return Ok((parsed.into_owned(), profile.len()));
+        }
 
-fn parse_header(input: &str) -> IResult<&str, u64> {
-    let (input, _) = tag("heap_v2/")(input)?;
-    map_res(digit1, |digit_str: &str| digit_str.parse::<u64>())(input)
-}
+        let input = strip_bom(profile);
+        let profile = skip_preamble(input);
+        if !is_header(profile) {
+            return Err(ParseError::UnsupportedFormat)
+        }
 
-fn parse_stack(input: &str) -> IResult<&str, Stack> {
-    let (input, addrs) = terminated(parse_stack_addrs, line_ending)(input)?;
-    let (input, threads) = many1(terminated(preceded(space1, parse_thread), line_ending))(input)?;
+        let (rest, parsed) = parse_profile(profile, options).map_err(|err| ParseError::from_nom(input, err))?;
 
-    let stack = Stack {
-        addrs,
-        threads
-    };
+        if options.strict {
+            if !rest.is_empty() {
+                return Err(ParseError::Truncated { line: line_at(input, rest) });
+            }
 
-    Ok((input, stack))
-}
+            if !input.ends_with('\n') {
+                return Err(ParseError::Truncated { line: input.matches('\n').count() + 1 });
+            }
+        }
 
+        if options.error_on_duplicate_stacks {
+            let mut seen: HashMap<StackKey, usize> = HashMap::new();
+            for (index, stack) in parsed.stacks.iter().enumerate() {
+                if let Some(first_index) = seen.insert(stack.key(), index) {
+                    return Err(ParseError::DuplicateStack {
+                        addrs: stack.addrs.clone(),
+                        first_index,
+                        second_index: index,
+                    });
+                }
+            }
+        }
 
-fn parse_stack_addrs(input: &str) -> IResult<&str, Vec<i64>> {
-    let (input, _) = tag("@")(input)?;
-    many1(preceded(space1, hexadecimal_value))(input)
-}
+        Ok((parsed, input.len() - rest.len()))
+    }
 
-fn parse_mapped_library(input: &str) -> IResult<&str, MappedLibrary> {
-    // 7f99f42dd000-7f99f42e0000
-    let (input, first) = hexadecimal_value(input)?;
-    let (input, _) = tag("-")(input)?;
-    let (input, last) = hexadecimal_value(input)?;
+    /// Parses only the header and the totals block, stopping at the first
+    /// stack, for indexing many dumps by sampling rate and size far faster
+    /// than a full parse. The preamble is skipped as in
+    /// [`Profile::parse_with`]. Nothing past the totals block is read, so
+    /// errors there go unnoticed.
+    pub fn parse_summary(input: &'a str) -> Result<ProfileSummary<'a>, ParseError> {
+        let input = strip_bom(input);
+        let profile = skip_preamble(input);
+        if !is_header(profile) {
+            return Err(ParseError::UnsupportedFormat);
+        }
 
-    // r--p
-    let (input, _) = preceded(space1, many_m_n(4, 4, anychar))(input)?;
+        let (_, (sampling_rate, totals)) = parser::header_and_totals(profile, &ParseOptions::default())
+            .map_err(|err| ParseError::from_nom(input, err))?;
+        Ok(ProfileSummary { sampling_rate, totals })
+    }
 
-    // 00000000
-    let (input, _) = preceded(space1, many_m_n(8, 8, one_of("0123456789abcdefABCDEF")))(input)?;
+    /// Parses a profile held in raw bytes, such as a file dropped into a web
+    /// page, into a profile that no longer borrows them.
+    ///
+    /// Input that is not valid UTF-8 is decoded lossily: each invalid
+    /// sequence becomes U+FFFD, so a stray byte in a library path or thread
+    /// name ends up in that string instead of failing the parse, and the
+    /// stacks and totals still come through. Paths containing one no longer
+    /// name the file on disk. An invalid byte within the numbers or
+    /// addresses still fails with [`ParseError::Malformed`] at its line.
+    ///
+    /// Nothing here touches the file system, so this is the entry point to
+    /// use on `wasm32-unknown-unknown`.
+    pub fn parse_bytes(bytes: &[u8]) -> Result<OwnedProfile, ParseError> {
+        Profile::parse(&String::from_utf8_lossy(bytes)).map(Profile::into_owned)
+    }
 
-    // 103:02
-    let (input, _) = preceded(space1, digit1)(input)?;
-    let (input, _) = tag(":")(input)?;
-    let (input, _) = digit1(input)?;
+    /// The format the profile was read from, or is written in by
+    /// [`Profile::to_heap_text`].
+    pub fn version(&self) -> HeapVersion {
+        HeapVersion::V2
+    }
 
-    // 5000
-    let (input, _) = preceded(space1, digit1)(input)?;
+    /// A profile with a zeroed `t*` totals row, no stacks and no mapped
+    /// libraries, to start merges and other accumulations from.
+    pub fn empty(sampling_rate: u64) -> Self {
+        Profile {
+            sampling_rate,
+            totals: vec![Thread {
+                id: ThreadId::Total,
+                inuse_count: 0,
+                insuse_space: 0,
+                alloc_count: 0,
+                alloc_space: 0,
+                extra: Vec::new(),
+            }],
+            stacks: Vec::new(),
+            mapped_libraries: Vec::new(),
+            sequence: 0,
+            captured_at: None,
+        }
+    }
 
-    // /usr/lib/x86_64-linux-gnu/libgcc_s.so.1
-    let (input, path) = preceded(space1, not_line_ending)(input)?;
+    /// Copies every borrowed string so the profile no longer refers to the
+    /// input it was parsed from.
+    pub fn into_owned(self) -> OwnedProfile {
+        Profile {
+            sampling_rate: self.sampling_rate,
+            totals: self.totals.into_iter().map(Thread::into_owned).collect(),
+            stacks: self.stacks.into_iter().map(Stack::into_owned).collect(),
+            mapped_libraries: self.mapped_libraries.into_iter().map(MappedLibrary::into_owned).collect(),
+            sequence: self.sequence,
+            captured_at: self.captured_at,
+        }
+    }
 
-    let library = MappedLibrary {
-        first,
-        last,
-        path
-    };
+    /// Parses a file holding several dumps concatenated one after the other,
+    /// as produced by periodic dumping into a single file.
+    ///
+    /// Profiles are returned in input order with [`Profile::sequence`] set to
+    /// their index, so a time series can be built directly from the result.
+    /// Blank lines and `#` comment lines are allowed before and between
+    /// dumps.
+    pub fn parse_all(input: &'a str) -> Result<Vec<Self>, ParseError> {
+        let input = strip_bom(input);
+        let mut profiles = Vec::new();
+        let mut rest = input;
 
-    Ok((input, library))
-}
+        loop {
+            rest = skip_preamble(rest);
+            if rest.is_empty() {
+                break;
+            }
 
-fn parse_thread(input: &str) -> IResult<&str, Thread> {
-    let (input, _) = tag("t")(input)?;
-    let (input, id) = take_while(|c: char| c.is_alphanumeric() || c == '*')(input)?;
-    let (input, _) = tag(": ")(input)?;
-    let (input, inuse_count) = map_res(digit1, |digit_str: &str| digit_str.parse::<u64>())(input)?;
-    let (input, _) = tag(": ")(input)?;
-    let (input, insuse_space) = map_res(digit1, |digit_str: &str| digit_str.parse::<u64>())(input)?;
-    let (input, _) = tag(" [")(input)?;
-    let (input, alloc_count) = map_res(digit1, |digit_str: &str| digit_str.parse::<u64>())(input)?;
-    let (input, _) = tag(": ")(input)?;
-    let (input, alloc_space) = map_res(digit1, |digit_str: &str| digit_str.parse::<u64>())(input)?;
-    let (input, _) = tag("]")(input)?;
+            if !is_header(rest) {
+                return Err(ParseError::Malformed { line: line_at(input, rest) });
+            }
 
-    let thread = Thread {
-        id,
-        inuse_count,
-        insuse_space,
-        alloc_count,
-        alloc_space,
-    };
+            let (remaining, mut profile) =
+                parse_profile(rest, &ParseOptions::default()).map_err(|err| ParseError::from_nom(input, err))?;
+            profile.sequence = profiles.len();
 
-    Ok((input, thread))
-}
+            profiles.push(profile);
+            rest = remaining;
+        }
 
-fn hexadecimal_value(input: &str) -> IResult<&str, i64> {
-  map_res(
-    preceded(
-      opt(alt((tag("0x"), tag("0X")))),
-      recognize(
-        many1(
-          terminated(one_of("0123456789abcdefABCDEF"), many0(char('_')))
-        )
-      )
-    ),
-    |out: &str| i64::from_str_radix(&str::replace(out, "_", ""), 16)
-  ).parse(input)
-}
+        Ok(profiles)
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// Checks that no two mapped libraries cover overlapping `[first, last)`
+    /// address ranges.
+    ///
+    /// A well-formed maps section never overlaps, so any overlap points at a
+    /// parse error or at several dumps concatenated together. Every
+    /// overlapping pair is reported, with both paths, in the returned error,
+    /// including mappings nested in a wider one. Empty mappings overlap
+    /// nothing.
+    pub fn check_mapping_overlaps(&self) -> Result<(), Box<dyn Error>> {
+        let libraries = RangeIndex::new(self.mapped_libraries.iter().map(|lib| (lib.first, lib.last, lib)));
+        let overlaps: Vec<String> = libraries
+            .overlaps()
+            .into_iter()
+            .map(|(prev, lib)| {
+                format!(
+                    "{} [{:#x}, {:#x}) overlaps {} [{:#x}, {:#x})",
+                    prev.path, prev.first, prev.last, lib.path, lib.first, lib.last
+                )
+            })
+            .collect();
 
-    #[test]
-    fn test_parse_profile() {
-        let data = "heap_v2/131072
-  t*: 4385: 810327 [0: 0]
-  t0: 129: 4965 [0: 0]
-  t1: 191: 7942 [0: 0]
-  t2: 0: 0 [0: 0]
-  t3: 128: 5268 [0: 0]
-  t4: 4: 405115494 [0: 0]
-  t5: 468: 42015 [0: 0]
-  t6: 148: 5586 [0: 0]
-  t7: 232: 15290 [0: 0]
-  t8: 0: 0 [0: 0]
-@ 0x004 0x003 0x002 0x001
-  t*: 1: 224 [0: 0]
-  t5: 1: 224 [0: 0]
-@ 0x001 0x002 0x003 0x004
-  t*: 1: 224 [0: 0]
-  t5: 1: 224 [0: 0]";
-        let (_, profile) = parse_profile(data).unwrap();
-        assert_eq!(131072, profile.sampling_rate);
-        assert_eq!("*", profile.totals[0].id);
-        assert_eq!(4385, profile.totals[0].inuse_count);
-        assert_eq!(4385, profile.totals[0].inuse_count);
-        assert_eq!(2, profile.stacks.len());
+        if overlaps.is_empty() {
+            Ok(())
+        } else {
+            Err(format!("overlapping mapped libraries: {}", overlaps.join("; ")).into())
+        }
+    }
 
-        let stack = &profile.stacks[0];
-        assert_eq!(4, stack.addrs.len());
-        assert_eq!(4, stack.addrs[0]);
+    /// Removes mapped libraries that repeat an earlier entry with identical
+    /// `first`, `last` and `path`, keeping the first occurrence in place.
+    ///
+    /// Re-dumped or merged profiles can list the same mapping many times.
+    pub fn dedup_libraries(&mut self) {
+        let mut seen = HashSet::new();
+        self.mapped_libraries.retain(|lib| seen.insert((lib.first, lib.last, lib.path.clone())));
     }
 
-    #[test]
-    fn test_parse_header() {
-        let data = "heap_v2/12345";
-        let (_, sampling_rate) = parse_header(data).unwrap();
-        assert_eq!(12345, sampling_rate)
+    /// Sorts the mapped libraries by load address, `first` ascending, keeping
+    /// the dump order of libraries loaded at the same address, for listings
+    /// that are easy to match addresses against by eye.
+    ///
+    /// Lookups do not need this, [`Profile::library_ranges`] sorts on its
+    /// own, but indices into [`Profile::mapped_libraries`], such as those
+    /// in a [`FrameKey`], change.
+    pub fn sort_libraries(&mut self) {
+        self.mapped_libraries.sort_by_key(|lib| lib.first);
     }
 
-    #[test]
-    fn test_parse_stack_addrs() {
-        let data = "@ 0x000000000001 0x000000000002 0x000000000003 0x000000000004";
-        let (_, addrs) = parse_stack_addrs(data).unwrap();
-        assert_eq!(4, addrs.len());
-        assert_eq!(1, addrs[0]);
-        assert_eq!(2, addrs[1]);
-        assert_eq!(3, addrs[2]);
-        assert_eq!(4, addrs[3]);
+    /// Keeps only the mapped libraries for which `f` returns `true`, in
+    /// order. Addresses in the dropped ranges no longer resolve through
+    /// [`Profile::library_for_addr`].
+    pub fn retain_libraries<F: FnMut(&MappedLibrary) -> bool>(&mut self, mut f: F) {
+        self.mapped_libraries.retain(|lib| f(lib));
     }
 
-    #[test]
-    fn test_parse_library() {
-        let data = "00000001-00000004 r--p 00000000 103:02 5000                      /usr/lib/x86_64-linux-gnu/libgcc_s.so.1";
-        let (_, lib) = parse_mapped_library(data).unwrap();
-        assert_eq!("/usr/lib/x86_64-linux-gnu/libgcc_s.so.1", lib.path);
-        assert_eq!(1, lib.first);
-        assert_eq!(4, lib.last);
+    /// Keeps only the mapped libraries whose path matches `re`, e.g. to
+    /// separate application binaries from system libraries.
+    pub fn retain_libraries_matching(&mut self, re: &Regex) {
+        self.retain_libraries(|lib| re.is_match(&lib.path));
     }
 
-    #[test]
-    fn test_parse_thread() {
-        let data = "t123: 5000: 6000 [7000: 8000]";
-        let result = parse_thread(data);
+    /// Multiplies every counter of every thread row, in the totals block and
+    /// in the stacks, by `factor`, e.g. to express two captures per request
+    /// before diffing them.
+    ///
+    /// Each counter is rounded to the nearest integer, halves away from
+    /// zero, and clamped to `0..=u64::MAX`, so a negative or NaN factor
+    /// zeroes the profile.
+    pub fn scale(&mut self, factor: f64) {
+        let threads = self.stacks.iter_mut().flat_map(|stack| stack.threads.iter_mut());
+        for thread in self.totals.iter_mut().chain(threads) {
+            let scaled = thread.metrics().scale(factor);
+            thread.inuse_count = scaled.inuse_count;
+            thread.insuse_space = scaled.inuse_space;
+            thread.alloc_count = scaled.alloc_count;
+            thread.alloc_space = scaled.alloc_space;
+        }
+    }
+
+    /// Serializes the profile back into the `heap_v2` text format, so that a
+    /// filtered profile can be saved and read again by `jeprof`.
+    ///
+    /// The totals block is recomputed from the stacks that remain rather
+    /// than copied, so it stays consistent after stacks have been removed.
+    /// Rows keep the order of the original totals block, with rows that only
+    /// appear in stacks appended. Spacing matches what jemalloc writes.
+    /// [`Thread::extra`] brackets are written after the alloc counters, with
+    /// those of a totals row summed bracket by bracket over the stacks.
+    /// Device and inode of mapped libraries are not kept by the parser and
+    /// are written as `00:00 0`, which jeprof ignores.
+    pub fn to_heap_text(&self) -> String {
+        let mut ids: Vec<&ThreadId<'a>> = self.totals.iter().map(|thread| &thread.id).collect();
+        for thread in self.stacks.iter().flat_map(|stack| &stack.threads) {
+            if !ids.contains(&&thread.id) {
+                ids.push(&thread.id);
+            }
+        }
 
-        match result {
-            Ok((_, thread)) => {
-                assert_eq!(thread.id, "123");
-                assert_eq!(thread.inuse_count, 5000);
-                assert_eq!(thread.insuse_space, 6000);
-                assert_eq!(thread.alloc_count, 7000);
-                assert_eq!(thread.alloc_space, 8000);
+        let mut out = format!("{}/{}\n", HEAP_V2_HEADER, self.sampling_rate);
+        for id in ids {
+            let mut metrics = Metrics::default();
+            let mut extra: Vec<(u64, u64)> = Vec::new();
+            for thread in self.stacks.iter().flat_map(|stack| &stack.threads).filter(|thread| &thread.id == id) {
+                metrics = metrics + thread.metrics();
+                if extra.len() < thread.extra.len() {
+                    extra.resize(thread.extra.len(), (0, 0));
+                }
+                for (sum, (a, b)) in extra.iter_mut().zip(&thread.extra) {
+                    *sum = (sum.0.saturating_add(*a), sum.1.saturating_add(*b));
+                }
             }
-            Err(err) => panic!("Parsing failed with error: {:?}", err),
+            out.push_str(&format_thread_line(id, &metrics, &extra));
         }
+
+        for stack in &self.stacks {
+            out.push('@');
+            for addr in &stack.addrs {
+                out.push_str(&format!(" {:#x}", addr));
+            }
+            out.push('\n');
+
+            for thread in &stack.threads {
+                out.push_str(&format_thread_line(&thread.id, &thread.metrics(), &thread.extra));
+            }
+        }
+
+        out.push_str("\nMAPPED_LIBRARIES:\n");
+        for lib in &self.mapped_libraries {
+            out.push_str(&format!(
+                "{:x}-{:x} {} {:08x} 00:00 0 {}{}\n",
+                lib.first,
+                lib.last,
+                lib.perms,
+                lib.offset,
+                lib.path,
+                if lib.deleted { " (deleted)" } else { "" }
+            ));
+        }
+
+        out
     }
 
-    #[test]
-    fn test_parse_thread_star() {
-        let data = "t*: 5000: 6000 [7000: 9000]";
-        let result = parse_thread(data);
+    /// Renders the `t*` totals as Prometheus gauges in the text exposition
+    /// format, attaching `labels` to every series.
+    ///
+    /// A profile without a totals row reports zeroes.
+    pub fn to_prometheus(&self, labels: &[(&str, &str)]) -> String {
+        let metrics = self.totals_for(ThreadId::Total).map(Thread::metrics).unwrap_or_default();
+
+        let labels = if labels.is_empty() {
+            String::new()
+        } else {
+            let pairs: Vec<String> = labels
+                .iter()
+                .map(|(name, value)| format!("{}=\"{}\"", name, escape_label_value(value)))
+                .collect();
+            format!("{{{}}}", pairs.join(","))
+        };
+
+        let gauges = [
+            ("jemalloc_inuse_bytes", "Sampled bytes currently in use.", metrics.inuse_space),
+            ("jemalloc_inuse_objects", "Sampled objects currently in use.", metrics.inuse_count),
+            ("jemalloc_alloc_bytes", "Sampled bytes allocated.", metrics.alloc_space),
+            ("jemalloc_alloc_objects", "Sampled objects allocated.", metrics.alloc_count),
+        ];
+
+        let mut out = String::new();
+        for (name, help, value) in gauges {
+            out.push_str(&format!("# HELP {} {}\n", name, help));
+            out.push_str(&format!("# TYPE {} gauge\n", name));
+            out.push_str(&format!("{}{} {}\n", name, labels, value));
+        }
+
+        out
+    }
+
+    /// Estimates how many objects are actually live, correcting the sampled
+    /// `t*` counts of every stack for jemalloc's sampling.
+    ///
+    /// jemalloc samples an allocation of `size` bytes with probability
+    /// `1 - exp(-size / sampling_rate)`, so, as in jeprof, each stack's
+    /// count is scaled by `1 / (1 - exp(-avg / sampling_rate))` where `avg`
+    /// is that stack's `inuse_space / inuse_count`. The factor depends on the
+    /// stack's average object size, which is why it is applied per stack
+    /// rather than once to the totals. The scaled counts are summed and
+    /// rounded to the nearest integer.
+    pub fn estimated_live_objects(&self) -> u64 {
+        let total: f64 = self
+            .stacks
+            .iter()
+            .filter_map(|stack| stack.thread(ThreadId::Total))
+            .map(|thread| {
+                thread.inuse_count as f64 * unsampling_factor(self.sampling_rate, thread.insuse_space, thread.inuse_count)
+            })
+            .sum();
+
+        total.round() as u64
+    }
+
+    /// Percentiles of `metric` across stacks, using each stack's `t*` row.
+    ///
+    /// Each entry of `pcts` is a percentile between 0 and 100 (values outside
+    /// are clamped) and yields one value in the result, in the same order.
+    /// Percentiles are computed by linear interpolation between the two
+    /// nearest ranks: the stack values are sorted ascending and percentile
+    /// `p` lies at fractional index `(n - 1) * p / 100`, which is the
+    /// method numpy and most spreadsheets use by default. The interpolated
+    /// value is rounded to the nearest integer. Every percentile of a
+    /// profile without stacks is `0`.
+    pub fn metric_percentiles(&self, metric: Metric, pcts: &[f64]) -> Vec<u64> {
+        let mut values: Vec<u64> = self
+            .stacks
+            .iter()
+            .filter_map(|stack| stack.thread(ThreadId::Total))
+            .map(|thread| thread.metrics().get(metric))
+            .collect();
+        values.sort_unstable();
+
+        pcts.iter()
+            .map(|pct| {
+                if values.is_empty() {
+                    return 0;
+                }
+
+                let rank = (values.len() - 1) as f64 * pct.clamp(0.0, 100.0) / 100.0;
+                let lower = rank.floor() as usize;
+                let upper = rank.ceil() as usize;
+                let fraction = rank - lower as f64;
+
+                let value = values[lower] as f64 + (values[upper] as f64 - values[lower] as f64) * fraction;
+                value.round() as u64
+            })
+            .collect()
+    }
+
+    /// Every distinct address appearing in any stack, for resolving them in
+    /// one batch, or working out with [`Profile::library_for_addr`] which
+    /// debug files are needed, ahead of symbolization.
+    pub fn unique_addresses(&self) -> BTreeSet<u64> {
+        self.stacks.iter().flat_map(|stack| stack.addrs.iter().copied()).collect()
+    }
+
+    /// Deduplicates the addresses of every stack into a table of locations.
+    ///
+    /// Returns the unique addresses sorted ascending, together with a map from
+    /// each address to its index in that list, so that exporters can refer
+    /// to locations by id instead of repeating addresses per stack.
+    pub fn location_table(&self) -> (Vec<u64>, HashMap<u64, u32>) {
+        let mut addrs: Vec<u64> = self.stacks.iter().flat_map(|stack| stack.addrs.iter().copied()).collect();
+        addrs.sort_unstable();
+        addrs.dedup();
+
+        let ids = addrs.iter().enumerate().map(|(id, addr)| (*addr, id as u32)).collect();
+        (addrs, ids)
+    }
+
+    /// Attributes every stack to its first frame, from the leaf, that lies
+    /// outside the libraries matched by `skip`, and sums the `t*` metrics per
+    /// such frame.
+    ///
+    /// `skip` is matched against library paths, typically to step over the
+    /// allocator, libc and libstdc++ frames so that allocations are charged
+    /// to application code. Addresses outside every mapped library are never
+    /// skipped. Stacks made only of skipped frames are grouped under `None`.
+    /// Results are sorted by `metric`, largest first.
+    pub fn group_by_app_frame(&self, skip: &[Regex], metric: Metric) -> Vec<(Option<u64>, Metrics)> {
+        let mut groups: HashMap<Option<u64>, Metrics> = HashMap::new();
+        let libraries = self.library_ranges();
+
+        for stack in &self.stacks {
+            let Some(thread) = stack.thread(ThreadId::Total) else {
+                continue;
+            };
+
+            let frame = stack.addrs.iter().copied().find(|addr| match self.library_in(&libraries, *addr) {
+                Some(lib) => !skip.iter().any(|re| re.is_match(&lib.path)),
+                None => true,
+            });
+
+            let group = groups.entry(frame).or_default();
+            *group = *group + thread.metrics();
+        }
+
+        let mut groups: Vec<(Option<u64>, Metrics)> = groups.into_iter().collect();
+        groups.sort_by(|a, b| b.1.get(metric).cmp(&a.1.get(metric)).then(a.0.cmp(&b.0)));
+        groups
+    }
+
+    /// The virtual address space covered by the mapped libraries, in bytes.
+    ///
+    /// Ranges are merged first, so mappings listed twice or overlapping are
+    /// counted once. Corrupt entries whose end lies before their start are
+    /// skipped.
+    pub fn mapped_bytes(&self) -> u64 {
+        let mut ranges: Vec<(u64, u64)> = self
+            .mapped_libraries
+            .iter()
+            .filter(|lib| lib.first < lib.last)
+            .map(|lib| (lib.first, lib.last))
+            .collect();
+        ranges.sort_unstable();
+
+        let mut bytes = 0u64;
+        let mut covered_to = 0;
+        for (first, last) in ranges {
+            let first = first.max(covered_to);
+            if last > first {
+                bytes = bytes.saturating_add(last - first);
+                covered_to = last;
+            }
+        }
+
+        bytes
+    }
+
+    /// The distinct libraries mapped into the dumped process, by basename, or
+    /// by full path when `full_paths` is set. Comparing these sets across
+    /// dumps shows when a deploy changed what gets loaded.
+    pub fn loaded_libraries(&self, full_paths: bool) -> BTreeSet<&str> {
+        self.mapped_libraries
+            .iter()
+            .map(|lib| if full_paths { &lib.path } else { folded::basename(&lib.path) })
+            .collect()
+    }
+
+    /// The mapped library whose `[first, last)` range contains `addr`.
+    ///
+    /// Each call builds a [`Profile::library_ranges`] index; build it once
+    /// instead when looking up many addresses.
+    pub fn library_for_addr(&self, addr: u64) -> Option<&MappedLibrary<'a>> {
+        self.library_in(&self.library_ranges(), addr)
+    }
+
+    /// The `[first, last)` range of every mapped library, with its index in
+    /// [`Profile::mapped_libraries`], for looking up the library holding an
+    /// address by binary search.
+    pub fn library_ranges(&self) -> RangeIndex<usize> {
+        RangeIndex::new(self.mapped_libraries.iter().enumerate().map(|(index, lib)| (lib.first, lib.last, index)))
+    }
+
+    /// The mapped library `libraries`, built by [`Profile::library_ranges`],
+    /// finds for `addr`.
+    pub(crate) fn library_in(&self, libraries: &RangeIndex<usize>, addr: u64) -> Option<&MappedLibrary<'a>> {
+        libraries.lookup(addr).map(|index| &self.mapped_libraries[*index])
+    }
+
+    /// The `t*` metrics of `stack` in the given mode, `None` without a `t*`
+    /// row.
+    pub(crate) fn stack_values(&self, stack: &Stack, values: ValueMode) -> Option<Metrics> {
+        Some(values.apply(stack.thread(ThreadId::Total)?.metrics(), self.sampling_rate))
+    }
+
+    /// The stacks with a `t*` row and their metrics in the given mode, by
+    /// `metric`, largest first, then by address. Exporters write stacks in
+    /// this order so that their output does not depend on the order of the
+    /// dump.
+    pub(crate) fn export_order(&self, metric: Metric, values: ValueMode) -> Vec<(&Stack<'a>, Metrics)> {
+        let mut stacks: Vec<(&Stack<'a>, Metrics)> =
+            self.stacks.iter().filter_map(|stack| Some((stack, self.stack_values(stack, values)?))).collect();
+        stacks.sort_by(|(a, a_metrics), (b, b_metrics)| {
+            b_metrics.get(metric).cmp(&a_metrics.get(metric)).then_with(|| a.addrs.cmp(&b.addrs))
+        });
+        stacks
+    }
+
+    /// The share, between `0.0` and `1.0`, of `t*` `inuse_space` allocated
+    /// at stacks whose leaf lies outside every mapped library, such as
+    /// anonymous mappings or JIT code. Symbolization cannot help with those.
+    /// Returns `0.0` when nothing is in use.
+    pub fn anonymous_fraction(&self) -> f64 {
+        let (mut anonymous, mut total) = (0u64, 0u64);
+        let libraries = self.library_ranges();
+        for stack in &self.stacks {
+            let Some(thread) = stack.thread(ThreadId::Total) else {
+                continue;
+            };
+
+            total = total.saturating_add(thread.insuse_space);
+            if stack.leaf().is_some_and(|leaf| self.library_in(&libraries, leaf).is_none()) {
+                anonymous = anonymous.saturating_add(thread.insuse_space);
+            }
+        }
+
+        if total == 0 {
+            return 0.0;
+        }
+
+        anonymous as f64 / total as f64
+    }
+
+    /// The mapped library whose code allocated the largest share of `t*`
+    /// `inuse_space`, judged by the leaf of each stack, together with that
+    /// share between `0.0` and `1.0`. Ties go to the library listed first.
+    ///
+    /// Returns `None` when nothing is in use or every leaf lies outside the
+    /// mapped libraries.
+    pub fn dominant_library(&self) -> Option<(&MappedLibrary<'a>, f64)> {
+        let mut space = vec![0u64; self.mapped_libraries.len()];
+        let mut total = 0u64;
+        let libraries = self.library_ranges();
+        for stack in &self.stacks {
+            let Some(thread) = stack.thread(ThreadId::Total) else {
+                continue;
+            };
+
+            total = total.saturating_add(thread.insuse_space);
+            if let Some(index) = stack.leaf().and_then(|leaf| libraries.lookup(leaf).copied()) {
+                space[index] = space[index].saturating_add(thread.insuse_space);
+            }
+        }
+
+        let (index, largest) = space.into_iter().enumerate().rev().max_by_key(|(_, space)| *space)?;
+        if largest == 0 {
+            return None;
+        }
+
+        Some((&self.mapped_libraries[index], largest as f64 / total as f64))
+    }
+
+    /// The `t*` `metric` of every stack summed by the library holding its
+    /// leaf, largest first, ties in path order with
+    /// [`LibraryRef::Anonymous`] last, e.g. to chart the heap by component.
+    /// A library's mappings count together. Buckets that sum to zero are
+    /// left out.
+    pub fn leaf_library_breakdown(&self, metric: Metric) -> Vec<(LibraryRef<'_>, u64)> {
+        let mut buckets: HashMap<LibraryRef<'_>, u64> = HashMap::new();
+        let libraries = self.library_ranges();
+        for stack in &self.stacks {
+            let Some(thread) = stack.thread(ThreadId::Total) else {
+                continue;
+            };
+
+            let bucket = match stack.leaf().and_then(|leaf| self.library_in(&libraries, leaf)) {
+                Some(lib) => LibraryRef::Path(&lib.path),
+                None => LibraryRef::Anonymous,
+            };
+            let value = buckets.entry(bucket).or_default();
+            *value = value.saturating_add(thread.metrics().get(metric));
+        }
+
+        let mut buckets: Vec<(LibraryRef<'_>, u64)> = buckets.into_iter().filter(|(_, value)| *value > 0).collect();
+        buckets.sort_by(|(a, a_value), (b, b_value)| b_value.cmp(a_value).then_with(|| a.cmp(b)));
+        buckets
+    }
+
+    /// Guesses whether the profile comes from a 32-bit or a 64-bit process,
+    /// e.g. to pad addresses to the right width or to catch a dump from an
+    /// unexpected architecture.
+    ///
+    /// This is a heuristic: the dump does not say, so the profile counts as
+    /// 32-bit when no stack address nor mapped library reaches past
+    /// `0xffffffff`. A 64-bit process whose code all happens to be mapped
+    /// low is misreported, and so is a profile without any addresses.
+    pub fn address_width(&self) -> AddressWidth {
+        let stacks = self.stacks.iter().flat_map(|stack| stack.addrs.iter().copied());
+        let libraries = self.mapped_libraries.iter().map(|lib| lib.last.saturating_sub(1));
+
+        match stacks.chain(libraries).max() {
+            Some(addr) if addr > u64::from(u32::MAX) => AddressWidth::Bits64,
+            _ => AddressWidth::Bits32,
+        }
+    }
+
+    /// `addr` made independent of where libraries happened to be loaded, for
+    /// keying frames by location within a file.
+    pub fn frame_key(&self, addr: u64) -> FrameKey {
+        self.frame_key_in(&self.library_ranges(), addr)
+    }
+
+    /// Like [`Profile::frame_key`], looking `addr` up in `libraries`, built
+    /// by [`Profile::library_ranges`].
+    pub(crate) fn frame_key_in(&self, libraries: &RangeIndex<usize>, addr: u64) -> FrameKey {
+        let library = libraries.lookup(addr).copied();
+        match library.and_then(|index| Some((index, self.mapped_libraries[index].file_offset(addr)?))) {
+            Some((library, offset)) => FrameKey::Library { library, offset },
+            None => FrameKey::Absolute(addr),
+        }
+    }
+
+    /// The number of distinct threads listed in the totals block, not
+    /// counting the `t*` row.
+    pub fn thread_count(&self) -> usize {
+        self.totals
+            .iter()
+            .map(|thread| &thread.id)
+            .filter(|id| **id != ThreadId::Total)
+            .collect::<HashSet<_>>()
+            .len()
+    }
+
+    /// The allocations of thread `id` as a profile of their own.
+    ///
+    /// Only the stacks with a row for `id` are kept, each with that row and a
+    /// `t*` row holding the same counters, so that everything reading `t*`,
+    /// such as [`Profile::top`] or the folded output, sees just this thread.
+    /// The totals block is built the same way from the thread's totals row,
+    /// or from the sum of its stacks if the totals block has no such row.
+    /// Mapped libraries are copied as they are.
+    pub fn thread_profile(&self, id: ThreadId) -> OwnedProfile {
+        let stacks = self
+            .stacks
+            .iter()
+            .filter_map(|stack| Some(thread_stack(stack, stack.thread(id.clone())?)))
+            .collect();
+
+        self.sub_profile(id.into_owned(), stacks)
+    }
+
+    /// Splits the profile by thread in one pass, as if calling
+    /// [`Profile::thread_profile`] for every thread of the totals block or
+    /// of any stack. `t*` rows are not threads and get no entry. Threads
+    /// iterate in [`ThreadId`] order.
+    pub fn partition_by_thread(&self) -> BTreeMap<ThreadId<'static>, OwnedProfile> {
+        let mut stacks: BTreeMap<ThreadId<'static>, Vec<Stack<'static>>> = BTreeMap::new();
+
+        for thread in self.totals.iter().filter(|thread| thread.id != ThreadId::Total) {
+            stacks.entry(thread.id.clone().into_owned()).or_default();
+        }
+
+        for stack in &self.stacks {
+            for thread in stack.threads.iter().filter(|thread| thread.id != ThreadId::Total) {
+                stacks.entry(thread.id.clone().into_owned()).or_default().push(thread_stack(stack, thread));
+            }
+        }
+
+        stacks
+            .into_iter()
+            .map(|(id, stacks)| (id.clone(), self.sub_profile(id, stacks)))
+            .collect()
+    }
+
+    /// Wraps the stacks of a single thread, built by `thread_stack`, into a
+    /// profile carrying this profile's sampling rate and libraries.
+    fn sub_profile(&self, id: ThreadId<'static>, stacks: Vec<Stack<'static>>) -> OwnedProfile {
+        let metrics = match self.totals_for(id.clone()) {
+            Some(thread) => thread.metrics(),
+            None => stacks
+                .iter()
+                .flat_map(|stack| stack.thread(ThreadId::Total))
+                .fold(Metrics::default(), |sum, thread| sum + thread.metrics()),
+        };
+
+        Profile {
+            sampling_rate: self.sampling_rate,
+            totals: vec![Thread::from_metrics(ThreadId::Total, metrics), Thread::from_metrics(id, metrics)],
+            stacks,
+            mapped_libraries: self.mapped_libraries.iter().cloned().map(MappedLibrary::into_owned).collect(),
+            sequence: self.sequence,
+            captured_at: self.captured_at,
+        }
+    }
+
+    /// The row of the totals block belonging to `id`.
+    pub fn totals_for(&self, id: ThreadId) -> Option<&Thread<'a>> {
+        self.totals.iter().find(|thread| thread.id == id)
+    }
+
+    /// The `inuse_space` jemalloc reported for the whole heap in the `t*`
+    /// row of the totals block, or `None` without one. This is the figure
+    /// to quote for a complete dump, and the one stack percentages are of.
+    pub fn totals_row_inuse_space(&self) -> Option<u64> {
+        self.totals_for(ThreadId::Total).map(|thread| thread.insuse_space)
+    }
+
+    /// The `t*` `inuse_space` summed over the stacks actually present. It
+    /// matches [`Profile::totals_row_inuse_space`] for a complete dump but
+    /// falls short of it when stacks were lost to truncation or removed
+    /// since, which makes comparing the two a cheap validity check. Prefer
+    /// it after filtering the stacks.
+    pub fn computed_inuse_space(&self) -> u64 {
+        self.stacks
+            .iter()
+            .filter_map(|stack| stack.thread(ThreadId::Total))
+            .fold(0, |sum, thread| sum.saturating_add(thread.insuse_space))
+    }
+
+    /// The `n` stacks with the largest `metric`, largest first, ties broken
+    /// by address so that the result does not depend on the order of the
+    /// dump.
+    ///
+    /// Stacks are ranked by the row selected by `totals_selector`, so passing
+    /// a numeric thread ranks that thread's stacks without building a
+    /// separate profile for it. Stacks without such a row are skipped.
+    pub fn top(&self, n: usize, metric: Metric, totals_selector: ThreadId) -> Vec<(&Stack<'a>, Metrics)> {
+        let mut stacks: Vec<(&Stack<'a>, Metrics)> = self
+            .stacks
+            .iter()
+            .filter_map(|stack| stack.thread(totals_selector.clone()).map(|thread| (stack, thread.metrics())))
+            .collect();
+
+        stacks.sort_by(|(a, a_metrics), (b, b_metrics)| {
+            b_metrics.get(metric).cmp(&a_metrics.get(metric)).then_with(|| a.addrs.cmp(&b.addrs))
+        });
+        stacks.truncate(n);
+        stacks
+    }
+
+    /// Scores every stack with a `t*` row by both the memory it holds and the
+    /// memory it churns through, highest first, ties broken by address:
+    ///
+    /// `score = weights.live * inuse_space + weights.freed * (alloc_space - inuse_space)`
+    ///
+    /// The freed term saturates at zero, and is only meaningful for dumps
+    /// taken with `prof_accum` enabled, since `alloc_space` is otherwise
+    /// always zero.
+    pub fn allocation_score(&self, weights: ScoreWeights) -> Vec<(&Stack<'a>, f64)> {
+        let mut scores: Vec<(&Stack<'a>, f64)> = self
+            .stacks
+            .iter()
+            .filter_map(|stack| {
+                let thread = stack.thread(ThreadId::Total)?;
+                let freed = thread.alloc_space.saturating_sub(thread.insuse_space);
+                Some((stack, weights.live * thread.insuse_space as f64 + weights.freed * freed as f64))
+            })
+            .collect();
+
+        scores.sort_by(|(a, a_score), (b, b_score)| b_score.total_cmp(a_score).then_with(|| a.addrs.cmp(&b.addrs)));
+        scores
+    }
 
-        match result {
-            Ok((_, thread)) => {
-                assert_eq!(thread.id, "*");
-                assert_eq!(thread.inuse_count, 5000);
-                assert_eq!(thread.insuse_space, 6000);
-                assert_eq!(thread.alloc_count, 7000);
-                assert_eq!(thread.alloc_space, 9000);
+    /// The share, in percent, of the selected row's `metric` that `stack`
+    /// accounts for.
+    ///
+    /// Both the stack's value and the denominator come from the row selected
+    /// by `totals_selector`. Returns `0.0` when either row is missing or the
+    /// total is zero.
+    pub fn percent(&self, stack: &Stack, metric: Metric, totals_selector: ThreadId) -> f64 {
+        let total = self.totals_for(totals_selector.clone()).map_or(0, |thread| thread.metrics().get(metric));
+        let value = stack.thread(totals_selector).map_or(0, |thread| thread.metrics().get(metric));
+
+        if total == 0 {
+            return 0.0;
+        }
+
+        value as f64 * 100.0 / total as f64
+    }
+
+    /// The stack with the most frames, with its depth, where runaway
+    /// recursion shows first. Of several equally deep stacks the first in
+    /// dump order is returned.
+    pub fn deepest_stack(&self) -> Option<(&Stack<'a>, usize)> {
+        // max_by_key keeps the last of equal maxima.
+        let stack = self.stacks.iter().rev().max_by_key(|stack| stack.addrs.len())?;
+        Some((stack, stack.addrs.len()))
+    }
+
+    /// How concentrated `metric` is across stacks, as the Gini coefficient
+    /// of the sampled `t*` values of the stacks where it is non-zero.
+    ///
+    /// With `n` stacks holding values `x₁ ≤ … ≤ xₙ` the coefficient is
+    /// `2·Σ i·xᵢ / (n·Σ xᵢ) − (n + 1) / n`. It is `0.0` when every stack
+    /// holds the same amount, death by a thousand cuts, and approaches `1.0`
+    /// as one stack comes to hold everything, one thing to go fix. A single
+    /// stack, or none, gives `0.0`.
+    pub fn concentration(&self, metric: Metric) -> f64 {
+        let mut values: Vec<u64> = self
+            .stacks
+            .iter()
+            .filter_map(|stack| Some(stack.thread(ThreadId::Total)?.metrics().get(metric)))
+            .filter(|value| *value > 0)
+            .collect();
+        values.sort_unstable();
+
+        if values.len() < 2 {
+            return 0.0;
+        }
+
+        let n = values.len() as f64;
+        let total: f64 = values.iter().map(|value| *value as f64).sum();
+
+        let weighted: f64 = values.iter().enumerate().map(|(i, value)| (i + 1) as f64 * *value as f64).sum();
+        2.0 * weighted / (n * total) - (n + 1.0) / n
+    }
+
+    /// Roughly how many bytes the profile occupies, including the heap
+    /// allocations of its stacks, address and thread vectors and mapped
+    /// libraries, e.g. to bound a cache of parsed profiles by footprint.
+    ///
+    /// Vectors count by capacity. Strings count only when owned, so a
+    /// profile borrowing from its input is charged for its vectors but not
+    /// for the input. Allocator overhead is left out.
+    pub fn approx_memory_usage(&self) -> usize {
+        let cow = |s: &Cow<str>| match s {
+            Cow::Borrowed(_) => 0,
+            Cow::Owned(s) => s.capacity(),
+        };
+        let thread = |thread: &Thread| {
+            let id = match &thread.id {
+                ThreadId::Named(name) => cow(name),
+                _ => 0,
+            };
+            id + thread.extra.capacity() * size_of::<(u64, u64)>()
+        };
+        let threads = |threads: &Vec<Thread>| {
+            threads.capacity() * size_of::<Thread>() + threads.iter().map(thread).sum::<usize>()
+        };
+
+        let stacks: usize = self
+            .stacks
+            .iter()
+            .map(|stack| stack.addrs.capacity() * size_of::<u64>() + threads(&stack.threads))
+            .sum();
+        let libraries: usize = self.mapped_libraries.iter().map(|lib| cow(&lib.perms) + cow(&lib.path)).sum();
+
+        size_of::<Self>()
+            + threads(&self.totals)
+            + self.stacks.capacity() * size_of::<Stack>()
+            + stacks
+            + self.mapped_libraries.capacity() * size_of::<MappedLibrary>()
+            + libraries
+    }
+
+    /// The longest chain of callers shared by all of `stacks`, such as the
+    /// result of [`Profile::top`], pointing at the code responsible for all
+    /// of them.
+    ///
+    /// Stacks are matched from the root, since callers sit at the end of
+    /// [`Stack::addrs`], and the chain is returned in the same leaf-to-root
+    /// order, so its first address is the deepest frame they have in common.
+    /// Empty when `stacks` is empty or the roots differ.
+    pub fn common_prefix(&self, stacks: &[&Stack]) -> Vec<u64> {
+        let Some((first, rest)) = stacks.split_first() else {
+            return Vec::new();
+        };
+
+        let mut len = first.addrs.len();
+        for stack in rest {
+            len = first.addrs.iter().rev().zip(stack.addrs.iter().rev()).take(len).take_while(|(a, b)| a == b).count();
+        }
+
+        first.addrs[first.addrs.len() - len..].to_vec()
+    }
+}
+
+/// `stack` reduced to its row `thread`, which is also copied into `t*`.
+fn thread_stack(stack: &Stack, thread: &Thread) -> Stack<'static> {
+    Stack {
+        addrs: stack.addrs.clone(),
+        threads: vec![
+            Thread::from_metrics(ThreadId::Total, thread.metrics()),
+            thread.clone().into_owned(),
+        ],
+    }
+}
+
+/// Drops the UTF-8 byte order mark some Windows tools prepend to text files.
+fn strip_bom(input: &str) -> &str {
+    input.strip_prefix('\u{FEFF}').unwrap_or(input)
+}
+
+/// Skips the lines that capture scripts and wrappers put in front of a
+/// dump: any number of blank lines and `#` comment lines, and up to
+/// [`MAX_PREAMBLE_LINES`] other lines if a header follows them. Without a
+/// header in reach, stops at the first line that is neither blank nor a
+/// comment.
+fn skip_preamble(input: &str) -> &str {
+    let mut rest = input;
+    let mut first_other = None;
+    let mut others = 0;
+
+    while !rest.is_empty() && !is_header(rest) {
+        let (line, next) = rest.split_once('\n').unwrap_or((rest, ""));
+        if !line.trim().is_empty() && !line.starts_with('#') {
+            first_other.get_or_insert(rest);
+            others += 1;
+            if others > MAX_PREAMBLE_LINES {
+                break;
             }
-            Err(err) => panic!("Parsing failed with error: {:?}", err),
         }
+        rest = next;
+    }
+
+    if is_header(rest) {
+        rest
+    } else {
+        first_other.unwrap_or(rest)
+    }
+}
+
+/// Whether `input` starts with a `heap_v2/` header, optionally written with
+/// a leading `@`.
+pub(crate) fn is_header(input: &str) -> bool {
+    let input = input.strip_prefix('@').unwrap_or(input);
+    input.starts_with(HEAP_V2_HEADER) && input[HEAP_V2_HEADER.len()..].starts_with('/')
+}
+
+/// The factor by which jeprof scales a sampled `(space, count)` pair to
+/// estimate the true values. A sampling rate of zero means every allocation
+/// was recorded.
+fn unsampling_factor(sampling_rate: u64, space: u64, count: u64) -> f64 {
+    if sampling_rate == 0 || count == 0 || space == 0 {
+        return 1.0;
+    }
+
+    let ratio = (space as f64 / count as f64) / sampling_rate as f64;
+    1.0 / (1.0 - (-ratio).exp())
+}
+
+fn format_thread_line(id: &ThreadId, metrics: &Metrics, extra: &[(u64, u64)]) -> String {
+    let mut line = format!(
+        "  {}: {}: {} [{}: {}]",
+        id, metrics.inuse_count, metrics.inuse_space, metrics.alloc_count, metrics.alloc_space
+    );
+    for (a, b) in extra {
+        line.push_str(&format!(" [{}: {}]", a, b));
+    }
+    line.push('\n');
+    line
+}
+
+fn escape_label_value(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// A sampled backtrace and the per-thread counters recorded for it.
+///
+/// `addrs` is kept in the order jemalloc writes it: leaf to root, i.e. the
+/// first address is the innermost frame (the allocation site) and the last is
+/// the outermost caller. The parser never reorders addresses, so two stacks
+/// whose addresses appear in opposite orders are different call paths.
+///
+/// `Debug` prints addresses in `0x`-prefixed hex, as disassemblers and
+/// `/proc/<pid>/maps` do.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Stack<'a> {
+    pub addrs: Vec<u64>,
+    pub threads: Vec<Thread<'a>>,
+}
+
+impl<'a> Stack<'a> {
+    /// A copy of this stack in canonical form, suitable for comparing
+    /// captures with `==`.
+    ///
+    /// Addresses stay leaf to root. Thread rows are sorted with `t*` first,
+    /// then numeric ids ascending, then any other ids by name, so the order
+    /// in which jemalloc happened to list threads does not matter.
+    pub fn canonical(&self) -> Stack<'a> {
+        let mut threads = self.threads.clone();
+        threads.sort_by(|a, b| a.id.cmp(&b.id));
+
+        Stack {
+            addrs: self.addrs.clone(),
+            threads,
+        }
+    }
+
+    pub fn into_owned(self) -> Stack<'static> {
+        Stack {
+            addrs: self.addrs,
+            threads: self.threads.into_iter().map(Thread::into_owned).collect(),
+        }
+    }
+
+    /// The innermost frame, i.e. the allocation site.
+    pub fn leaf(&self) -> Option<u64> {
+        self.addrs.first().copied()
+    }
+
+    /// The outermost frame, i.e. the entry point of the thread.
+    pub fn root(&self) -> Option<u64> {
+        self.addrs.last().copied()
+    }
+
+    /// The identity of this stack, for use as a map key when matching stacks
+    /// across profiles.
+    pub fn key(&self) -> StackKey<'_> {
+        StackKey(&self.addrs)
+    }
+
+    /// The row of this stack belonging to `id`.
+    pub fn thread(&self, id: ThreadId) -> Option<&Thread<'a>> {
+        self.threads.iter().find(|thread| thread.id == id)
+    }
+
+    /// The average size of the objects live at this stack, from its `t*`
+    /// row. `None` when nothing is live or the stack has no `t*` row.
+    pub fn avg_alloc_size(&self) -> Option<u64> {
+        self.thread(ThreadId::Total)?.metrics().avg_alloc_size()
     }
+
+    /// The share of the bytes ever allocated at this stack that are still
+    /// live, `inuse_space / alloc_space` of its `t*` row. Near `1.0` hints
+    /// at a leak, near `0.0` at allocations that are freed as fast as they
+    /// are made.
+    ///
+    /// `None` when `alloc_space` is zero, which is also the case when the
+    /// dump was taken without `prof_accum`, or the stack has no `t*` row.
+    pub fn retention(&self) -> Option<f64> {
+        let totals = self.thread(ThreadId::Total)?;
+        if totals.alloc_space == 0 {
+            return None;
+        }
+
+        Some(totals.insuse_space as f64 / totals.alloc_space as f64)
+    }
+
+    /// Every row of this stack with its metrics, sorted by thread id, so the
+    /// `t*` row comes first and the threads contributing to it follow.
+    pub fn thread_table(&self) -> Vec<(ThreadId<'a>, Metrics)> {
+        let mut table: Vec<(ThreadId<'a>, Metrics)> =
+            self.threads.iter().map(|thread| (thread.id.clone(), thread.metrics())).collect();
+        table.sort_by(|(a, _), (b, _)| a.cmp(b));
+        table
+    }
+}
+
+impl fmt::Debug for Stack<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Stack")
+            .field("addrs", &HexList(&self.addrs))
+            .field("threads", &self.threads)
+            .finish()
+    }
+}
+
+/// The identity of a [`Stack`]: its addresses, leaf to root.
+///
+/// Two stacks are the same call path exactly when their keys are equal; the
+/// thread rows they carry play no part.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct StackKey<'s>(pub &'s [u64]);
+
+impl fmt::Debug for StackKey<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("StackKey").field(&HexList(self.0)).finish()
+    }
+}
+
+/// Where a frame lies once library load addresses are factored out, see
+/// [`Profile::frame_key`].
+///
+/// Different libraries can share an offset, so the key always carries the
+/// library as well.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum FrameKey {
+    /// `offset` into the file mapped by `mapped_libraries[library]`.
+    Library { library: usize, offset: u64 },
+    /// An address outside every mapped library.
+    Absolute(u64),
+}
+
+/// A bucket of [`Profile::leaf_library_breakdown`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum LibraryRef<'p> {
+    /// The mapped file at this path, across all of its mappings.
+    Path(&'p str),
+    /// Leaves outside every mapped library, such as anonymous mappings or
+    /// JIT code.
+    Anonymous,
+}
+
+/// The pointer width of the process a profile was dumped from, as guessed
+/// by [`Profile::address_width`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AddressWidth {
+    Bits32,
+    Bits64,
+}
+
+impl AddressWidth {
+    /// The width in bits.
+    pub fn bits(self) -> u32 {
+        match self {
+            AddressWidth::Bits32 => 32,
+            AddressWidth::Bits64 => 64,
+        }
+    }
+}
+
+/// Identifies a thread row in a profile.
+///
+/// Ids order as `t*` first, then numeric ids ascending, then named ids.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum ThreadId<'a> {
+    /// The `t*` row aggregating every thread.
+    #[default]
+    Total,
+    /// A `t<n>` row for a single thread.
+    Numeric(u64),
+    /// A row whose id is not a decimal number, as written by wrappers that
+    /// label threads by name.
+    Named(Cow<'a, str>),
+}
+
+impl<'a> ThreadId<'a> {
+    /// Classifies the id of a `t<id>:` thread line.
+    pub fn from_id(id: &'a str) -> Self {
+        if id == "*" {
+            return ThreadId::Total;
+        }
+
+        if !id.is_empty() && id.bytes().all(|b| b.is_ascii_digit()) {
+            if let Ok(n) = id.parse() {
+                return ThreadId::Numeric(n);
+            }
+        }
+
+        ThreadId::Named(Cow::Borrowed(id))
+    }
+
+    pub fn into_owned(self) -> ThreadId<'static> {
+        match self {
+            ThreadId::Total => ThreadId::Total,
+            ThreadId::Numeric(n) => ThreadId::Numeric(n),
+            ThreadId::Named(name) => ThreadId::Named(Cow::Owned(name.into_owned())),
+        }
+    }
+}
+
+/// Formats the id as a thread line labels it: `t*`, `t5` or `t<name>`.
+impl fmt::Display for ThreadId<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ThreadId::Total => write!(f, "t*"),
+            ThreadId::Numeric(n) => write!(f, "t{}", n),
+            ThreadId::Named(name) => write!(f, "t{}", name),
+        }
+    }
+}
+
+/// Parses the [`Display`](fmt::Display) form, `t` followed by the id as in
+/// [`ThreadId::from_id`]. A bare `*` is accepted for [`ThreadId::Total`].
+impl FromStr for ThreadId<'static> {
+    type Err = UnknownName;
+
+    fn from_str(s: &str) -> Result<Self, UnknownName> {
+        if s == "*" {
+            return Ok(ThreadId::Total);
+        }
+
+        match s.strip_prefix('t') {
+            Some(id) if !id.is_empty() => Ok(ThreadId::from_id(id).into_owned()),
+            _ => Err(UnknownName(s.to_string())),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Thread<'a> {
+    pub id: ThreadId<'a>,
+    pub inuse_count: u64,
+    pub insuse_space: u64,
+    pub alloc_count: u64,
+    pub alloc_space: u64,
+    /// The `[<a>: <b>]` brackets that follow the alloc counters on the
+    /// lines of some jemalloc builds, such as extent stats, in order. Empty
+    /// for the standard format.
+    pub extra: Vec<(u64, u64)>,
+}
+
+impl<'a> Thread<'a> {
+    pub(crate) fn from_metrics(id: ThreadId<'a>, metrics: Metrics) -> Self {
+        Thread {
+            id,
+            inuse_count: metrics.inuse_count,
+            insuse_space: metrics.inuse_space,
+            alloc_count: metrics.alloc_count,
+            alloc_space: metrics.alloc_space,
+            extra: Vec::new(),
+        }
+    }
+
+    /// The four numeric fields of this thread row, bundled together.
+    pub fn metrics(&self) -> Metrics {
+        Metrics {
+            inuse_count: self.inuse_count,
+            inuse_space: self.insuse_space,
+            alloc_count: self.alloc_count,
+            alloc_space: self.alloc_space,
+        }
+    }
+
+    pub fn into_owned(self) -> Thread<'static> {
+        Thread {
+            id: self.id.into_owned(),
+            inuse_count: self.inuse_count,
+            insuse_space: self.insuse_space,
+            alloc_count: self.alloc_count,
+            alloc_space: self.alloc_space,
+            extra: self.extra,
+        }
+    }
+}
+
+/// The counters jemalloc records for a thread row.
+///
+/// Addition and subtraction saturate instead of overflowing, so deltas
+/// between two captures never wrap around.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Metrics {
+    pub inuse_count: u64,
+    pub inuse_space: u64,
+    pub alloc_count: u64,
+    pub alloc_space: u64,
+}
+
+impl Metrics {
+    /// The counter selected by `metric`.
+    pub fn get(&self, metric: Metric) -> u64 {
+        match metric {
+            Metric::InuseSpace => self.inuse_space,
+            Metric::InuseCount => self.inuse_count,
+            Metric::AllocSpace => self.alloc_space,
+            Metric::AllocCount => self.alloc_count,
+        }
+    }
+
+    /// The average size of the live objects, `inuse_space / inuse_count`.
+    /// `None` when nothing is live.
+    pub fn avg_alloc_size(&self) -> Option<u64> {
+        self.inuse_space.checked_div(self.inuse_count)
+    }
+
+    /// Every counter multiplied by `factor`, rounded and clamped as in
+    /// [`Profile::scale`].
+    pub fn scale(self, factor: f64) -> Metrics {
+        // Float to integer casts saturate, and map NaN to zero.
+        let scale = |value: u64| (value as f64 * factor).round() as u64;
+        Metrics {
+            inuse_count: scale(self.inuse_count),
+            inuse_space: scale(self.inuse_space),
+            alloc_count: scale(self.alloc_count),
+            alloc_space: scale(self.alloc_space),
+        }
+    }
+
+    /// Estimates the true counters from sampled ones, as jeprof does: the
+    /// in-use pair is scaled by the [unsampling factor](Profile::estimated_live_objects)
+    /// of its average object size, and so is the allocated pair.
+    pub fn unsampled(self, sampling_rate: u64) -> Metrics {
+        let scale = |value: u64, factor: f64| (value as f64 * factor).round() as u64;
+        let inuse = unsampling_factor(sampling_rate, self.inuse_space, self.inuse_count);
+        let alloc = unsampling_factor(sampling_rate, self.alloc_space, self.alloc_count);
+        Metrics {
+            inuse_count: scale(self.inuse_count, inuse),
+            inuse_space: scale(self.inuse_space, inuse),
+            alloc_count: scale(self.alloc_count, alloc),
+            alloc_space: scale(self.alloc_space, alloc),
+        }
+    }
+}
+
+/// Selects one of the counters of a [`Metrics`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Metric {
+    /// Bytes currently allocated.
+    #[default]
+    InuseSpace,
+    /// Objects currently allocated.
+    InuseCount,
+    /// Bytes allocated since profiling started.
+    AllocSpace,
+    /// Objects allocated since profiling started.
+    AllocCount,
+}
+
+impl Metric {
+    /// The metric's name, the same as the field of [`Metrics`] it selects.
+    pub fn name(self) -> &'static str {
+        match self {
+            Metric::InuseSpace => "inuse_space",
+            Metric::InuseCount => "inuse_count",
+            Metric::AllocSpace => "alloc_space",
+            Metric::AllocCount => "alloc_count",
+        }
+    }
+}
+
+impl fmt::Display for Metric {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Parses [`Metric::name`], or the `inuse_objects` and `alloc_objects`
+/// spelling of the counts that jeprof and pprof use.
+impl FromStr for Metric {
+    type Err = UnknownName;
+
+    fn from_str(s: &str) -> Result<Self, UnknownName> {
+        match s {
+            "inuse_space" => Ok(Metric::InuseSpace),
+            "inuse_count" | "inuse_objects" => Ok(Metric::InuseCount),
+            "alloc_space" => Ok(Metric::AllocSpace),
+            "alloc_count" | "alloc_objects" => Ok(Metric::AllocCount),
+            _ => Err(UnknownName(s.to_string())),
+        }
+    }
+}
+
+/// A string that names no [`Metric`] or [`ThreadId`], returned by their
+/// [`FromStr`] impls.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownName(pub String);
+
+impl fmt::Display for UnknownName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unknown name {:?}", self.0)
+    }
+}
+
+impl Error for UnknownName {}
+
+/// Whether exported values are the counters as dumped or estimates of the
+/// true values, see [`Metrics::unsampled`]. Exporters taking one say which
+/// in their output where the format has room for it.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ValueMode {
+    /// The sampled counters, as jemalloc wrote them.
+    #[default]
+    Sampled,
+    /// The counters corrected for sampling, as jeprof reports them.
+    Unsampled,
+}
+
+impl ValueMode {
+    /// `sampled` or `unsampled`, as outputs are annotated with.
+    pub fn name(self) -> &'static str {
+        match self {
+            ValueMode::Sampled => "sampled",
+            ValueMode::Unsampled => "unsampled",
+        }
+    }
+
+    pub(crate) fn apply(self, metrics: Metrics, sampling_rate: u64) -> Metrics {
+        match self {
+            ValueMode::Sampled => metrics,
+            ValueMode::Unsampled => metrics.unsampled(sampling_rate),
+        }
+    }
+}
+
+impl Add for Metrics {
+    type Output = Metrics;
+
+    fn add(self, rhs: Metrics) -> Metrics {
+        Metrics {
+            inuse_count: self.inuse_count.saturating_add(rhs.inuse_count),
+            inuse_space: self.inuse_space.saturating_add(rhs.inuse_space),
+            alloc_count: self.alloc_count.saturating_add(rhs.alloc_count),
+            alloc_space: self.alloc_space.saturating_add(rhs.alloc_space),
+        }
+    }
+}
+
+impl Sub for Metrics {
+    type Output = Metrics;
+
+    fn sub(self, rhs: Metrics) -> Metrics {
+        Metrics {
+            inuse_count: self.inuse_count.saturating_sub(rhs.inuse_count),
+            inuse_space: self.inuse_space.saturating_sub(rhs.inuse_space),
+            alloc_count: self.alloc_count.saturating_sub(rhs.alloc_count),
+            alloc_space: self.alloc_space.saturating_sub(rhs.alloc_space),
+        }
+    }
+}
+
+/// The access bits of a mapping, as in the `r-xp` field of the maps
+/// section.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Permissions {
+    pub read: bool,
+    pub write: bool,
+    pub execute: bool,
+    /// Copy-on-write (`p`) rather than shared (`s`).
+    pub private: bool,
+}
+
+/// One line of the maps section. `Debug` prints addresses and the offset in
+/// hex.
+#[derive(Clone)]
+pub struct MappedLibrary<'a> {
+    pub(crate) first: u64,
+    pub(crate) last: u64,
+    pub(crate) offset: u64,
+    pub(crate) perms: Cow<'a, str>,
+    pub(crate) path: Cow<'a, str>,
+    pub(crate) deleted: bool,
+}
+
+impl<'a> MappedLibrary<'a> {
+    /// The offset within the mapped file that `addr` corresponds to, `None`
+    /// when `addr` lies outside `[first, last)`.
+    pub(crate) fn file_offset(&self, addr: u64) -> Option<u64> {
+        if addr >= self.last {
+            return None;
+        }
+        addr.checked_sub(self.first)?.checked_add(self.offset)
+    }
+
+    /// The mapping's access bits. Only executable mappings hold code, so
+    /// filtering on [`Permissions::execute`] keeps data mappings of the same
+    /// file from being symbolized.
+    pub fn permissions(&self) -> Permissions {
+        let bits = self.perms.as_bytes();
+        let bit = |index: usize, set: u8| bits.get(index) == Some(&set);
+        Permissions {
+            read: bit(0, b'r'),
+            write: bit(1, b'w'),
+            execute: bit(2, b'x'),
+            private: bit(3, b'p'),
+        }
+    }
+
+    /// Whether the file was unlinked while mapped, which the maps section
+    /// marks with a trailing ` (deleted)`. The marker is not part of the
+    /// path.
+    pub fn is_deleted(&self) -> bool {
+        self.deleted
+    }
+
+    pub fn into_owned(self) -> MappedLibrary<'static> {
+        MappedLibrary {
+            first: self.first,
+            last: self.last,
+            offset: self.offset,
+            perms: Cow::Owned(self.perms.into_owned()),
+            path: Cow::Owned(self.path.into_owned()),
+            deleted: self.deleted,
+        }
+    }
+}
+
+impl fmt::Debug for MappedLibrary<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MappedLibrary")
+            .field("first", &Hex(self.first))
+            .field("last", &Hex(self.last))
+            .field("offset", &Hex(self.offset))
+            .field("perms", &self.perms)
+            .field("path", &self.path)
+            .field("deleted", &self.deleted)
+            .finish()
+    }
+}
+
+/// Debug-formats as `0x`-prefixed hex.
+struct Hex(u64);
+
+impl fmt::Debug for Hex {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#x}", self.0)
+    }
+}
+
+struct HexList<'s>(&'s [u64]);
+
+impl fmt::Debug for HexList<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.0.iter().map(|addr| Hex(*addr))).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{parse_stack, parse_thread};
+
+    #[test]
+    fn test_check_mapping_overlaps() {
+        let data = "heap_v2/524288
+  t*: 1: 16 [0: 0]
+@ 0x1
+  t*: 1: 16 [0: 0]
+
+MAPPED_LIBRARIES:
+00001000-00002000 r--p 00000000 103:02 5000 /usr/lib/liba.so
+00002000-00003000 r--p 00000000 103:02 5000 /usr/lib/libb.so
+";
+        let profile = Profile::parse(data).unwrap();
+        assert!(profile.check_mapping_overlaps().is_ok());
+
+        let overlapping = format!("{}00002800-00004000 r--p 00000000 103:02 5000 /usr/lib/libc.so\n", data);
+        let profile = Profile::parse(&overlapping).unwrap();
+        let err = profile.check_mapping_overlaps().unwrap_err().to_string();
+        assert!(err.contains("/usr/lib/libb.so"));
+        assert!(err.contains("/usr/lib/libc.so"));
+        assert!(!err.contains("/usr/lib/liba.so"));
+
+        let empty = format!("{}00002800-00002800 r--p 00000000 103:02 5000 /usr/lib/libe.so\n", data);
+        assert!(Profile::parse(&empty).unwrap().check_mapping_overlaps().is_ok());
+
+        let nested = format!(
+            "{}00010000-00020000 r--p 00000000 103:02 5000 /usr/lib/libwide.so
+00011000-00012000 r--p 00000000 103:02 5000 /usr/lib/libd.so
+00011000-00012000 r--p 00000000 103:02 5000 /usr/lib/libd.so
+",
+            data
+        );
+        let err = Profile::parse(&nested).unwrap().check_mapping_overlaps().unwrap_err().to_string();
+        let duplicate = "/usr/lib/libd.so [0x11000, 0x12000) overlaps /usr/lib/libd.so [0x11000, 0x12000)";
+        assert!(err.contains(duplicate), "{}", err);
+        assert_eq!(2, err.matches("/usr/lib/libwide.so").count());
+    }
+
+    #[test]
+    fn test_dedup_libraries() {
+        let data = "heap_v2/524288
+  t*: 1: 16 [0: 0]
+@ 0x1
+  t*: 1: 16 [0: 0]
+
+MAPPED_LIBRARIES:
+00001000-00002000 r--p 00000000 103:02 5000 /usr/lib/liba.so
+00002000-00003000 r--p 00000000 103:02 5000 /usr/lib/libb.so
+00001000-00002000 r--p 00000000 103:02 5000 /usr/lib/liba.so
+00001000-00002000 r--p 00000000 103:02 5000 /usr/lib/libc.so
+00002000-00003000 r--p 00000000 103:02 5000 /usr/lib/libb.so
+";
+        let mut profile = Profile::parse(data).unwrap();
+        assert_eq!(5, profile.mapped_libraries.len());
+
+        profile.dedup_libraries();
+        let paths: Vec<&str> = profile.mapped_libraries.iter().map(|lib| lib.path.as_ref()).collect();
+        assert_eq!(vec!["/usr/lib/liba.so", "/usr/lib/libb.so", "/usr/lib/libc.so"], paths);
+    }
+
+    #[test]
+    fn test_sort_libraries() {
+        let data = "heap_v2/524288
+  t*: 1: 16 [0: 0]
+@ 0x1010
+  t*: 1: 16 [0: 0]
+
+MAPPED_LIBRARIES:
+00003000-00004000 r--p 00000000 103:02 5000 /usr/lib/libc.so
+00001000-00002000 r--p 00000000 103:02 5000 /usr/lib/liba.so
+00002000-00003000 r--p 00000000 103:02 5000 /usr/lib/libb.so
+00001000-00001800 r--p 00000000 103:02 5000 /usr/lib/libd.so
+";
+        let mut profile = Profile::parse(data).unwrap();
+        assert_eq!("/usr/lib/liba.so", profile.library_for_addr(0x1010).unwrap().path);
+
+        profile.sort_libraries();
+        let paths: Vec<&str> = profile.mapped_libraries.iter().map(|lib| lib.path.as_ref()).collect();
+        assert_eq!(vec!["/usr/lib/liba.so", "/usr/lib/libd.so", "/usr/lib/libb.so", "/usr/lib/libc.so"], paths);
+        assert_eq!("/usr/lib/liba.so", profile.library_for_addr(0x1010).unwrap().path);
+    }
+
+    #[test]
+    fn test_retain_libraries() {
+        let data = "heap_v2/524288
+  t*: 1: 16 [0: 0]
+@ 0x1010
+  t*: 1: 16 [0: 0]
+
+MAPPED_LIBRARIES:
+00001000-00002000 r-xp 00000000 103:02 5000 /srv/app/bin/server
+00002000-00003000 r-xp 00000000 103:02 5000 /usr/lib/libc.so.6
+00003000-00004000 r-xp 00000000 103:02 5000 /srv/app/lib/libapp.so
+";
+        let mut profile = Profile::parse(data).unwrap();
+        profile.retain_libraries_matching(&Regex::new("^/srv/app/").unwrap());
+        let paths: Vec<&str> = profile.mapped_libraries.iter().map(|lib| lib.path.as_ref()).collect();
+        assert_eq!(vec!["/srv/app/bin/server", "/srv/app/lib/libapp.so"], paths);
+        assert!(profile.library_for_addr(0x2010).is_none());
+
+        profile.retain_libraries(|lib| lib.first != 0x1000);
+        assert!(profile.library_for_addr(0x1010).is_none());
+        assert_eq!(1, profile.mapped_libraries.len());
+    }
+
+    #[test]
+    fn test_estimated_live_objects() {
+        let data = "heap_v2/524288
+  t*: 12: 1048672 [0: 0]
+@ 0x1
+  t*: 10: 160 [0: 0]
+@ 0x2
+  t*: 2: 1048576 [0: 0]
+@ 0x3
+  t*: 0: 0 [0: 0]
+";
+        let profile = Profile::parse(data).unwrap();
+
+        // 10 objects of 16 bytes are sampled with probability ~1/32768 each,
+        // 2 objects of 512KiB with probability 1 - e^-1.
+        let small = 10.0 / (1.0 - (-16.0f64 / 524288.0).exp());
+        let large = 2.0 / (1.0 - (-1.0f64).exp());
+        assert_eq!((small + large).round() as u64, profile.estimated_live_objects());
+        assert_eq!(327688, profile.estimated_live_objects());
+
+        let unsampled = Profile::parse("heap_v2/0\n  t*: 3: 48 [0: 0]\n@ 0x1\n  t*: 3: 48 [0: 0]\n").unwrap();
+        assert_eq!(3, unsampled.estimated_live_objects());
+    }
+
+    #[test]
+    fn test_to_heap_text() {
+        let data = "heap_v2/524288
+  t*: 3: 48 [4: 64]
+  t0: 1: 16 [1: 16]
+  t1: 2: 32 [3: 48]
+@ 0x1 0x2
+  t*: 1: 16 [1: 16]
+  t0: 1: 16 [1: 16]
+@ 0x3
+  t*: 2: 32 [3: 48]
+  t1: 2: 32 [3: 48]
+
+MAPPED_LIBRARIES:
+00001000-00002000 r-xp 00001000 103:02 5000 /usr/lib/liba.so
+";
+        let mut profile = Profile::parse(data).unwrap();
+        profile.stacks.remove(0);
+
+        let text = profile.to_heap_text();
+        assert_eq!("heap_v2/524288
+  t*: 2: 32 [3: 48]
+  t0: 0: 0 [0: 0]
+  t1: 2: 32 [3: 48]
+@ 0x3
+  t*: 2: 32 [3: 48]
+  t1: 2: 32 [3: 48]
+
+MAPPED_LIBRARIES:
+1000-2000 r-xp 00001000 00:00 0 /usr/lib/liba.so
+", text);
+
+        let extra = "heap_v2/524288
+  t*: 2: 32 [2: 32] [2: 8192]
+@ 0x1
+  t*: 1: 16 [1: 16] [1: 4096]
+@ 0x2
+  t*: 1: 16 [1: 16] [1: 4096] [7: 8]
+";
+        let extra_text = Profile::parse(extra).unwrap().to_heap_text();
+        assert!(extra_text.starts_with("heap_v2/524288
+  t*: 2: 32 [2: 32] [2: 8192] [7: 8]
+@ 0x1
+  t*: 1: 16 [1: 16] [1: 4096]
+@ 0x2
+  t*: 1: 16 [1: 16] [1: 4096] [7: 8]
+"), "{}", extra_text);
+        assert_eq!(vec![(1, 4096), (7, 8)], Profile::parse(&extra_text).unwrap().stacks[1].threads[0].extra);
+
+        let reparsed = Profile::parse(&text).unwrap();
+        assert_eq!(profile.stacks, reparsed.stacks);
+        assert_eq!(1, reparsed.mapped_libraries.len());
+        assert_eq!(text, reparsed.to_heap_text());
+    }
+
+    #[test]
+    fn test_metric_percentiles() {
+        let mut data = String::from("heap_v2/524288\n  t*: 0: 0 [0: 0]\n");
+        for space in [40, 10, 30, 20, 100] {
+            data.push_str(&format!("@ 0x{:x}\n  t*: 1: {} [1: {}]\n", space, space, space));
+        }
+        let profile = Profile::parse(&data).unwrap();
+
+        assert_eq!(
+            vec![10, 30, 76, 98, 100, 10],
+            profile.metric_percentiles(Metric::InuseSpace, &[0.0, 50.0, 90.0, 99.0, 100.0, -5.0])
+        );
+        assert_eq!(vec![1], profile.metric_percentiles(Metric::InuseCount, &[50.0]));
+
+        let empty = Profile::parse("heap_v2/524288\n  t*: 0: 0 [0: 0]\n@ 0x1\n  t0: 0: 0 [0: 0]\n").unwrap();
+        assert_eq!(vec![0, 0], empty.metric_percentiles(Metric::InuseSpace, &[50.0, 99.0]));
+    }
+
+    #[test]
+    fn test_unique_addresses() {
+        let data = "heap_v2/524288
+  t*: 2: 32 [0: 0]
+@ 0x30 0x10 0x20
+  t*: 1: 16 [0: 0]
+@ 0x10 0x30
+  t*: 1: 16 [0: 0]
+";
+        let profile = Profile::parse(data).unwrap();
+        assert_eq!(vec![0x10, 0x20, 0x30], profile.unique_addresses().into_iter().collect::<Vec<_>>());
+        assert!(OwnedProfile::empty(1).unique_addresses().is_empty());
+    }
+
+    #[test]
+    fn test_location_table() {
+        let data = "heap_v2/524288
+  t*: 2: 32 [0: 0]
+@ 0x30 0x10 0x20
+  t*: 1: 16 [0: 0]
+@ 0x10 0xffffffffffffff00
+  t*: 1: 16 [0: 0]
+";
+        let profile = Profile::parse(data).unwrap();
+        let (addrs, ids) = profile.location_table();
+
+        assert_eq!(vec![0x10, 0x20, 0x30, 0xffffffffffffff00], addrs);
+        assert_eq!(4, ids.len());
+        for (id, addr) in addrs.iter().enumerate() {
+            assert_eq!(id as u32, ids[addr]);
+        }
+    }
+
+    #[test]
+    fn test_group_by_app_frame() {
+        let data = "heap_v2/524288
+  t*: 7: 112 [0: 0]
+@ 0x1010 0x2010 0x3010
+  t*: 1: 16 [0: 0]
+@ 0x1020 0x2020 0x3010
+  t*: 2: 32 [0: 0]
+@ 0x1010 0x3010
+  t*: 3: 48 [0: 0]
+@ 0x1010 0x2010
+  t*: 1: 16 [0: 0]
+
+MAPPED_LIBRARIES:
+00001000-00002000 r-xp 00000000 103:02 5000 /usr/lib/libjemalloc.so.2
+00002000-00003000 r-xp 00000000 103:02 5000 /usr/lib/libstdc++.so.6
+00003000-00004000 r-xp 00000000 103:02 5000 /opt/app/server
+";
+        let profile = Profile::parse(data).unwrap();
+        let skip = [Regex::new("libjemalloc").unwrap(), Regex::new(r"libstdc\+\+").unwrap()];
+
+        let groups = profile.group_by_app_frame(&skip, Metric::InuseSpace);
+        assert_eq!(2, groups.len());
+        assert_eq!(Some(0x3010), groups[0].0);
+        assert_eq!(Metrics { inuse_count: 6, inuse_space: 96, alloc_count: 0, alloc_space: 0 }, groups[0].1);
+        assert_eq!(None, groups[1].0);
+        assert_eq!(16, groups[1].1.inuse_space);
+
+        let groups = profile.group_by_app_frame(&[], Metric::InuseSpace);
+        assert_eq!(vec![Some(0x1010), Some(0x1020)], groups.iter().map(|(frame, _)| *frame).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_frame_key() {
+        let data = "heap_v2/524288
+  t*: 3: 48 [0: 0]
+@ 0x1010
+  t*: 1: 16 [0: 0]
+@ 0x5010
+  t*: 2: 32 [0: 0]
+@ 0x9000
+  t*: 0: 0 [0: 0]
+
+MAPPED_LIBRARIES:
+00001000-00002000 r-xp 00000000 103:02 5000 /opt/a/libfoo.so
+00005000-00006000 r-xp 00000000 103:02 5001 /opt/b/libfoo.so
+";
+        let profile = Profile::parse(data).unwrap();
+        assert_eq!(FrameKey::Library { library: 0, offset: 0x10 }, profile.frame_key(0x1010));
+        assert_eq!(FrameKey::Library { library: 1, offset: 0x10 }, profile.frame_key(0x5010));
+        assert_eq!(FrameKey::Absolute(0x9000), profile.frame_key(0x9000));
+
+        assert_eq!(
+            "/opt/b/libfoo.so+0x10 32\n/opt/a/libfoo.so+0x10 16\n",
+            profile.to_folded_with(Metric::InuseSpace, FoldedFrames::LibraryOffsets)
+        );
+        assert_eq!(3, profile.to_text_report(Metric::InuseSpace, 10, ValueMode::Sampled).lines().count());
+    }
+
+    #[test]
+    fn test_anonymous_fraction() {
+        let data = "heap_v2/524288
+  t*: 3: 64 [0: 0]
+@ 0x1010 0x9000
+  t*: 1: 16 [0: 0]
+@ 0x9000 0x1010
+  t*: 2: 48 [0: 0]
+
+MAPPED_LIBRARIES:
+00001000-00002000 r-xp 00000000 103:02 5000 /usr/lib/libfoo.so
+";
+        let mut profile = Profile::parse(data).unwrap();
+        assert_eq!(0.75, profile.anonymous_fraction());
+
+        profile.mapped_libraries.clear();
+        assert_eq!(1.0, profile.anonymous_fraction());
+        assert_eq!(0.0, Profile::empty(1).anonymous_fraction());
+    }
+
+    #[test]
+    fn test_dominant_library() {
+        let data = "heap_v2/524288
+  t*: 4: 100 [0: 0]
+@ 0x1010
+  t*: 1: 20 [0: 0]
+@ 0x2010 0x1010
+  t*: 2: 60 [0: 0]
+@ 0x9000
+  t*: 1: 20 [0: 0]
+
+MAPPED_LIBRARIES:
+00001000-00002000 r-xp 00000000 103:02 5000 /usr/lib/liba.so
+00002000-00003000 r-xp 00000000 103:02 5001 /usr/lib/libb.so
+";
+        let profile = Profile::parse(data).unwrap();
+        let (library, share) = profile.dominant_library().unwrap();
+        assert_eq!("/usr/lib/libb.so", library.path);
+        assert_eq!(0.6, share);
+
+        let anonymous = Profile::parse("heap_v2/1\n  t*: 1: 8 [0: 0]\n@ 0x9000\n  t*: 1: 8 [0: 0]\n").unwrap();
+        assert!(anonymous.dominant_library().is_none());
+        assert!(Profile::empty(1).dominant_library().is_none());
+    }
+
+    #[test]
+    fn test_leaf_library_breakdown() {
+        let data = "heap_v2/524288
+  t*: 5: 100 [0: 0]
+@ 0x1010
+  t*: 1: 20 [0: 0]
+@ 0x2010 0x1010
+  t*: 2: 40 [0: 0]
+@ 0x3010
+  t*: 1: 20 [0: 0]
+@ 0x9000
+  t*: 1: 20 [0: 0]
+
+MAPPED_LIBRARIES:
+00001000-00002000 r-xp 00000000 103:02 5000 /usr/lib/liba.so
+00002000-00003000 r-xp 00000000 103:02 5001 /usr/lib/libb.so
+00003000-00004000 r-xp 00001000 103:02 5000 /usr/lib/liba.so
+";
+        let profile = Profile::parse(data).unwrap();
+        let (liba, libb) = (LibraryRef::Path("/usr/lib/liba.so"), LibraryRef::Path("/usr/lib/libb.so"));
+        assert_eq!(
+            vec![(liba, 40), (libb, 40), (LibraryRef::Anonymous, 20)],
+            profile.leaf_library_breakdown(Metric::InuseSpace)
+        );
+        assert_eq!(vec![(liba, 2), (libb, 2), (LibraryRef::Anonymous, 1)], profile.leaf_library_breakdown(Metric::InuseCount));
+        assert!(profile.leaf_library_breakdown(Metric::AllocSpace).is_empty());
+    }
+
+    #[test]
+    fn test_address_width() {
+        let data = "heap_v2/524288
+  t*: 1: 16 [0: 0]
+@ 0x8048010 0x8049010
+  t*: 1: 16 [0: 0]
+
+MAPPED_LIBRARIES:
+08048000-08050000 r-xp 00000000 103:02 5000 /usr/bin/app
+fffff000-100000000 r-xp 00000000 00:00 0 /usr/lib/libtop.so
+";
+        assert_eq!(AddressWidth::Bits32, Profile::parse(data).unwrap().address_width());
+        assert_eq!(32, AddressWidth::Bits32.bits());
+
+        let wide = data.replace("0x8049010", "0x7f99f42dd000");
+        assert_eq!(AddressWidth::Bits64, Profile::parse(&wide).unwrap().address_width());
+    }
+
+    #[test]
+    fn test_mapped_bytes() {
+        let data = "heap_v2/524288
+  t*: 0: 0 [0: 0]
+@ 0x1
+  t*: 0: 0 [0: 0]
+
+MAPPED_LIBRARIES:
+00001000-00003000 r--p 00000000 103:02 5000 /usr/lib/liba.so
+00002000-00004000 r-xp 00001000 103:02 5000 /usr/lib/liba.so
+00001000-00003000 r--p 00000000 103:02 5000 /usr/lib/liba.so
+00009000-00008000 r--p 00000000 103:02 5001 /usr/lib/corrupt.so
+00010000-00010800 r--p 00000000 103:02 5002 /usr/lib/libb.so
+";
+        let profile = Profile::parse(data).unwrap();
+        assert_eq!(0x3000 + 0x800, profile.mapped_bytes());
+        assert_eq!(0, Profile::empty(1).mapped_bytes());
+    }
+
+    #[test]
+    fn test_loaded_libraries() {
+        let data = "heap_v2/524288
+  t*: 0: 0 [0: 0]
+@ 0x1
+  t*: 0: 0 [0: 0]
+
+MAPPED_LIBRARIES:
+00001000-00002000 r--p 00000000 103:02 5000 /usr/lib/libc.so.6
+00002000-00003000 r-xp 00001000 103:02 5000 /usr/lib/libc.so.6
+00003000-00004000 r-xp 00000000 103:02 5001 /opt/app/lib/libc.so.6
+00004000-00005000 r-xp 00000000 103:02 5002 /opt/app/server
+";
+        let profile = Profile::parse(data).unwrap();
+        assert_eq!(BTreeSet::from(["libc.so.6", "server"]), profile.loaded_libraries(false));
+        assert_eq!(
+            BTreeSet::from(["/opt/app/lib/libc.so.6", "/opt/app/server", "/usr/lib/libc.so.6"]),
+            profile.loaded_libraries(true)
+        );
+    }
+
+    #[test]
+    fn test_inuse_space_totals() {
+        let data = "heap_v2/524288
+  t*: 3: 48 [0: 0]
+@ 0x1
+  t*: 1: 16 [0: 0]
+@ 0x2
+  t*: 2: 32 [0: 0]
+";
+        let mut profile = Profile::parse(data).unwrap();
+        assert_eq!(Some(48), profile.totals_row_inuse_space());
+        assert_eq!(48, profile.computed_inuse_space());
+
+        profile.stacks.pop();
+        assert_eq!(Some(48), profile.totals_row_inuse_space());
+        assert_eq!(16, profile.computed_inuse_space());
+
+        profile.totals.clear();
+        assert_eq!(None, profile.totals_row_inuse_space());
+    }
+
+    #[test]
+    fn test_thread_count() {
+        let data = "heap_v2/524288
+  t*: 3: 48 [0: 0]
+  t0: 1: 16 [0: 0]
+  t1: 2: 32 [0: 0]
+  t2: 0: 0 [0: 0]
+  tworker: 0: 0 [0: 0]
+@ 0x1
+  t*: 3: 48 [0: 0]
+  t7: 3: 48 [0: 0]
+";
+        assert_eq!(4, Profile::parse(data).unwrap().thread_count());
+    }
+
+    #[test]
+    fn test_partition_by_thread() {
+        let data = "heap_v2/524288
+  t*: 4: 64 [0: 0]
+  t0: 1: 16 [0: 0]
+  t1: 3: 48 [0: 0]
+  t2: 0: 0 [0: 0]
+@ 0x1
+  t*: 2: 32 [0: 0]
+  t0: 1: 16 [0: 0]
+  t1: 1: 16 [0: 0]
+@ 0x2
+  t*: 2: 32 [0: 0]
+  t1: 2: 32 [0: 0]
+  tworker: 1: 8 [0: 0]
+";
+        let profile = Profile::parse(data).unwrap();
+        let threads = profile.partition_by_thread();
+        assert_eq!(4, threads.len());
+        let ids: Vec<&ThreadId> = threads.keys().collect();
+        assert_eq!(
+            vec![&ThreadId::Numeric(0), &ThreadId::Numeric(1), &ThreadId::Numeric(2), &ThreadId::Named("worker".into())],
+            ids
+        );
+
+        let t1 = &threads[&ThreadId::Numeric(1)];
+        assert_eq!(2, t1.stacks.len());
+        assert_eq!(48, t1.totals_for(ThreadId::Total).unwrap().insuse_space);
+        assert_eq!(32, t1.stacks[1].thread(ThreadId::Total).unwrap().insuse_space);
+        assert_eq!(ThreadId::Numeric(1), t1.stacks[1].threads[1].id);
+        assert_eq!(t1.stacks[1].threads[0].metrics(), t1.stacks[1].threads[1].metrics());
+
+        let t0 = &threads[&ThreadId::Numeric(0)];
+        assert_eq!(vec![vec![1]], t0.stacks.iter().map(|stack| stack.addrs.clone()).collect::<Vec<_>>());
+
+        assert!(threads[&ThreadId::Numeric(2)].stacks.is_empty());
+
+        let worker = &threads[&ThreadId::Named("worker".into())];
+        assert_eq!(8, worker.totals_for(ThreadId::Total).unwrap().insuse_space);
+
+        let single = profile.thread_profile(ThreadId::Numeric(1));
+        assert_eq!(t1.to_heap_text(), single.to_heap_text());
+    }
+
+    #[test]
+    fn test_to_prometheus() {
+        let data = "heap_v2/524288
+  t*: 3: 48 [5: 80]
+  t0: 3: 48 [5: 80]
+@ 0x1
+  t*: 3: 48 [5: 80]
+  t0: 3: 48 [5: 80]
+";
+        let profile = Profile::parse(data).unwrap();
+        let out = profile.to_prometheus(&[("service", "api"), ("host", "a\"b")]);
+
+        assert!(out.contains("# TYPE jemalloc_inuse_bytes gauge\n"));
+        assert!(out.contains("jemalloc_inuse_bytes{service=\"api\",host=\"a\\\"b\"} 48\n"));
+        assert!(out.contains("jemalloc_inuse_objects{service=\"api\",host=\"a\\\"b\"} 3\n"));
+        assert!(out.contains("jemalloc_alloc_bytes{service=\"api\",host=\"a\\\"b\"} 80\n"));
+        assert!(out.contains("jemalloc_alloc_objects{service=\"api\",host=\"a\\\"b\"} 5\n"));
+
+        let out = profile.to_prometheus(&[]);
+        assert!(out.contains("jemalloc_inuse_bytes 48\n"));
+    }
+
+    #[test]
+    fn test_top_with_totals_selector() {
+        let data = "heap_v2/524288
+  t*: 3: 300 [0: 0]
+  t0: 1: 100 [0: 0]
+  t1: 2: 200 [0: 0]
+@ 0x1
+  t*: 1: 100 [0: 0]
+  t1: 1: 100 [0: 0]
+@ 0x2
+  t*: 2: 200 [0: 0]
+  t0: 1: 100 [0: 0]
+  t1: 1: 100 [0: 0]
+";
+        let profile = Profile::parse(data).unwrap();
+
+        let top = profile.top(1, Metric::InuseSpace, ThreadId::Total);
+        assert_eq!(1, top.len());
+        assert_eq!(vec![2], top[0].0.addrs);
+        assert_eq!(200, top[0].1.inuse_space);
+        assert!((profile.percent(top[0].0, Metric::InuseSpace, ThreadId::Total) - 66.666).abs() < 0.01);
+
+        let top = profile.top(10, Metric::InuseSpace, ThreadId::Numeric(0));
+        assert_eq!(1, top.len());
+        assert_eq!(vec![2], top[0].0.addrs);
+        assert_eq!(100.0, profile.percent(top[0].0, Metric::InuseSpace, ThreadId::Numeric(0)));
+        assert_eq!(50.0, profile.percent(&profile.stacks[0], Metric::InuseSpace, ThreadId::Numeric(1)));
+        assert!((profile.percent(&profile.stacks[0], Metric::InuseCount, ThreadId::Total) - 33.333).abs() < 0.01);
+
+        let ties = Profile::parse("heap_v2/1\n  t*: 2: 2 [0: 0]\n@ 0x9\n  t*: 1: 1 [0: 0]\n@ 0x3\n  t*: 1: 1 [0: 0]\n").unwrap();
+        let top = ties.top(2, Metric::InuseSpace, ThreadId::Total);
+        assert_eq!(vec![vec![3], vec![9]], top.iter().map(|(stack, _)| stack.addrs.clone()).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_allocation_score() {
+        let data = "heap_v2/524288
+  t*: 3: 300 [9: 2300]
+@ 0x1
+  t*: 1: 200 [1: 200]
+@ 0x2
+  t*: 1: 100 [7: 2100]
+@ 0x3
+  t1: 1: 16 [0: 0]
+";
+        let profile = Profile::parse(data).unwrap();
+        let scores = |weights| {
+            profile.allocation_score(weights).into_iter().map(|(stack, score)| (stack.addrs[0], score)).collect::<Vec<_>>()
+        };
+
+        assert_eq!(vec![(2, 1100.0), (1, 200.0)], scores(ScoreWeights::default()));
+        assert_eq!(vec![(1, 200.0), (2, 100.0)], scores(ScoreWeights { live: 1.0, freed: 0.0 }));
+    }
+
+    #[test]
+    fn test_deepest_stack() {
+        let data = "heap_v2/524288
+  t*: 3: 48 [0: 0]
+@ 0x1 0x2
+  t*: 1: 16 [0: 0]
+@ 0x3 0x4 0x4 0x4
+  t*: 1: 16 [0: 0]
+@ 0x5 0x6 0x7 0x8
+  t*: 1: 16 [0: 0]
+";
+        let profile = Profile::parse(data).unwrap();
+        let (stack, depth) = profile.deepest_stack().unwrap();
+        assert_eq!((vec![3, 4, 4, 4], 4), (stack.addrs.clone(), depth));
+
+        assert!(Profile::empty(1).deepest_stack().is_none());
+    }
+
+    #[test]
+    fn test_concentration() {
+        let data = "heap_v2/524288
+  t*: 4: 64 [4: 64]
+@ 0x1
+  t*: 1: 16 [1: 16]
+@ 0x2
+  t*: 1: 16 [1: 16]
+@ 0x3
+  t*: 2: 32 [2: 32]
+@ 0x4
+  t*: 0: 0 [1: 16]
+";
+        let profile = Profile::parse(data).unwrap();
+        // Values 16, 16 and 32: 2 * (16 + 32 + 96) / (3 * 64) - 4 / 3.
+        assert!((profile.concentration(Metric::InuseSpace) - 1.0 / 6.0).abs() < 1e-9);
+        // Values 16, 16, 16 and 32.
+        assert!((profile.concentration(Metric::AllocSpace) - 0.15).abs() < 1e-9);
+
+        let data = "heap_v2/524288
+  t*: 2: 32 [0: 0]
+@ 0x1
+  t*: 1: 16 [0: 0]
+@ 0x2
+  t*: 1: 16 [0: 0]
+";
+        assert_eq!(0.0, Profile::parse(data).unwrap().concentration(Metric::InuseSpace));
+        assert_eq!(0.0, Profile::empty(1).concentration(Metric::InuseSpace));
+    }
+
+    #[test]
+    fn test_approx_memory_usage() {
+        let data = "heap_v2/524288
+  t*: 2: 32 [0: 0]
+@ 0x1 0x2
+  t*: 1: 16 [0: 0]
+@ 0x3
+  t*: 1: 16 [0: 0]
+
+MAPPED_LIBRARIES:
+00001000-00002000 r-xp 00000000 103:02 5000 /usr/lib/libfoo.so
+";
+        let profile = Profile::parse(data).unwrap();
+        let empty = Profile::empty(524288).approx_memory_usage();
+        let borrowed = profile.approx_memory_usage();
+        assert!(borrowed >= empty + 3 * size_of::<u64>() + 2 * size_of::<Stack>(), "{} {}", borrowed, empty);
+
+        // Owning the library path and perms costs their bytes on top.
+        let owned = profile.into_owned().approx_memory_usage();
+        assert!(owned >= borrowed + "/usr/lib/libfoo.so".len() + "r-xp".len(), "{} {}", owned, borrowed);
+    }
+
+    #[test]
+    fn test_common_prefix() {
+        let data = "heap_v2/524288
+  t*: 6: 96 [0: 0]
+@ 0x1 0x2 0x3 0x4
+  t*: 3: 48 [0: 0]
+@ 0x5 0x3 0x4
+  t*: 2: 32 [0: 0]
+@ 0x6 0x7 0x4
+  t*: 1: 16 [0: 0]
+";
+        let profile = Profile::parse(data).unwrap();
+        let stacks: Vec<&Stack> = profile.top(2, Metric::InuseSpace, ThreadId::Total).into_iter().map(|(s, _)| s).collect();
+        assert_eq!(vec![3, 4], profile.common_prefix(&stacks));
+
+        let all: Vec<&Stack> = profile.stacks.iter().collect();
+        assert_eq!(vec![4], profile.common_prefix(&all));
+        assert_eq!(vec![1, 2, 3, 4], profile.common_prefix(&all[..1]));
+        assert!(profile.common_prefix(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_display_and_from_str() {
+        for id in [ThreadId::Total, ThreadId::Numeric(5), ThreadId::Named("worker".into())] {
+            assert_eq!(id, id.to_string().parse().unwrap());
+        }
+        assert_eq!("t5", ThreadId::Numeric(5).to_string());
+        assert_eq!(Ok(ThreadId::Total), "*".parse());
+        assert_eq!(Err(UnknownName("5".to_string())), "5".parse::<ThreadId>());
+        assert!("t".parse::<ThreadId>().is_err());
+
+        for metric in [Metric::InuseSpace, Metric::InuseCount, Metric::AllocSpace, Metric::AllocCount] {
+            assert_eq!(metric, metric.to_string().parse().unwrap());
+        }
+        assert_eq!("inuse_space", Metric::InuseSpace.to_string());
+        assert_eq!(Ok(Metric::AllocCount), "alloc_objects".parse());
+        assert!("space".parse::<Metric>().is_err());
+    }
+
+    #[test]
+    fn test_stack_canonical() {
+        let (_, a) = parse_stack("@ 0x1 0x2\n  t*: 2: 32 [0: 0]\n  t3: 1: 16 [0: 0]\n  t1: 1: 16 [0: 0]\n").unwrap();
+        let (_, b) = parse_stack("@ 0x1 0x2\n  t1: 1: 16 [0: 0]\n  t*: 2: 32 [0: 0]\n  t3: 1: 16 [0: 0]\n").unwrap();
+        let (_, reversed) = parse_stack("@ 0x2 0x1\n  t*: 2: 32 [0: 0]\n  t1: 1: 16 [0: 0]\n  t3: 1: 16 [0: 0]\n").unwrap();
+
+        assert_ne!(a, b);
+        assert_eq!(a.canonical(), b.canonical());
+        assert_ne!(a.canonical(), reversed.canonical());
+
+        let ids: Vec<ThreadId> = a.canonical().threads.iter().map(|thread| thread.id.clone()).collect();
+        assert_eq!(vec![ThreadId::Total, ThreadId::Numeric(1), ThreadId::Numeric(3)], ids);
+        assert_eq!(vec![1, 2], a.canonical().addrs);
+    }
+
+    #[test]
+    fn test_stack_leaf_and_root() {
+        let (_, stack) = parse_stack("@ 0x1 0x2 0x3\n  t*: 1: 16 [0: 0]\n").unwrap();
+        assert_eq!(Some(0x1), stack.leaf());
+        assert_eq!(Some(0x3), stack.root());
+
+        let (_, stack) = parse_stack("@ 0x7\n  t*: 1: 16 [0: 0]\n").unwrap();
+        assert_eq!(stack.leaf(), stack.root());
+
+        let empty = Stack { addrs: Vec::new(), threads: Vec::new() };
+        assert_eq!(None, empty.leaf());
+        assert_eq!(None, empty.root());
+    }
+
+    #[test]
+    fn test_stack_avg_alloc_size() {
+        let (_, stack) = parse_stack("@ 0x1\n  t*: 3: 100 [0: 0]\n").unwrap();
+        assert_eq!(Some(33), stack.avg_alloc_size());
+
+        let (_, stack) = parse_stack("@ 0x1\n  t*: 0: 0 [2: 32]\n").unwrap();
+        assert_eq!(None, stack.avg_alloc_size());
+
+        let (_, stack) = parse_stack("@ 0x1\n  t1: 1: 16 [0: 0]\n").unwrap();
+        assert_eq!(None, stack.avg_alloc_size());
+    }
+
+    #[test]
+    fn test_stack_retention() {
+        let (_, stack) = parse_stack("@ 0x1\n  t*: 1: 16 [4: 64]\n").unwrap();
+        assert_eq!(Some(0.25), stack.retention());
+
+        let (_, stack) = parse_stack("@ 0x1\n  t*: 1: 16 [0: 0]\n").unwrap();
+        assert_eq!(None, stack.retention());
+
+        let (_, stack) = parse_stack("@ 0x1\n  t1: 1: 16 [1: 16]\n").unwrap();
+        assert_eq!(None, stack.retention());
+    }
+
+    #[test]
+    fn test_stack_thread_table() {
+        let (_, stack) = parse_stack("@ 0x1\n  t12: 1: 16 [0: 0]\n  t*: 3: 48 [0: 0]\n  t3: 2: 32 [0: 0]\n").unwrap();
+        let table = stack.thread_table();
+        let ids: Vec<ThreadId> = table.iter().map(|(id, _)| id.clone()).collect();
+        assert_eq!(vec![ThreadId::Total, ThreadId::Numeric(3), ThreadId::Numeric(12)], ids);
+        assert_eq!(32, table[1].1.inuse_space);
+        assert_eq!(Some(1), stack.thread(ThreadId::Numeric(12)).map(|thread| thread.inuse_count));
+    }
+
+    #[test]
+    fn test_debug_prints_hex() {
+        let data = "heap_v2/524288
+  t*: 1: 16 [0: 0]
+@ 0x1010 0xdeadbeef
+  t*: 1: 16 [0: 0]
+
+MAPPED_LIBRARIES:
+00001000-00002000 r-xp 00004000 103:02 5000 /usr/lib/liba.so
+";
+        let profile = Profile::parse(data).unwrap();
+
+        let stack = format!("{:?}", profile.stacks[0]);
+        assert!(stack.starts_with("Stack { addrs: [0x1010, 0xdeadbeef], threads: [Thread {"), "{}", stack);
+        assert_eq!("StackKey([0x1010, 0xdeadbeef])", format!("{:?}", profile.stacks[0].key()));
+        assert_eq!(
+            "MappedLibrary { first: 0x1000, last: 0x2000, offset: 0x4000, perms: \"r-xp\", path: \"/usr/lib/liba.so\", deleted: false }",
+            format!("{:?}", profile.mapped_libraries[0])
+        );
+        assert!(format!("{:#?}", profile).contains("            addrs: [\n                0x1010,\n"));
+    }
+
+    #[test]
+    fn test_stack_key() {
+        let (_, a) = parse_stack("@ 0x1 0x2\n  t*: 2: 32 [0: 0]\n").unwrap();
+        let (_, b) = parse_stack("@ 0x1 0x2\n  t*: 5: 80 [0: 0]\n  t3: 5: 80 [0: 0]\n").unwrap();
+        let (_, reversed) = parse_stack("@ 0x2 0x1\n  t*: 2: 32 [0: 0]\n").unwrap();
+
+        assert_eq!(a.key(), b.key());
+        assert_ne!(a.key(), reversed.key());
+
+        let index: HashMap<StackKey, u32> = [(a.key(), 1)].into_iter().collect();
+        assert_eq!(Some(&1), index.get(&b.key()));
+        assert_eq!(None, index.get(&reversed.key()));
+    }
+
+    #[test]
+    fn test_parse_without_libraries() {
+        let data = "heap_v2/524288
+  t*: 1: 16 [0: 0]
+@ 0x1010
+  t*: 1: 16 [0: 0]
+
+MAPPED_LIBRARIES:
+00001000-00002000 r-xp 00000000 103:02 5000 /usr/lib/liba.so
+00002000-00003000 r-xp 00000000 103:02 5001 /usr/lib/libb.so
+";
+        assert_eq!(2, Profile::parse(data).unwrap().mapped_libraries.len());
+
+        let options = ParseOptions { parse_libraries: false, strict: true, ..Default::default() };
+        let profile = Profile::parse_with(data, &options).unwrap();
+        assert!(profile.mapped_libraries.is_empty());
+        assert_eq!(1, profile.stacks.len());
+    }
+
+    #[test]
+    fn test_parse_keep_threads() {
+        let data = "heap_v2/524288
+  t*: 3: 48 [0: 0]
+  t1: 1: 16 [0: 0]
+  t12: 2: 32 [0: 0]
+@ 0x1010
+  t*: 2: 32 [0: 0]
+  t1: 1: 16 [0: 0]
+  t12: 1: 16 [0: 0]
+@ 0x2010
+  t*: 1: 16 [0: 0]
+  t12: 1: 16 [0: 0]
+";
+        let options = ParseOptions { keep_threads: Some(vec![ThreadId::Numeric(12)]), ..Default::default() };
+        let profile = Profile::parse_with(data, &options).unwrap();
+
+        let ids = |threads: &[Thread]| threads.iter().map(|thread| thread.id.to_string()).collect::<Vec<_>>();
+        assert_eq!(vec!["t*", "t12"], ids(&profile.totals));
+        assert_eq!(vec!["t*", "t12"], ids(&profile.stacks[0].threads));
+        assert_eq!(vec!["t*", "t12"], ids(&profile.stacks[1].threads));
+
+        let options = ParseOptions { keep_threads: Some(Vec::new()), ..Default::default() };
+        assert_eq!(vec!["t*"], ids(&Profile::parse_with(data, &options).unwrap().stacks[0].threads));
+    }
+
+    #[test]
+    fn test_parse_collapse_recursion() {
+        let data = "heap_v2/524288
+  t*: 1: 16 [0: 0]
+@ 0x1 0x2 0x2 0x2 0x3 0x2
+  t*: 1: 16 [0: 0]
+";
+        assert_eq!(vec![1, 2, 2, 2, 3, 2], Profile::parse(data).unwrap().stacks[0].addrs);
+
+        let options = ParseOptions { collapse_recursion: true, max_stack_depth: 6, ..Default::default() };
+        assert_eq!(vec![1, 2, 3, 2], Profile::parse_with(data, &options).unwrap().stacks[0].addrs);
+    }
+
+    #[test]
+    fn test_parse_strict_truncation() {
+        let complete = "heap_v2/524288
+  t*: 1: 16 [0: 0]
+@ 0x7f99f42dd000
+  t*: 1: 16 [0: 0]
+";
+        let strict = ParseOptions { strict: true, ..Default::default() };
+        assert!(Profile::parse_with(complete, &strict).is_ok());
+
+        let truncated = format!("{}@ 0x7f99f4", complete);
+        assert_eq!(1, Profile::parse(&truncated).unwrap().stacks.len());
+        let err = Profile::parse_with(&truncated, &strict).unwrap_err().to_string();
+        assert!(err.contains("line 5"), "{}", err);
+
+        let truncated = &complete[..complete.len() - 1];
+        let err = Profile::parse_with(truncated, &strict).unwrap_err().to_string();
+        assert!(err.contains("line 4"), "{}", err);
+    }
+
+    #[test]
+    fn test_parse_field_overflow() {
+        let data = "heap_v2/524288
+  t*: 1: 16 [0: 0]
+@ 0x1
+  t*: 1: 16 [0: 0]
+@ 0x2
+  t*: 1: 99999999999999999999999 [0: 0]
+";
+        assert_eq!(
+            ParseError::FieldOverflow { field: "inuse_space", line: 6 },
+            Profile::parse(data).unwrap_err()
+        );
+
+        let lenient = ParseOptions { saturate_overflow: true, ..Default::default() };
+        let profile = Profile::parse_with(data, &lenient).unwrap();
+        assert_eq!(2, profile.stacks.len());
+        assert_eq!(u64::MAX, profile.stacks[1].threads[0].insuse_space);
+
+        let data = "heap_v2/99999999999999999999999\n  t*: 1: 16 [0: 0]\n@ 0x1\n  t*: 1: 16 [0: 99999999999999999999999]\n";
+        assert_eq!(
+            ParseError::FieldOverflow { field: "sampling_rate", line: 1 },
+            Profile::parse(data).unwrap_err()
+        );
+
+        assert_eq!(ParseError::UnsupportedFormat, Profile::parse("heap_v1/1\n").unwrap_err());
+        assert_eq!(ParseError::Malformed { line: 3 }, Profile::parse("heap_v2/1\n  t*: 1: 16 [0: 0]\n@ xyz\n").unwrap_err());
+    }
+
+    #[test]
+    fn test_parse_max_stack_depth() {
+        let deep: String = (1..=5000).map(|addr| format!(" {:#x}", addr)).collect();
+        let data = format!("heap_v2/524288\n  t*: 2: 32 [0: 0]\n@ 0x1\n  t*: 1: 16 [0: 0]\n@{}\n  t*: 1: 16 [0: 0]\n", deep);
+        assert_eq!(
+            ParseError::StackTooDeep { depth: 5000, limit: 4096, line: 5 },
+            Profile::parse(&data).unwrap_err()
+        );
+
+        let options = ParseOptions { max_stack_depth: 5000, ..Default::default() };
+        assert_eq!(5000, Profile::parse_with(&data, &options).unwrap().stacks[1].addrs.len());
+
+        let options = ParseOptions { max_stack_depth: 1, ..Default::default() };
+        let data = "heap_v2/1\n  t*: 1: 16 [0: 0]\n@ 0x1 0x2\n  t*: 1: 16 [0: 0]\n";
+        assert_eq!(
+            ParseError::StackTooDeep { depth: 2, limit: 1, line: 3 },
+            Profile::parse_with(data, &options).unwrap_err()
+        );
+    }
+
+    #[test]
+    fn test_parse_thread_format() {
+        let data = "heap_v2/524288\n  t*: 16: 1 [32: 2]\n@ 0x1\n  t*: 16: 1 [32: 2]\n";
+        let options = ParseOptions { thread_format: ThreadLineFormat::SpaceFirst, ..Default::default() };
+        let profile = Profile::parse_with(data, &options).unwrap();
+        assert_eq!(
+            Metrics { inuse_count: 1, inuse_space: 16, alloc_count: 2, alloc_space: 32 },
+            profile.stacks[0].threads[0].metrics()
+        );
+    }
+
+    #[test]
+    fn test_parse_duplicate_stacks() {
+        let data = "heap_v2/524288
+  t*: 3: 48 [0: 0]
+@ 0x1 0x2
+  t*: 1: 16 [0: 0]
+@ 0x3
+  t*: 1: 16 [0: 0]
+@ 0x1 0x2
+  t*: 1: 16 [0: 0]
+";
+        assert_eq!(3, Profile::parse(data).unwrap().stacks.len());
+
+        let options = ParseOptions { error_on_duplicate_stacks: true, ..Default::default() };
+        assert_eq!(
+            ParseError::DuplicateStack { addrs: vec![1, 2], first_index: 0, second_index: 2 },
+            Profile::parse_with(data, &options).unwrap_err()
+        );
+    }
+
+    #[test]
+    fn test_parse_limits() {
+        let data = "heap_v2/524288\n  t*: 2: 32 [0: 0]\n@ 0x1\n  t*: 1: 16 [0: 0]\n@ 0x2\n  t*: 1: 16 [0: 0]\n";
+        let limits = |max_input_bytes, max_stacks| ParseOptions { max_input_bytes, max_stacks, ..Default::default() };
+
+        assert_eq!(2, Profile::parse_with(data, &limits(Some(data.len()), Some(2))).unwrap().stacks.len());
+        assert_eq!(
+            ParseError::LimitExceeded { option: "max_input_bytes", max: 10 },
+            Profile::parse_with(data, &limits(Some(10), None)).unwrap_err()
+        );
+        assert_eq!(
+            ParseError::LimitExceeded { option: "max_stacks", max: 1 },
+            Profile::parse_with(data, &limits(None, Some(1))).unwrap_err()
+        );
+    }
+
+    #[test]
+    fn test_parse_reconstruct_lines() {
+        let data = "heap_v2/524288
+  t*: 3: 48 [0: 0]
+  t1: 3: 48 [0: 0]
+@ 0x1 0x2
+  t*: 1: 16 [0: 0]
+  t1: 1: 16 [0: 0]
+@ 0x3
+  t*: 2: 32 [0: 0]
+  t1: 2: 32 [0: 0]
+
+MAPPED_LIBRARIES:
+00001000-00002000 r-xp 00000000 103:02 5000 /usr/lib/liba.so
+00002000-00003000 r-xp 00000000 103:02 5001 /usr/lib/libb.so (deleted)
+";
+        let flattened = data.split_whitespace().collect::<Vec<_>>().join(" ");
+        assert!(Profile::parse(&flattened).is_err());
+
+        let options = ParseOptions { reconstruct_lines: true, ..Default::default() };
+        let profile = Profile::parse_with(&flattened, &options).unwrap();
+        assert_eq!(Profile::parse(data).unwrap().content_hash(), profile.content_hash());
+        assert_eq!(2, profile.mapped_libraries.len());
+        assert!(profile.mapped_libraries[1].is_deleted());
+    }
+
+    #[test]
+    fn test_parse_skips_preamble() {
+        let data = "# captured by dump.sh\n\n  \r\n#pid 4242\nheap_v2/524288\n  t*: 1: 16 [0: 0]\n@ 0x1\n  t*: 1: 16 [0: 0]\n";
+        let profile = Profile::parse(data).unwrap();
+        assert_eq!(524288, profile.sampling_rate);
+        assert_eq!(1, profile.stacks.len());
+
+        let data = "# two dumps\nheap_v2/1\n  t*: 0: 0 [0: 0]\n@ 0x1\n  t*: 0: 0 [0: 0]\n\n# second\nheap_v2/2\n  t*: 0: 0 [0: 0]\n@ 0x1\n  t*: 0: 0 [0: 0]\n";
+        assert_eq!(2, Profile::parse_all(data).unwrap().len());
+
+        assert_eq!(Err(ParseError::Malformed { line: 4 }), Profile::parse("#\n\nheap_v2/1\n  t*: x\n").map(|_| ()));
+        assert_eq!(Err(ParseError::UnsupportedFormat), Profile::parse("# nothing else\n").map(|_| ()));
+
+        let data = "heap profile: dumped by wrapper 1.2\n@heap_v2/524288\n  t*: 1: 16 [0: 0]\n@ 0x1\n  t*: 1: 16 [0: 0]\n";
+        assert_eq!(524288, Profile::parse(data).unwrap().sampling_rate);
+        let too_far = format!("{}{}", "wrapper line\n".repeat(MAX_PREAMBLE_LINES + 1), data);
+        assert_eq!(Err(ParseError::UnsupportedFormat), Profile::parse(&too_far).map(|_| ()));
+    }
+
+    #[test]
+    fn test_parse_with_bom() {
+        let data = "\u{FEFF}heap_v2/524288\n  t*: 1: 16 [0: 0]\n@ 0x1\n  t*: 1: 16 [0: 0]\n";
+        let profile = Profile::parse(data).unwrap();
+        assert_eq!(524288, profile.sampling_rate);
+        assert_eq!(1, profile.stacks.len());
+        assert_eq!(1, Profile::parse_all(data).unwrap().len());
+    }
+
+    #[test]
+    fn test_into_owned() {
+        let profile: OwnedProfile = {
+            let data = String::from("heap_v2/524288\n  tworker: 1: 16 [0: 0]\n@ 0x1\n  tworker: 1: 16 [0: 0]\n\nMAPPED_LIBRARIES:\n00001000-00002000 r-xp 00000000 103:02 5000 /usr/lib/liba.so\n");
+            Profile::parse(&data).unwrap().into_owned()
+        };
+
+        assert_eq!(ThreadId::Named("worker".into()), profile.stacks[0].threads[0].id);
+        assert_eq!("/usr/lib/liba.so", profile.mapped_libraries[0].path);
+        assert_eq!("r-xp", profile.mapped_libraries[0].perms);
+    }
+
+    #[test]
+    fn test_mapped_library_permissions() {
+        let data = "heap_v2/524288
+  t*: 1: 16 [0: 0]
+@ 0x1
+  t*: 1: 16 [0: 0]
+
+MAPPED_LIBRARIES:
+00001000-00002000 r-xp 00000000 103:02 5000 /usr/lib/liba.so
+00002000-00003000 rw-s 00001000 103:02 5000 /usr/lib/liba.so
+";
+        let profile = Profile::parse(data).unwrap();
+        assert_eq!(
+            Permissions { read: true, write: false, execute: true, private: true },
+            profile.mapped_libraries[0].permissions()
+        );
+        assert_eq!(
+            Permissions { read: true, write: true, execute: false, private: false },
+            profile.mapped_libraries[1].permissions()
+        );
+    }
+
+    #[test]
+    fn test_parse_summary() {
+        let data = "# dumped by the test\nheap_v2/524288\n  t*: 2: 32 [0: 0]\n  t3: 2: 32 [0: 0]\n@ 0x1\n  t*: garbage\n";
+        let summary = Profile::parse_summary(data).unwrap();
+        assert_eq!(524288, summary.sampling_rate);
+        assert_eq!(2, summary.totals.len());
+        assert_eq!(Some(32), summary.totals_for(ThreadId::Total).map(|thread| thread.insuse_space));
+
+        assert_eq!(Err(ParseError::UnsupportedFormat), Profile::parse_summary("heap_v1/1\n"));
+        assert_eq!(Err(ParseError::Malformed { line: 2 }), Profile::parse_summary("heap_v2/1\n  t*: x\n@ 0x1\n"));
+    }
+
+    #[test]
+    fn test_parse_with_metrics() {
+        let data = "\u{FEFF}# dumped by the test\nheap_v2/524288\n  t*: 2: 32 [0: 0]\n@ 0x1\n  t*: 1: 16 [0: 0]\n@ 0x2\n  t*: 1: 16 [0: 0]\n";
+        let (profile, metrics) = Profile::parse_with_metrics(data, &ParseOptions::default()).unwrap();
+        assert_eq!(2, profile.stacks.len());
+        assert_eq!(2, metrics.stacks);
+        assert_eq!(data.len() - '\u{FEFF}'.len_utf8(), metrics.bytes);
+    }
+
+    #[test]
+    fn test_parse_bytes() {
+        let profile = Profile::parse_bytes(b"heap_v2/524288\n  t*: 1: 16 [0: 0]\n@ 0x1\n  t*: 1: 16 [0: 0]\n").unwrap();
+        assert_eq!(524288, profile.sampling_rate);
+        assert_eq!(1, profile.stacks.len());
+
+        let invalid = b"heap_v2/524288\n  t*: 1: 16 [0: 0]\n@ 0x1\xff\n";
+        assert_eq!(ParseError::Malformed { line: 3 }, Profile::parse_bytes(invalid).unwrap_err());
+
+        let stray = b"heap_v2/524288
+  t*: 1: 16 [0: 0]
+@ 0x1010
+  t*: 1: 16 [0: 0]
+
+MAPPED_LIBRARIES:
+00001000-00002000 r-xp 00000000 103:02 5000 /opt/caf\xe9/lib.so
+";
+        let profile = Profile::parse_bytes(stray).unwrap();
+        assert_eq!(1, profile.stacks.len());
+        assert_eq!("/opt/caf\u{FFFD}/lib.so", profile.mapped_libraries[0].path);
+    }
+
+    #[test]
+    fn test_version() {
+        let profile = Profile::parse("heap_v2/1\n  t*: 1: 16 [0: 0]\n@ 0x1\n  t*: 1: 16 [0: 0]\n").unwrap();
+        assert_eq!(HeapVersion::V2, profile.version());
+        assert!(SUPPORTED_VERSIONS.contains(&profile.version()));
+        assert!(profile.to_heap_text().starts_with(profile.version().header()));
+    }
+
+    #[test]
+    fn test_empty_profile() {
+        let profile = OwnedProfile::empty(524288);
+        assert_eq!(524288, profile.sampling_rate);
+        assert_eq!(Metrics::default(), profile.totals_for(ThreadId::Total).unwrap().metrics());
+        assert!(profile.stacks.is_empty());
+        assert!(profile.mapped_libraries.is_empty());
+        assert_eq!("heap_v2/524288\n  t*: 0: 0 [0: 0]\n\nMAPPED_LIBRARIES:\n", profile.to_heap_text());
+    }
+
+    #[test]
+    fn test_parse_all() {
+        let data = "heap_v2/524288
+  t*: 1: 16 [0: 0]
+@ 0x1
+  t*: 1: 16 [0: 0]
+
+MAPPED_LIBRARIES:
+00001000-00002000 r--p 00000000 103:02 5000 /usr/lib/liba.so
+heap_v2/524288
+  t*: 2: 32 [0: 0]
+@ 0x1
+  t*: 2: 32 [0: 0]
+
+MAPPED_LIBRARIES:
+00001000-00002000 r--p 00000000 103:02 5000 /usr/lib/liba.so
+
+heap_v2/524288
+  t*: 3: 48 [0: 0]
+@ 0x1
+  t*: 3: 48 [0: 0]
+";
+        let profiles = Profile::parse_all(data).unwrap();
+        assert_eq!(3, profiles.len());
+
+        for (i, profile) in profiles.iter().enumerate() {
+            assert_eq!(i, profile.sequence);
+            assert_eq!(None, profile.captured_at);
+            assert_eq!((i as u64 + 1) * 16, profile.totals[0].insuse_space);
+        }
+        assert_eq!(1, profiles[0].mapped_libraries.len());
+        assert_eq!(1, profiles[1].mapped_libraries.len());
+        assert!(profiles[2].mapped_libraries.is_empty());
+
+        assert!(Profile::parse_all("heap_v2/524288\n  t*: 1: 16 [0: 0]\n@ 0x1\n  t*: 1: 16 [0: 0]\ngarbage\n").is_err());
+    }
+
+    #[test]
+    fn test_metrics_arithmetic() {
+        let (_, a) = parse_thread("t1: 10: 100 [20: 200]").unwrap();
+        let (_, b) = parse_thread("t2: 4: 150 [5: 50]").unwrap();
+
+        let sum = a.metrics() + b.metrics();
+        assert_eq!(Metrics { inuse_count: 14, inuse_space: 250, alloc_count: 25, alloc_space: 250 }, sum);
+
+        let delta = a.metrics() - b.metrics();
+        assert_eq!(Metrics { inuse_count: 6, inuse_space: 0, alloc_count: 15, alloc_space: 150 }, delta);
+    }
+
+    #[test]
+    fn test_scale() {
+        let data = "heap_v2/524288
+  t*: 3: 100 [5: 300]
+@ 0x1
+  t*: 3: 100 [5: 300]
+  t1: 1: 15 [18446744073709551615: 0]
+";
+        let mut profile = Profile::parse(data).unwrap();
+        profile.scale(0.5);
+        assert_eq!(
+            Metrics { inuse_count: 2, inuse_space: 50, alloc_count: 3, alloc_space: 150 },
+            profile.totals_for(ThreadId::Total).unwrap().metrics()
+        );
+        assert_eq!(8, profile.stacks[0].thread(ThreadId::Numeric(1)).unwrap().insuse_space);
+
+        profile.scale(4.0);
+        assert_eq!(u64::MAX, profile.stacks[0].thread(ThreadId::Numeric(1)).unwrap().alloc_count);
+
+        profile.scale(-1.0);
+        assert_eq!(Metrics::default(), profile.totals_for(ThreadId::Total).unwrap().metrics());
+    }
+
+    #[test]
+    fn test_metrics_unsampled() {
+        let sampled = Metrics { inuse_count: 1, inuse_space: 512, alloc_count: 2, alloc_space: 1 << 30 };
+        // 1 / (1 - e^-1) for the in-use pair; the allocated pair averages far
+        // above the rate, so it barely moves.
+        assert_eq!(
+            Metrics { inuse_count: 2, inuse_space: 810, alloc_count: 2, alloc_space: 1 << 30 },
+            sampled.unsampled(512)
+        );
+        assert_eq!(sampled, sampled.unsampled(0));
+        assert_eq!(sampled, ValueMode::Sampled.apply(sampled, 512));
+    }
+
 }
@@ -1,10 +1,18 @@
 use std::fs;
+use std::io::{self, IsTerminal, Read};
 
 use jeprof_rs::Profile;
 
 fn main() {
-    let file_path = std::env::args().nth(1).expect("no file given");
-    let profile = fs::read_to_string(file_path).unwrap();
+    let profile = match std::env::args().nth(1) {
+        Some(file_path) if file_path != "-" => fs::read_to_string(file_path).unwrap(),
+        None if io::stdin().is_terminal() => panic!("no file given"),
+        _ => {
+            let mut profile = String::new();
+            io::stdin().read_to_string(&mut profile).unwrap();
+            profile
+        }
+    };
     let profile = Profile::parse(&profile);
     println!("{:#?}", profile);
 }
@@ -0,0 +1,609 @@
+//! The nom combinators behind [`Profile::parse`](crate::Profile::parse).
+//!
+//! They are exposed so that fragments of jemalloc output, such as a single
+//! thread line pasted from a log, can be parsed on their own. Apart from
+//! [`parse_stack`], which spans several lines, they stop before the line
+//! ending and leave it to the caller.
+
+use std::borrow::Cow;
+
+use nom::{
+    bytes::complete::{tag, take_while},
+    character::complete::{char, digit1, hex_digit1, line_ending, space1, one_of, anychar, not_line_ending},
+    combinator::{recognize, opt, eof},
+    error::{ErrorKind, FromExternalError, ParseError as NomParseError},
+    multi::{fold_many0, fold_many1, many1, many0, many_m_n},
+    sequence::{delimited, preceded, terminated},
+    IResult, branch::alt, Parser,
+};
+
+use crate::{MappedLibrary, ParseOptions, Profile, Stack, Thread, ThreadId, ThreadLineFormat};
+
+const MAPPED_LIBRARIES_HEADER: &str = "MAPPED_LIBRARIES:\n";
+
+/// The error used internally while parsing a whole profile. On top of nom's
+/// position and kind it remembers why a limit was hit, so that the
+/// corresponding [`ParseError`](crate::ParseError) can give the details.
+#[derive(Debug, PartialEq)]
+pub(crate) struct Error<'a> {
+    pub(crate) input: &'a str,
+    pub(crate) code: ErrorKind,
+    pub(crate) cause: Option<Cause>,
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum Cause {
+    /// The named numeric field does not fit in a `u64`.
+    Overflow(&'static str),
+    /// A stack has more addresses than [`ParseOptions::max_stack_depth`].
+    TooDeep { depth: usize, limit: usize },
+    /// The profile has more stacks than [`ParseOptions::max_stacks`].
+    TooManyStacks(usize),
+}
+
+impl<'a> NomParseError<&'a str> for Error<'a> {
+    fn from_error_kind(input: &'a str, code: ErrorKind) -> Self {
+        Error { input, code, cause: None }
+    }
+
+    fn append(_: &'a str, _: ErrorKind, other: Self) -> Self {
+        other
+    }
+}
+
+impl<'a, E> FromExternalError<&'a str, E> for Error<'a> {
+    fn from_external_error(input: &'a str, code: ErrorKind, _: E) -> Self {
+        Error { input, code, cause: None }
+    }
+}
+
+type Result<'a, T> = IResult<&'a str, T, Error<'a>>;
+
+/// Strips our field information so that the public combinators keep nom's
+/// default error type.
+fn into_nom(err: nom::Err<Error<'_>>) -> nom::Err<nom::error::Error<&str>> {
+    err.map(|err| nom::error::Error::new(err.input, err.code))
+}
+
+pub(crate) fn parse_profile<'a>(input: &'a str, options: &ParseOptions) -> Result<'a, Profile<'a>> {
+    let (input, (sampling_rate, threads)) = header_and_totals(input, options)?;
+    let (input, stacks) = stacks(input, options)?;
+
+    let (input, _) = many0(line_ending)(input)?;
+
+    // Truncated dumps or hand-written fixtures may stop before the maps section.
+    let (input, header) = opt(tag(MAPPED_LIBRARIES_HEADER))(input)?;
+
+    let (input, mapped_libraries) = match header {
+        Some(_) if options.parse_libraries => many0(terminated(mapped_library, line_ending_or_eof))(input)?,
+        Some(_) => fold_many0(terminated(mapped_library, line_ending_or_eof), Vec::new, |libs, _| libs)(input)?,
+        None => (input, Vec::new()),
+    };
+    let mapped_libraries = mapped_libraries.into_iter().filter(|lib| !lib.path.is_empty()).collect();
+
+    let profile = Profile {
+        sampling_rate,
+        totals: threads,
+        stacks,
+        mapped_libraries,
+        sequence: 0,
+        captured_at: None,
+    };
+
+    Ok((input, profile))
+}
+
+/// Parses one or more stacks, failing as soon as one more than
+/// [`ParseOptions::max_stacks`] has been read.
+fn stacks<'a>(mut input: &'a str, options: &ParseOptions) -> Result<'a, Vec<Stack<'a>>> {
+    let limit = options.max_stacks.unwrap_or(usize::MAX);
+    let mut stacks = Vec::new();
+
+    loop {
+        match stack(input, options) {
+            Ok(_) if stacks.len() == limit => {
+                return Err(nom::Err::Failure(Error {
+                    input,
+                    code: ErrorKind::TooLarge,
+                    cause: Some(Cause::TooManyStacks(limit)),
+                }));
+            }
+            Ok((rest, stack)) => {
+                stacks.push(stack);
+                input = rest;
+            }
+            Err(nom::Err::Error(_)) if !stacks.is_empty() => return Ok((input, stacks)),
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Parses the header and the totals block, up to the first stack.
+pub(crate) fn header_and_totals<'a>(input: &'a str, options: &ParseOptions) -> Result<'a, (u64, Vec<Thread<'a>>)> {
+    let (input, sampling_rate) = header(input, options)?;
+    let (input, _) = line_ending(input)?;
+    let (input, threads) = thread_rows(input, options, line_ending)?;
+    Ok((input, (sampling_rate, threads)))
+}
+
+/// Parses a profile up to the end of its stacks, handing each stack to
+/// `visit` as soon as it is parsed instead of collecting them. The maps
+/// section is left unparsed.
+pub(crate) fn visit_stacks<'a>(input: &'a str, options: &ParseOptions, mut visit: impl FnMut(Stack<'a>)) -> Result<'a, ()> {
+    let (input, _) = header_and_totals(input, options)?;
+    fold_many1(|i| stack(i, options), || (), |(), stack| visit(stack))(input)
+}
+
+/// Puts back the line endings of a dump flattened onto one line, see
+/// [`ParseOptions::reconstruct_lines`].
+///
+/// A line is started before every header, `@` address line and `t<id>:`
+/// thread line, which is indented, and before the maps section marker,
+/// after which every `<first>-<last>` address range starts a line. Runs of
+/// whitespace become single spaces, and the result ends with a line ending.
+pub(crate) fn reconstruct_lines(input: &str) -> String {
+    let maps_header = MAPPED_LIBRARIES_HEADER.trim_end();
+    let mut out = String::with_capacity(input.len() + input.len() / 8);
+    let mut in_maps = false;
+
+    for token in input.split_ascii_whitespace() {
+        let is_thread = !in_maps && token.len() > 2 && token.starts_with('t') && token.ends_with(':');
+        let starts_line = if in_maps {
+            token.split_once('-').is_some_and(|(first, last)| is_hex(first) && is_hex(last))
+        } else if token == maps_header {
+            in_maps = true;
+            out.push('\n');
+            true
+        } else {
+            is_thread || token.starts_with('@') || token.starts_with("heap_v2/")
+        };
+
+        if !out.is_empty() {
+            out.push(if starts_line { '\n' } else { ' ' });
+        }
+        if is_thread {
+            out.push_str("  ");
+        }
+        out.push_str(token);
+    }
+
+    out.push('\n');
+    out
+}
+
+fn is_hex(token: &str) -> bool {
+    !token.is_empty() && token.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Parses the `heap_v2/<sampling rate>` header, returning the rate. A
+/// leading `@`, written by some wrappers, is accepted.
+pub fn parse_header(input: &str) -> IResult<&str, u64> {
+    header(input, &ParseOptions::default()).map_err(into_nom)
+}
+
+pub(crate) fn header<'a>(input: &'a str, options: &ParseOptions) -> Result<'a, u64> {
+    let (input, _) = preceded(opt(char('@')), tag("heap_v2/"))(input)?;
+    decimal_field(input, "sampling_rate", options)
+}
+
+/// Parses an `@` address line and the thread lines below it, including
+/// their line endings.
+pub fn parse_stack(input: &str) -> IResult<&str, Stack<'_>> {
+    stack(input, &ParseOptions::default()).map_err(into_nom)
+}
+
+pub(crate) fn stack<'a>(input: &'a str, options: &ParseOptions) -> Result<'a, Stack<'a>> {
+    let (input, addrs) = terminated(|i| stack_addrs(i, options), line_ending)(input)?;
+    let (input, threads) = thread_rows(input, options, line_ending_or_eof)?;
+
+    let stack = Stack {
+        addrs,
+        threads
+    };
+
+    Ok((input, stack))
+}
+
+/// Parses one or more indented thread lines, each followed by `ending`,
+/// keeping only the rows [`ParseOptions::keep_threads`] asks for.
+fn thread_rows<'a>(
+    input: &'a str,
+    options: &ParseOptions,
+    ending: impl Parser<&'a str, &'a str, Error<'a>>,
+) -> Result<'a, Vec<Thread<'a>>> {
+    let kept = move |thread: &Thread| match &options.keep_threads {
+        Some(ids) => thread.id == ThreadId::Total || ids.contains(&thread.id),
+        None => true,
+    };
+
+    fold_many1(
+        terminated(preceded(space1, |i| thread(i, options)), ending),
+        Vec::new,
+        move |mut threads, thread| {
+            if kept(&thread) {
+                threads.push(thread);
+            }
+            threads
+        },
+    )(input)
+}
+
+/// Parses an `@ 0x... 0x...` address line. Each address may be followed by
+/// an annotation in parentheses, such as `0x1234 (libfoo)`, which is
+/// skipped.
+pub fn parse_stack_addrs(input: &str) -> IResult<&str, Vec<u64>> {
+    stack_addrs(input, &ParseOptions::default()).map_err(into_nom)
+}
+
+/// Stops collecting addresses one past [`ParseOptions::max_stack_depth`], so
+/// that an absurdly long line is counted rather than stored, and collapses
+/// runs of one address if [`ParseOptions::collapse_recursion`] is set.
+fn stack_addrs<'a>(input: &'a str, options: &ParseOptions) -> Result<'a, Vec<u64>> {
+    let line = input;
+    let (input, _) = tag("@")(input)?;
+
+    let limit = options.max_stack_depth;
+    let (input, mut addrs) = many_m_n(1, limit.saturating_add(1), preceded(space1, frame))(input)?;
+    if addrs.len() > limit {
+        let (_, rest) = fold_many0(preceded(space1, frame), || 0, |count, _| count + 1)(input)?;
+        return Err(nom::Err::Failure(Error {
+            input: line,
+            code: ErrorKind::TooLarge,
+            cause: Some(Cause::TooDeep { depth: addrs.len() + rest, limit }),
+        }));
+    }
+
+    if options.collapse_recursion {
+        addrs.dedup();
+    }
+
+    Ok((input, addrs))
+}
+
+/// One address of an `@` line, with any annotation a patched allocator put
+/// after it.
+fn frame(input: &str) -> Result<'_, u64> {
+    let annotation = delimited(char('('), take_while(|c| c != ')' && c != '\n'), char(')'));
+    terminated(hex, opt(preceded(space1, annotation)))(input)
+}
+
+/// Parses one line of the `MAPPED_LIBRARIES:` section, in `/proc/<pid>/maps`
+/// format.
+pub fn parse_mapped_library(input: &str) -> IResult<&str, MappedLibrary<'_>> {
+    mapped_library(input).map_err(into_nom)
+}
+
+fn mapped_library(input: &str) -> Result<'_, MappedLibrary<'_>> {
+    // 7f99f42dd000-7f99f42e0000
+    let (input, first) = hex(input)?;
+    let (input, _) = tag("-")(input)?;
+    let (input, last) = hex(input)?;
+
+    // r--p
+    let (input, perms) = preceded(space1, recognize(many_m_n(4, 4, anychar)))(input)?;
+
+    // 00000000
+    let (input, offset) = preceded(space1, hex)(input)?;
+
+    // 103:02, or fe:00 since the device numbers are hex
+    let (input, _) = preceded(space1, hex_digit1)(input)?;
+    let (input, _) = tag(":")(input)?;
+    let (input, _) = hex_digit1(input)?;
+
+    // 5000
+    let (input, _) = preceded(space1, digit1)(input)?;
+
+    // /usr/lib/x86_64-linux-gnu/libgcc_s.so.1, absent for anonymous mappings
+    let (input, path) = opt(preceded(space1, not_line_ending))(input)?;
+    let (path, deleted) = library_path(path.unwrap_or(""));
+
+    let library = MappedLibrary {
+        first,
+        last,
+        offset,
+        perms: Cow::Borrowed(perms),
+        path: Cow::Borrowed(path),
+        deleted,
+    };
+
+    Ok((input, library))
+}
+
+/// Parses a `t<id>: <inuse count>: <inuse space> [<alloc count>: <alloc space>]`
+/// thread line, without its leading indentation. Further `[<a>: <b>]`
+/// brackets some jemalloc builds append, such as extent stats, are kept in
+/// [`Thread::extra`].
+pub fn parse_thread(input: &str) -> IResult<&str, Thread<'_>> {
+    thread(input, &ParseOptions::default()).map_err(into_nom)
+}
+
+fn thread<'a>(input: &'a str, options: &ParseOptions) -> Result<'a, Thread<'a>> {
+    let (input, _) = tag("t")(input)?;
+    let (input, id) = take_while(|c: char| c.is_alphanumeric() || c == '*')(input)?;
+    let (input, _) = tag(": ")(input)?;
+    let (input, (inuse_count, insuse_space)) = counters(input, "inuse_count", "inuse_space", options)?;
+    let (input, _) = tag(" [")(input)?;
+    let (input, (alloc_count, alloc_space)) = counters(input, "alloc_count", "alloc_space", options)?;
+    let (input, _) = tag("]")(input)?;
+    let (input, extra) = many0(preceded(space1, |i| extra_counters(i, options)))(input)?;
+
+    let thread = Thread {
+        id: ThreadId::from_id(id),
+        inuse_count,
+        insuse_space,
+        alloc_count,
+        alloc_space,
+        extra,
+    };
+
+    Ok((input, thread))
+}
+
+/// Parses a `[<a>: <b>]` bracket following the alloc counters of a thread
+/// line. Its fields are taken in the order written, whatever the
+/// [`ParseOptions::thread_format`].
+fn extra_counters<'a>(input: &'a str, options: &ParseOptions) -> Result<'a, (u64, u64)> {
+    let (input, _) = tag("[")(input)?;
+    let (input, a) = decimal_field(input, "extra", options)?;
+    let (input, _) = tag(": ")(input)?;
+    let (input, b) = decimal_field(input, "extra", options)?;
+    let (input, _) = tag("]")(input)?;
+    Ok((input, (a, b)))
+}
+
+/// Parses a `<a>: <b>` pair of counters into `(count, space)`, in the order
+/// given by [`ParseOptions::thread_format`].
+fn counters<'a>(input: &'a str, count: &'static str, space: &'static str, options: &ParseOptions) -> Result<'a, (u64, u64)> {
+    let (first, second) = match options.thread_format {
+        ThreadLineFormat::CountFirst => (count, space),
+        ThreadLineFormat::SpaceFirst => (space, count),
+    };
+
+    let (input, a) = decimal_field(input, first, options)?;
+    let (input, _) = tag(": ")(input)?;
+    let (input, b) = decimal_field(input, second, options)?;
+
+    match options.thread_format {
+        ThreadLineFormat::CountFirst => Ok((input, (a, b))),
+        ThreadLineFormat::SpaceFirst => Ok((input, (b, a))),
+    }
+}
+
+/// Splits the ` (deleted)` marker the kernel appends for files unlinked
+/// while mapped off the path, and strips the quotes some tools put around
+/// paths containing spaces.
+fn library_path(path: &str) -> (&str, bool) {
+    let path = path.trim_end();
+    let (path, deleted) = match path.strip_suffix(" (deleted)") {
+        Some(path) => (path, true),
+        None => (path, false),
+    };
+
+    let path = match path.strip_prefix('"').and_then(|path| path.strip_suffix('"')) {
+        Some(unquoted) => unquoted,
+        None => path,
+    };
+
+    (path, deleted)
+}
+
+/// Parses a decimal counter. A value too large for a `u64` saturates when
+/// [`ParseOptions::saturate_overflow`] is set, and otherwise aborts the
+/// parse with `field` recorded in the error.
+fn decimal_field<'a>(input: &'a str, field: &'static str, options: &ParseOptions) -> Result<'a, u64> {
+    let (rest, digits) = digit1(input)?;
+
+    match digits.parse::<u64>() {
+        Ok(value) => Ok((rest, value)),
+        Err(_) if options.saturate_overflow => Ok((rest, u64::MAX)),
+        Err(_) => Err(nom::Err::Failure(Error {
+            input,
+            code: ErrorKind::TooLarge,
+            cause: Some(Cause::Overflow(field)),
+        })),
+    }
+}
+
+/// Accepts a line ending, or the end of input for a final line without one.
+fn line_ending_or_eof<'a, E: NomParseError<&'a str>>(input: &'a str) -> IResult<&'a str, &'a str, E> {
+    alt((line_ending, eof))(input)
+}
+
+/// Parses a hexadecimal number with an optional `0x` prefix.
+pub fn hexadecimal_value(input: &str) -> IResult<&str, u64> {
+    hex(input)
+}
+
+fn hex<'a, E>(input: &'a str) -> IResult<&'a str, u64, E>
+where
+    E: NomParseError<&'a str> + FromExternalError<&'a str, std::num::ParseIntError>,
+{
+  nom::combinator::map_res(
+    preceded(
+      opt(alt((tag("0x"), tag("0X")))),
+      recognize(
+        many1(
+          terminated(one_of("0123456789abcdefABCDEF"), many0(char('_')))
+        )
+      )
+    ),
+    |out: &str| u64::from_str_radix(&str::replace(out, "_", ""), 16)
+  ).parse(input)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_profile() {
+        let data = "heap_v2/131072
+  t*: 4385: 810327 [0: 0]
+  t0: 129: 4965 [0: 0]
+  t1: 191: 7942 [0: 0]
+  t2: 0: 0 [0: 0]
+  t3: 128: 5268 [0: 0]
+  t4: 4: 405115494 [0: 0]
+  t5: 468: 42015 [0: 0]
+  t6: 148: 5586 [0: 0]
+  t7: 232: 15290 [0: 0]
+  t8: 0: 0 [0: 0]
+@ 0x004 0x003 0x002 0x001
+  t*: 1: 224 [0: 0]
+  t5: 1: 224 [0: 0]
+@ 0x001 0x002 0x003 0x004
+  t*: 1: 224 [0: 0]
+  t5: 1: 224 [0: 0]";
+        let (_, profile) = parse_profile(data, &ParseOptions::default()).unwrap();
+        assert_eq!(131072, profile.sampling_rate);
+        assert_eq!(ThreadId::Total, profile.totals[0].id);
+        assert_eq!(4385, profile.totals[0].inuse_count);
+        assert_eq!(4385, profile.totals[0].inuse_count);
+        assert_eq!(2, profile.stacks.len());
+
+        let stack = &profile.stacks[0];
+        assert_eq!(4, stack.addrs.len());
+        assert_eq!(4, stack.addrs[0]);
+    }
+
+    #[test]
+    fn test_parse_profile_optional_endings() {
+        let options = ParseOptions::default();
+        let stacks = "heap_v2/524288
+  t*: 1: 16 [0: 0]
+@ 0x1
+  t*: 1: 16 [0: 0]";
+
+        for data in [stacks.to_string(), format!("{}\n", stacks), format!("{}\n\n", stacks)] {
+            let (rest, profile) = parse_profile(&data, &options).unwrap();
+            assert_eq!("", rest);
+            assert_eq!(1, profile.stacks.len());
+            assert_eq!(16, profile.stacks[0].threads[0].metrics().inuse_space);
+            assert!(profile.mapped_libraries.is_empty());
+        }
+
+        let data = format!("{}\n\nMAPPED_LIBRARIES:\n00001000-00002000 r-xp 00000000 103:02 5000 /usr/lib/liba.so", stacks);
+        let (rest, profile) = parse_profile(&data, &options).unwrap();
+        assert_eq!("", rest);
+        assert_eq!("/usr/lib/liba.so", profile.mapped_libraries[0].path);
+    }
+
+    #[test]
+    fn test_parse_header() {
+        let data = "heap_v2/12345";
+        let (_, sampling_rate) = parse_header(data).unwrap();
+        assert_eq!(12345, sampling_rate)
+    }
+
+    #[test]
+    fn test_parse_stack_addrs() {
+        let data = "@ 0x000000000001 0x000000000002 0x000000000003 0x000000000004";
+        let (_, addrs) = parse_stack_addrs(data).unwrap();
+        assert_eq!(4, addrs.len());
+        assert_eq!(1, addrs[0]);
+        assert_eq!(2, addrs[1]);
+        assert_eq!(3, addrs[2]);
+        assert_eq!(4, addrs[3]);
+
+        let data = "@ 0x1 (libfoo) 0x2 0x3 (main+0x10)\n";
+        let (rest, addrs) = parse_stack_addrs(data).unwrap();
+        assert_eq!(vec![1, 2, 3], addrs);
+        assert_eq!("\n", rest);
+    }
+
+    #[test]
+    fn test_parse_library() {
+        let data = "00000001-00000004 r--p 00000000 103:02 5000                      /usr/lib/x86_64-linux-gnu/libgcc_s.so.1";
+        let (_, lib) = parse_mapped_library(data).unwrap();
+        assert_eq!("/usr/lib/x86_64-linux-gnu/libgcc_s.so.1", lib.path);
+        assert_eq!(1, lib.first);
+        assert_eq!(4, lib.last);
+
+        let data = "55d0c0a00000-55d0c0a21000 r-xp 00002000 fe:01 280542 /usr/bin/server";
+        let (_, lib) = parse_mapped_library(data).unwrap();
+        assert_eq!("/usr/bin/server", lib.path);
+        assert_eq!(0x2000, lib.offset);
+        assert!(!lib.is_deleted());
+
+        let data = "7f0000000000-7f0000001000 r-xp 00000000 fe:01 1234 /opt/My App/lib.so (deleted)";
+        let (_, lib) = parse_mapped_library(data).unwrap();
+        assert_eq!("/opt/My App/lib.so", lib.path);
+        assert!(lib.is_deleted());
+
+        let data = "7f0000000000-7f0000001000 r-xp 00000000 fe:01 1234 \"/opt/My App/lib.so\" (deleted)";
+        let (_, lib) = parse_mapped_library(data).unwrap();
+        assert_eq!("/opt/My App/lib.so", lib.path);
+        assert!(lib.is_deleted());
+
+        let data = "7f0000000000-7f0000001000 rw-p 00000000 00:00 0";
+        let (rest, lib) = parse_mapped_library(data).unwrap();
+        assert_eq!("", rest);
+        assert_eq!("", lib.path);
+    }
+
+    #[test]
+    fn test_parse_thread() {
+        let data = "t123: 5000: 6000 [7000: 8000]";
+        let result = parse_thread(data);
+
+        match result {
+            Ok((_, thread)) => {
+                assert_eq!(thread.id, ThreadId::Numeric(123));
+                assert_eq!(thread.inuse_count, 5000);
+                assert_eq!(thread.insuse_space, 6000);
+                assert_eq!(thread.alloc_count, 7000);
+                assert_eq!(thread.alloc_space, 8000);
+            }
+            Err(err) => panic!("Parsing failed with error: {:?}", err),
+        }
+    }
+
+    #[test]
+    fn test_parse_thread_star() {
+        let data = "t*: 5000: 6000 [7000: 9000]";
+        let result = parse_thread(data);
+
+        match result {
+            Ok((_, thread)) => {
+                assert_eq!(thread.id, ThreadId::Total);
+                assert_eq!(thread.inuse_count, 5000);
+                assert_eq!(thread.insuse_space, 6000);
+                assert_eq!(thread.alloc_count, 7000);
+                assert_eq!(thread.alloc_space, 9000);
+            }
+            Err(err) => panic!("Parsing failed with error: {:?}", err),
+        }
+    }
+
+    #[test]
+    fn test_parse_thread_extra() {
+        let (rest, thread) = parse_thread("t1: 1: 2 [3: 4] [5: 6] [7: 8]\n").unwrap();
+        assert_eq!("\n", rest);
+        assert_eq!(4, thread.alloc_space);
+        assert_eq!(vec![(5, 6), (7, 8)], thread.extra);
+
+        let data = "heap_v2/524288
+  t*: 1: 16 [1: 16] [2: 8192]
+@ 0x1
+  t*: 1: 16 [1: 16] [2: 8192]
+  t0: 1: 16 [1: 16]
+";
+        let (rest, profile) = parse_profile(data, &ParseOptions::default()).unwrap();
+        assert_eq!("", rest);
+        assert_eq!(vec![(2, 8192)], profile.totals[0].extra);
+        assert_eq!(vec![(2, 8192)], profile.stacks[0].threads[0].extra);
+        assert!(profile.stacks[0].threads[1].extra.is_empty());
+    }
+
+    #[test]
+    fn test_parse_thread_named() {
+        let (_, thread) = parse_thread("tworker7: 1: 2 [3: 4]").unwrap();
+        assert_eq!(ThreadId::Named("worker7".into()), thread.id);
+
+        let (_, thread) = parse_thread("t0a: 1: 2 [3: 4]").unwrap();
+        assert_eq!(ThreadId::Named("0a".into()), thread.id);
+
+        let (_, thread) = parse_thread("t99999999999999999999999: 1: 2 [3: 4]").unwrap();
+        assert_eq!(ThreadId::Named("99999999999999999999999".into()), thread.id);
+    }
+}
@@ -0,0 +1,416 @@
+//! The protobuf profile format read by `pprof` and `go tool pprof`.
+//!
+//! The message is small enough to encode by hand, which saves depending on a
+//! protobuf code generator. Field numbers follow `profile.proto` from
+//! github.com/google/pprof.
+
+use std::collections::{BTreeMap, HashMap};
+use std::time::UNIX_EPOCH;
+
+use crate::{Metric, Metrics, Profile, Stack, ThreadId, ValueMode};
+
+/// The sample types of a Go heap profile, in the order `runtime/pprof`
+/// writes them.
+const SAMPLE_TYPES: [(&str, &str); 4] = [
+    ("alloc_objects", "count"),
+    ("alloc_space", "bytes"),
+    ("inuse_objects", "count"),
+    ("inuse_space", "bytes"),
+];
+
+/// The sample type `pprof` opens on, as in Go heap profiles.
+const DEFAULT_SAMPLE_TYPE: &str = "inuse_space";
+
+/// Choices for [`Profile::to_pprof_with`].
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct PprofOptions {
+    /// Whether samples carry the sampled values or unsampled estimates,
+    /// recorded in the profile's comments either way.
+    pub values: ValueMode,
+    /// Collapse every stack to its leaf frame and merge stacks sharing a
+    /// leaf into one single-location sample, for a flat profile of the
+    /// allocation sites that stays small and opens instantly. Only leaf
+    /// addresses become locations. Unset by default, keeping full stacks.
+    pub leaf_only: bool,
+    /// Emit one sample per thread row of each stack instead of one per
+    /// stack, labelled `thread` with the thread's id, so that `pprof` can
+    /// focus on or group by thread. Stacks with only a `t*` row keep a
+    /// single, unlabelled sample. Unset by default, which keeps files
+    /// small.
+    pub per_thread: bool,
+}
+
+impl<'a> Profile<'a> {
+    /// Encodes the profile as an uncompressed `pprof` protobuf, laid out
+    /// like a Go heap profile so that `go tool pprof -http` opens it on
+    /// `inuse_space`.
+    ///
+    /// Every stack with a `t*` row becomes one sample carrying, in Go's
+    /// order, `alloc_objects`, `alloc_space`, `inuse_objects` and
+    /// `inuse_space`. Locations hold the raw addresses and point at the
+    /// mapped library containing them, which `pprof` uses to symbolize the
+    /// file on its own. The sampling rate is recorded as the period, in
+    /// bytes.
+    ///
+    /// Samples are sorted by `inuse_space`, largest first, then by address,
+    /// and locations by address, so two exports of the same profile are
+    /// byte-identical whatever order its stacks were dumped in.
+    pub fn to_pprof(&self) -> Vec<u8> {
+        self.to_pprof_with(&PprofOptions::default())
+    }
+
+    /// Like [`Profile::to_pprof`], encoding as `options` says.
+    pub fn to_pprof_with(&self, options: &PprofOptions) -> Vec<u8> {
+        let mut strings = StringTable::default();
+        let mut profile = Message::default();
+
+        for (name, unit) in SAMPLE_TYPES {
+            profile.message(1, value_type(&mut strings, name, unit));
+        }
+
+        let stacks = self.export_order(Metric::InuseSpace, options.values);
+        let mut leaves: BTreeMap<(u64, Option<&ThreadId>), Metrics> = BTreeMap::new();
+        let (addrs, ids, samples) = if options.leaf_only {
+            for (stack, metrics) in &stacks {
+                if let Some(leaf) = stack.leaf() {
+                    for (thread, metrics) in sample_rows(stack, *metrics, options, self.sampling_rate) {
+                        let sum = leaves.entry((leaf, thread)).or_default();
+                        *sum = *sum + metrics;
+                    }
+                }
+            }
+
+            let mut addrs: Vec<u64> = leaves.keys().map(|(leaf, _)| *leaf).collect();
+            addrs.dedup();
+            let ids = addrs.iter().enumerate().map(|(id, addr)| (*addr, id as u32)).collect();
+            let mut samples: Vec<(&[u64], Option<&ThreadId>, Metrics)> = leaves
+                .iter()
+                .map(|((leaf, thread), metrics)| (std::slice::from_ref(leaf), *thread, *metrics))
+                .collect();
+            samples.sort_by(|(a, a_thread, a_metrics), (b, b_thread, b_metrics)| {
+                b_metrics.inuse_space.cmp(&a_metrics.inuse_space).then_with(|| (a, a_thread).cmp(&(b, b_thread)))
+            });
+            (addrs, ids, samples)
+        } else {
+            let (addrs, ids) = self.location_table();
+            let samples = stacks
+                .iter()
+                .flat_map(|(stack, metrics)| {
+                    sample_rows(stack, *metrics, options, self.sampling_rate).into_iter().map(|(thread, metrics)| (&stack.addrs[..], thread, metrics))
+                })
+                .collect();
+            (addrs, ids, samples)
+        };
+
+        let thread_key = options.per_thread.then(|| strings.index("thread"));
+        for (stack, thread, metrics) in samples {
+            let mut sample = Message::default();
+            sample.packed(1, stack.iter().map(|addr| u64::from(ids[addr]) + 1));
+            sample.packed(
+                2,
+                [metrics.alloc_count, metrics.alloc_space, metrics.inuse_count, metrics.inuse_space].map(int64),
+            );
+            if let (Some(key), Some(thread)) = (thread_key, thread) {
+                let mut label = Message::default();
+                label.uint(1, key);
+                label.uint(2, strings.index(&thread.to_string()[1..]));
+                sample.message(3, label);
+            }
+            profile.message(2, sample);
+        }
+
+        for (id, lib) in self.mapped_libraries.iter().enumerate() {
+            let mut mapping = Message::default();
+            mapping.uint(1, id as u64 + 1);
+            mapping.uint(2, lib.first);
+            mapping.uint(3, lib.last);
+            mapping.uint(4, lib.offset);
+            mapping.uint(5, strings.index(&lib.path));
+            profile.message(3, mapping);
+        }
+
+        let libraries = self.library_ranges();
+        for (id, addr) in addrs.iter().enumerate() {
+            let mut location = Message::default();
+            location.uint(1, id as u64 + 1);
+            location.uint(2, libraries.lookup(*addr).map_or(0, |index| *index as u64 + 1));
+            location.uint(3, *addr);
+            profile.message(4, location);
+        }
+
+        let period_type = value_type(&mut strings, "space", "bytes");
+        let default_sample_type = strings.index(DEFAULT_SAMPLE_TYPE);
+        let comment = strings.index(&format!("jemalloc heap profile, {} values", options.values.name()));
+        for string in &strings.strings {
+            profile.bytes(6, string.as_bytes());
+        }
+
+        if let Some(nanos) = self.captured_at.and_then(|at| at.duration_since(UNIX_EPOCH).ok()) {
+            profile.uint(9, int64(nanos.as_nanos().try_into().unwrap_or(u64::MAX)));
+        }
+        profile.message(11, period_type);
+        profile.uint(12, int64(self.sampling_rate));
+        profile.packed(13, [comment]);
+        profile.uint(14, default_sample_type);
+
+        profile.0
+    }
+}
+
+/// The rows of `stack` to sample, with the thread to label each by: its
+/// thread rows with [`PprofOptions::per_thread`], otherwise, or if it has
+/// none, its `t*` `metrics` unlabelled.
+fn sample_rows<'s, 'a>(
+    stack: &'s Stack<'a>,
+    metrics: Metrics,
+    options: &PprofOptions,
+    sampling_rate: u64,
+) -> Vec<(Option<&'s ThreadId<'a>>, Metrics)> {
+    let threads: Vec<(Option<&ThreadId>, Metrics)> = stack
+        .threads
+        .iter()
+        .filter(|thread| options.per_thread && thread.id != ThreadId::Total)
+        .map(|thread| (Some(&thread.id), options.values.apply(thread.metrics(), sampling_rate)))
+        .collect();
+
+    if threads.is_empty() {
+        vec![(None, metrics)]
+    } else {
+        threads
+    }
+}
+
+fn value_type(strings: &mut StringTable, name: &str, unit: &str) -> Message {
+    let mut value_type = Message::default();
+    value_type.uint(1, strings.index(name));
+    value_type.uint(2, strings.index(unit));
+    value_type
+}
+
+/// Clamps a counter into the `int64` fields `pprof` stores values in.
+fn int64(value: u64) -> u64 {
+    value.min(i64::MAX as u64)
+}
+
+/// The string table every other message refers into by index. Index 0 is
+/// always the empty string.
+struct StringTable {
+    strings: Vec<String>,
+    ids: HashMap<String, u64>,
+}
+
+impl Default for StringTable {
+    fn default() -> Self {
+        StringTable {
+            strings: vec![String::new()],
+            ids: HashMap::from([(String::new(), 0)]),
+        }
+    }
+}
+
+impl StringTable {
+    fn index(&mut self, string: &str) -> u64 {
+        if let Some(id) = self.ids.get(string) {
+            return *id;
+        }
+
+        let id = self.strings.len() as u64;
+        self.strings.push(string.to_string());
+        self.ids.insert(string.to_string(), id);
+        id
+    }
+}
+
+/// An encoded protobuf message. Zero integers are left out, as proto3 does.
+#[derive(Default)]
+struct Message(Vec<u8>);
+
+impl Message {
+    fn uint(&mut self, field: u32, value: u64) {
+        if value != 0 {
+            self.key(field, 0);
+            varint(&mut self.0, value);
+        }
+    }
+
+    fn bytes(&mut self, field: u32, bytes: &[u8]) {
+        self.key(field, 2);
+        varint(&mut self.0, bytes.len() as u64);
+        self.0.extend_from_slice(bytes);
+    }
+
+    fn message(&mut self, field: u32, message: Message) {
+        self.bytes(field, &message.0);
+    }
+
+    fn packed(&mut self, field: u32, values: impl IntoIterator<Item = u64>) {
+        let mut packed = Vec::new();
+        for value in values {
+            varint(&mut packed, value);
+        }
+        self.bytes(field, &packed);
+    }
+
+    fn key(&mut self, field: u32, wire_type: u8) {
+        varint(&mut self.0, u64::from(field) << 3 | u64::from(wire_type));
+    }
+}
+
+fn varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The fields of one message, with varints decoded and length-delimited
+    /// fields left as bytes.
+    fn fields(mut data: &[u8]) -> Vec<(u64, Result<u64, &[u8]>)> {
+        fn read_varint(data: &mut &[u8]) -> u64 {
+            let mut value = 0;
+            for shift in (0..).step_by(7) {
+                let byte = data[0];
+                *data = &data[1..];
+                value |= u64::from(byte & 0x7f) << shift;
+                if byte < 0x80 {
+                    break;
+                }
+            }
+            value
+        }
+
+        let mut fields = Vec::new();
+        while !data.is_empty() {
+            let key = read_varint(&mut data);
+            let value = match key & 7 {
+                0 => Ok(read_varint(&mut data)),
+                2 => {
+                    let len = read_varint(&mut data) as usize;
+                    let (bytes, rest) = data.split_at(len);
+                    data = rest;
+                    Err(bytes)
+                }
+                wire_type => panic!("unexpected wire type {}", wire_type),
+            };
+            fields.push((key >> 3, value));
+        }
+        fields
+    }
+
+    #[test]
+    fn test_to_pprof() {
+        let data = "heap_v2/524288
+  t*: 3: 48 [5: 80]
+@ 0x1010 0x2010
+  t*: 1: 16 [2: 32]
+@ 0x1020 0x2010
+  t*: 2: 32 [3: 48]
+
+MAPPED_LIBRARIES:
+00001000-00002000 r-xp 00000000 103:02 5000 /usr/lib/liba.so
+";
+        let encoded = Profile::parse(data).unwrap().to_pprof();
+        let profile = fields(&encoded);
+        let field = |number| profile.iter().filter(move |(n, _)| *n == number).map(|(_, value)| *value);
+
+        let strings: Vec<&str> = field(6).map(|value| std::str::from_utf8(value.unwrap_err()).unwrap()).collect();
+        assert_eq!("", strings[0]);
+
+        let sample_types: Vec<&str> = field(1)
+            .map(|value| match fields(value.unwrap_err())[0] {
+                (1, Ok(name)) => strings[name as usize],
+                _ => unreachable!(),
+            })
+            .collect();
+        assert_eq!(vec!["alloc_objects", "alloc_space", "inuse_objects", "inuse_space"], sample_types);
+        assert_eq!(Some(Ok(strings.iter().position(|s| *s == "inuse_space").unwrap() as u64)), field(14).next());
+        assert_eq!(Some(Ok(524288)), field(12).next());
+        let comment = |encoded: &[u8]| {
+            let profile = fields(encoded);
+            let strings: Vec<&[u8]> = profile.iter().filter(|(n, _)| *n == 6).map(|(_, value)| value.unwrap_err()).collect();
+            let id = profile.iter().find(|(n, _)| *n == 13).unwrap().1.unwrap_err()[0];
+            String::from_utf8(strings[id as usize].to_vec()).unwrap()
+        };
+        assert_eq!("jemalloc heap profile, sampled values", comment(&encoded));
+
+        assert_eq!(2, field(2).count());
+        let sample = fields(field(2).next().unwrap().unwrap_err());
+        assert_eq!((1, Err(&[2, 3][..])), sample[0]);
+        assert_eq!((2, Err(&[3, 48, 2, 32][..])), sample[1]);
+
+        assert_eq!(1, field(3).count());
+        assert_eq!(3, field(4).count());
+        let unmapped = fields(field(4).nth(2).unwrap().unwrap_err());
+        assert_eq!(vec![(1, Ok(3)), (3, Ok(0x2010))], unmapped);
+
+        let options = PprofOptions { values: ValueMode::Unsampled, ..Default::default() };
+        let unsampled = Profile::parse(data).unwrap().to_pprof_with(&options);
+        assert_eq!("jemalloc heap profile, unsampled values", comment(&unsampled));
+        assert_ne!(encoded, unsampled);
+    }
+
+    #[test]
+    fn test_to_pprof_leaf_only() {
+        let data = "heap_v2/524288
+  t*: 4: 64 [5: 80]
+@ 0x1010 0x2010
+  t*: 1: 16 [2: 32]
+@ 0x1010 0x2020
+  t*: 2: 32 [2: 32]
+@ 0x3000 0x2010
+  t*: 1: 16 [1: 16]
+";
+        let options = PprofOptions { leaf_only: true, ..Default::default() };
+        let encoded = Profile::parse(data).unwrap().to_pprof_with(&options);
+        let profile = fields(&encoded);
+        let field = |number| profile.iter().filter(move |(n, _)| *n == number).map(|(_, value)| *value);
+
+        let samples: Vec<_> = field(2).map(|sample| fields(sample.unwrap_err())).collect();
+        assert_eq!(2, samples.len());
+        assert_eq!((1, Err(&[1][..])), samples[0][0]);
+        assert_eq!((2, Err(&[4, 64, 3, 48][..])), samples[0][1]);
+        assert_eq!((1, Err(&[2][..])), samples[1][0]);
+
+        let locations: Vec<_> = field(4).map(|location| fields(location.unwrap_err())).collect();
+        assert_eq!(vec![vec![(1, Ok(1)), (3, Ok(0x1010))], vec![(1, Ok(2)), (3, Ok(0x3000))]], locations);
+    }
+
+    #[test]
+    fn test_to_pprof_per_thread() {
+        let data = "heap_v2/524288
+  t*: 3: 48 [3: 48]
+  t0: 2: 32 [2: 32]
+  tworker: 1: 16 [1: 16]
+@ 0x1010 0x2010
+  t*: 3: 48 [3: 48]
+  t0: 2: 32 [2: 32]
+  tworker: 1: 16 [1: 16]
+@ 0x1020
+  t*: 1: 16 [1: 16]
+";
+        let options = PprofOptions { per_thread: true, ..Default::default() };
+        let encoded = Profile::parse(data).unwrap().to_pprof_with(&options);
+        let profile = fields(&encoded);
+        let field = |number| profile.iter().filter(move |(n, _)| *n == number).map(|(_, value)| *value);
+        let strings: Vec<&str> = field(6).map(|value| std::str::from_utf8(value.unwrap_err()).unwrap()).collect();
+
+        let labels: Vec<Option<(&str, &str)>> = field(2)
+            .map(|sample| {
+                let label = fields(sample.unwrap_err()).into_iter().find(|(n, _)| *n == 3)?;
+                match fields(label.1.unwrap_err())[..] {
+                    [(1, Ok(key)), (2, Ok(value))] => Some((strings[key as usize], strings[value as usize])),
+                    _ => unreachable!(),
+                }
+            })
+            .collect();
+        assert_eq!(vec![Some(("thread", "0")), Some(("thread", "worker")), None], labels);
+
+        let plain = Profile::parse(data).unwrap().to_pprof();
+        assert!(!fields(&plain).iter().any(|(n, value)| *n == 6 && *value == Err(&b"thread"[..])));
+    }
+}
@@ -0,0 +1,135 @@
+//! Lookup of the address range containing an address.
+
+/// Half-open `[start, end)` address ranges, each carrying a value, sorted
+/// for lookup by binary search.
+///
+/// Profiles use it to find the mapped library holding an address, see
+/// [`Profile::library_ranges`](crate::Profile::library_ranges), and it is
+/// public for building similar indexes, e.g. over a symbol table with sizes.
+///
+/// ```
+/// use jeprof_rs::RangeIndex;
+///
+/// let index = RangeIndex::new([(0x2000, 0x3000, "libb.so"), (0x1000, 0x2000, "liba.so")]);
+/// assert_eq!(Some(&"liba.so"), index.lookup(0x1fff));
+/// assert_eq!(None, index.lookup(0x3000));
+/// ```
+#[derive(Debug, Clone)]
+pub struct RangeIndex<T> {
+    /// `(start, end, value)` sorted by start, then end, then insertion.
+    ranges: Vec<(u64, u64, T)>,
+    /// The greatest end among `ranges[..=i]`, which bounds how far back a
+    /// lookup has to look for a range reaching `addr`.
+    reach: Vec<u64>,
+}
+
+impl<T> RangeIndex<T> {
+    /// Builds an index over `(start, end, value)` ranges given in any order.
+    /// Empty ranges, where `end <= start`, never match a lookup.
+    pub fn new(ranges: impl IntoIterator<Item = (u64, u64, T)>) -> Self {
+        let mut ranges: Vec<(u64, u64, T)> = ranges.into_iter().collect();
+        ranges.sort_by_key(|(start, end, _)| (*start, *end));
+
+        let reach = ranges
+            .iter()
+            .scan(0, |reach, (_, end, _)| {
+                *reach = (*reach).max(*end);
+                Some(*reach)
+            })
+            .collect();
+
+        RangeIndex { ranges, reach }
+    }
+
+    /// The value of the range containing `addr`.
+    ///
+    /// Where ranges overlap, the one that starts last wins, and among
+    /// identical ranges the one given first.
+    pub fn lookup(&self, addr: u64) -> Option<&T> {
+        let end = self.ranges.partition_point(|(start, _, _)| *start <= addr);
+        let mut found = (0..end)
+            .rev()
+            .take_while(|i| self.reach[*i] > addr)
+            .find(|i| addr < self.ranges[*i].1)?;
+
+        let (start, end, _) = self.ranges[found];
+        while found > 0 && self.ranges[found - 1].0 == start && self.ranges[found - 1].1 == end {
+            found -= 1;
+        }
+
+        Some(&self.ranges[found].2)
+    }
+
+    /// Every pair of overlapping ranges, by the values they carry. Pairs are
+    /// ordered by their later range, and each pair lists the range that
+    /// comes first in lookup order first. Empty ranges overlap nothing.
+    pub fn overlaps(&self) -> Vec<(&T, &T)> {
+        let mut overlaps = Vec::new();
+        // The earlier ranges still reaching past the start of the current one.
+        let mut open: Vec<&(u64, u64, T)> = Vec::new();
+
+        for range in self.ranges.iter().filter(|(start, end, _)| start < end) {
+            open.retain(|prev| prev.1 > range.0);
+            overlaps.extend(open.iter().map(|prev| (&prev.2, &range.2)));
+            open.push(range);
+        }
+
+        overlaps
+    }
+
+    /// The ranges in lookup order, by start address.
+    pub fn iter(&self) -> impl Iterator<Item = (u64, u64, &T)> {
+        self.ranges.iter().map(|(start, end, value)| (*start, *end, value))
+    }
+
+    pub fn len(&self) -> usize {
+        self.ranges.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lookup() {
+        let index = RangeIndex::new([(0x3000, 0x4000, 'c'), (0x1000, 0x2000, 'a'), (0x2000, 0x3000, 'b')]);
+        assert_eq!(3, index.len());
+        assert_eq!(None, index.lookup(0xfff));
+        assert_eq!(Some(&'a'), index.lookup(0x1000));
+        assert_eq!(Some(&'b'), index.lookup(0x2000));
+        assert_eq!(Some(&'c'), index.lookup(0x3fff));
+        assert_eq!(None, index.lookup(0x4000));
+        assert!(index.overlaps().is_empty());
+
+        let starts: Vec<u64> = index.iter().map(|(start, _, _)| start).collect();
+        assert_eq!(vec![0x1000, 0x2000, 0x3000], starts);
+    }
+
+    #[test]
+    fn test_lookup_overlapping() {
+        let index = RangeIndex::new([
+            (0x1000, 0x9000, 'a'),
+            (0x2000, 0x3000, 'b'),
+            (0x2000, 0x3000, 'c'),
+            (0x4000, 0x4000, 'e'),
+        ]);
+        assert_eq!(Some(&'b'), index.lookup(0x2800));
+        assert_eq!(Some(&'a'), index.lookup(0x3800));
+        assert_eq!(Some(&'a'), index.lookup(0x4000));
+        assert_eq!(None, index.lookup(0x9000));
+        assert_eq!(vec![(&'a', &'b'), (&'a', &'c'), (&'b', &'c')], index.overlaps());
+
+        let index = RangeIndex::new([
+            (0x1000, 0x3000, 'a'),
+            (0x2000, 0x4000, 'b'),
+            (0x3000, 0x5000, 'c'),
+            (0x6000, 0x7000, 'd'),
+        ]);
+        assert_eq!(vec![(&'a', &'b'), (&'b', &'c')], index.overlaps());
+    }
+}
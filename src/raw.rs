@@ -0,0 +1,70 @@
+//! The `--raw` output of jeprof: a symbol table followed by the heap dump,
+//! which `jeprof` reads back without needing the binaries.
+
+use std::fmt::Write;
+
+use crate::{Profile, SymbolizedProfile};
+
+impl<'a> Profile<'a> {
+    /// Serializes the profile as `jeprof --raw` does, with an empty symbol
+    /// table, so that jeprof-based pipelines can ingest a parsed and
+    /// filtered profile and symbolize it themselves. Use
+    /// [`SymbolizedProfile::to_jeprof_raw`] to ship the symbols along.
+    ///
+    /// The heap section is [`Profile::to_heap_text`].
+    pub fn to_jeprof_raw(&self) -> Vec<u8> {
+        jeprof_raw(self, "")
+    }
+}
+
+impl<'p, 'a> SymbolizedProfile<'p, 'a> {
+    /// Like [`Profile::to_jeprof_raw`], with a symbol table entry for every
+    /// address that resolved.
+    ///
+    /// As in jeprof, entries are keyed by the address looked up, one byte
+    /// before a return address, and list the inlined frames outermost first,
+    /// separated by `--`.
+    pub fn to_jeprof_raw(&self) -> Vec<u8> {
+        let mut resolved: Vec<_> = self.resolved().filter(|(_, frames)| !frames.is_empty()).collect();
+        resolved.sort_unstable_by_key(|(addr, _)| *addr);
+
+        let mut symbols = String::new();
+        for (addr, frames) in resolved {
+            let names: Vec<&str> = frames.iter().rev().map(|frame| self.name(frame.function)).collect();
+            let _ = writeln!(symbols, "0x{:016x} {}", addr, names.join("--"));
+        }
+
+        jeprof_raw(self.profile(), &symbols)
+    }
+}
+
+fn jeprof_raw(profile: &Profile, symbols: &str) -> Vec<u8> {
+    let mut out = String::from("--- symbol\n");
+    out.push_str(symbols);
+    out.push_str("---\n--- heap\n");
+    out.push_str(&profile.to_heap_text());
+    out.into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    #[test]
+    fn test_to_jeprof_raw() {
+        let data = "heap_v2/524288
+  t*: 1: 16 [0: 0]
+@ 0x1010 0x2000
+  t*: 1: 16 [0: 0]
+";
+        let profile = Profile::parse(data).unwrap();
+        let raw = String::from_utf8(profile.to_jeprof_raw()).unwrap();
+        assert_eq!(format!("--- symbol\n---\n--- heap\n{}", profile.to_heap_text()), raw);
+
+        let map = BTreeMap::from([(0x1000, "malloc".to_string()), (0x1f00, "main".to_string())]);
+        let raw = String::from_utf8(profile.apply_symbol_map(&map).to_jeprof_raw()).unwrap();
+        assert!(raw.starts_with("--- symbol\n0x0000000000001010 malloc\n0x0000000000001fff main\n---\n--- heap\nheap_v2/524288\n"));
+    }
+}
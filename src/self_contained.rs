@@ -0,0 +1,102 @@
+//! A parsed profile stored together with the text it borrows from.
+
+use std::fmt;
+use std::io::Read;
+
+use yoke::Yoke;
+
+use crate::{ParseError, ParseOptions, Profile};
+
+/// A [`Profile`] that owns its input, for returning a parsed profile from a
+/// function without the copying of [`Profile::into_owned`].
+///
+/// Every string in the profile still points into the input, so the profile
+/// is only handed out as a view tied to a borrow of the container, see
+/// [`SelfContainedProfile::profile`].
+///
+/// ```
+/// use jeprof_rs::SelfContainedProfile;
+///
+/// fn load() -> SelfContainedProfile {
+///     let text = String::from("heap_v2/524288\n  t*: 1: 16 [0: 0]\n@ 0x1\n  t*: 1: 16 [0: 0]\n");
+///     SelfContainedProfile::parse(text).unwrap()
+/// }
+///
+/// assert_eq!(1, load().profile().stacks.len());
+/// ```
+pub struct SelfContainedProfile {
+    profile: Yoke<Profile<'static>, Box<str>>,
+}
+
+impl SelfContainedProfile {
+    /// Parses `text` with [`ParseOptions::default`], taking ownership of it.
+    pub fn parse(text: String) -> Result<Self, ParseError> {
+        Self::parse_with(text, &ParseOptions::default())
+    }
+
+    /// Like [`Profile::parse_with`], taking ownership of `text`.
+    pub fn parse_with(text: String, options: &ParseOptions) -> Result<Self, ParseError> {
+        let profile = Yoke::try_attach_to_cart(text.into_boxed_str(), |text| Profile::parse_with(text, options))?;
+        Ok(SelfContainedProfile { profile })
+    }
+
+    /// Reads `reader` to the end and parses what it read. Failures to read,
+    /// including input that is not UTF-8, are reported as
+    /// [`ParseError::Io`].
+    pub fn from_reader<R: Read>(mut reader: R) -> Result<Self, ParseError> {
+        let mut text = String::new();
+        reader.read_to_string(&mut text).map_err(|err| ParseError::Io(err.kind()))?;
+        Self::parse(text)
+    }
+
+    /// The parsed profile, borrowing from the container.
+    pub fn profile(&self) -> &Profile<'_> {
+        self.profile.get()
+    }
+
+    /// The input the profile was parsed from.
+    pub fn text(&self) -> &str {
+        self.profile.backing_cart()
+    }
+}
+
+impl fmt::Debug for SelfContainedProfile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("SelfContainedProfile").field(self.profile()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DATA: &str = "heap_v2/524288
+  t*: 1: 16 [0: 0]
+@ 0x1010
+  t*: 1: 16 [0: 0]
+
+MAPPED_LIBRARIES:
+00001000-00002000 r-xp 00000000 103:02 5000 /usr/lib/libfoo.so
+";
+
+    #[test]
+    fn test_self_contained_profile() {
+        let contained = SelfContainedProfile::parse(DATA.to_string()).unwrap();
+
+        // Moving the container leaves the borrowed strings valid.
+        let moved = Box::new(contained);
+        let profile = moved.profile();
+        assert_eq!("/usr/lib/libfoo.so", profile.mapped_libraries[0].path);
+        assert!(matches!(profile.mapped_libraries[0].path, std::borrow::Cow::Borrowed(_)));
+        assert_eq!(DATA, moved.text());
+
+        let from_reader = SelfContainedProfile::from_reader(DATA.as_bytes()).unwrap();
+        assert_eq!(vec![0x1010], from_reader.profile().stacks[0].addrs);
+
+        assert_eq!(Err(ParseError::UnsupportedFormat), SelfContainedProfile::parse("heap_v3/1\n".to_string()).map(|_| ()));
+        assert!(matches!(SelfContainedProfile::from_reader(&[0xff, 0xfe][..]), Err(ParseError::Io(_))));
+
+        fn send_sync<T: Send + Sync>() {}
+        send_sync::<SelfContainedProfile>();
+    }
+}
@@ -0,0 +1,380 @@
+//! Incremental parsing of a profile that arrives in chunks.
+
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::str;
+
+use crate::error::line_at;
+use crate::parser;
+use crate::{is_header, skip_preamble, strip_bom, Metric, ParseError, ParseOptions, Profile, Stack, ThreadId, MAX_PREAMBLE_LINES};
+
+/// Parses a profile pushed to it chunk by chunk, handing out each stack as
+/// soon as it is complete.
+///
+/// A stack is only known to be complete once the line after it arrives, so
+/// the stack currently being read, and any partial line at the end of a
+/// chunk, are held back until the next [`feed`](StreamParser::feed) or
+/// [`finish`](StreamParser::finish). Everything else is dropped from the
+/// buffer, so memory stays bounded by the largest stack rather than by the
+/// whole profile. The totals block and the mapped libraries are skipped, as
+/// is the preamble before the header, as in [`Profile::parse_with`].
+///
+/// ```
+/// use jeprof_rs::StreamParser;
+///
+/// let mut parser = StreamParser::new();
+/// assert!(parser.feed(b"heap_v2/524288\n  t*: 1: 16 [0: 0]\n@ 0x1\n  t*: 1").unwrap().is_empty());
+/// assert_eq!(1, parser.feed(b": 16 [0: 0]\n@ 0x2\n  t*: 1: 16 [0: 0]\n").unwrap().len());
+/// assert_eq!(1, parser.finish().unwrap().len());
+/// ```
+#[derive(Debug, Default)]
+pub struct StreamParser {
+    buffer: Vec<u8>,
+    /// Offset in `buffer` up to which complete lines have been scanned.
+    scanned: usize,
+    /// Offset in `buffer`, and line number, of the stack being read.
+    stack_start: Option<(usize, usize)>,
+    /// Complete lines scanned so far.
+    lines: usize,
+    section: Section,
+    /// Lines before the header that are neither blank nor comments.
+    preamble_lines: usize,
+    sampling_rate: Option<u64>,
+    /// Bytes fed so far.
+    bytes: usize,
+    /// Stacks found so far, counted before they are parsed.
+    stacks: usize,
+    options: ParseOptions,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum Section {
+    #[default]
+    Header,
+    Totals,
+    Stacks,
+    Libraries,
+}
+
+impl StreamParser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A parser reading thread lines and stacks as `options` says, and
+    /// enforcing its [`max_input_bytes`](ParseOptions::max_input_bytes)
+    /// and [`max_stacks`](ParseOptions::max_stacks) as chunks arrive: a
+    /// chunk that would take the input past the byte limit is refused
+    /// before it is buffered. Options about the totals block or the maps
+    /// section have no effect.
+    pub fn with_options(options: ParseOptions) -> Self {
+        StreamParser {
+            options,
+            ..Self::default()
+        }
+    }
+
+    /// The sampling rate from the header, once the header has been read.
+    pub fn sampling_rate(&self) -> Option<u64> {
+        self.sampling_rate
+    }
+
+    /// Appends `chunk` and returns the stacks it completed.
+    pub fn feed(&mut self, chunk: &[u8]) -> Result<Vec<Stack<'_>>, ParseError> {
+        self.bytes = self.bytes.saturating_add(chunk.len());
+        if let Some(max) = self.options.max_input_bytes.filter(|max| self.bytes > *max) {
+            return Err(ParseError::LimitExceeded { option: "max_input_bytes", max });
+        }
+
+        let keep_from = self.stack_start.map_or(self.scanned, |(start, _)| start);
+        self.buffer.drain(..keep_from);
+        self.scanned -= keep_from;
+        if let Some((start, _)) = &mut self.stack_start {
+            *start -= keep_from;
+        }
+
+        self.buffer.extend_from_slice(chunk);
+
+        let mut complete = Vec::new();
+        while let Some(len) = self.buffer[self.scanned..].iter().position(|b| *b == b'\n') {
+            let start = self.scanned;
+            self.scanned += len + 1;
+            self.lines += 1;
+            self.scan_line(start, start + len, &mut complete)?;
+        }
+
+        self.count_stacks(complete.len())?;
+        self.parse_stacks(complete)
+    }
+
+    /// Consumes whatever is left, including a final line without a line
+    /// ending, and returns the stacks it completed.
+    pub fn finish(&mut self) -> Result<Vec<Stack<'_>>, ParseError> {
+        let mut complete = Vec::new();
+
+        if self.scanned < self.buffer.len() {
+            let start = self.scanned;
+            self.scanned = self.buffer.len();
+            self.lines += 1;
+            self.scan_line(start, self.buffer.len(), &mut complete)?;
+        }
+
+        if self.section == Section::Header {
+            return Err(ParseError::UnsupportedFormat);
+        }
+
+        if let Some((start, line)) = self.stack_start.take() {
+            complete.push((start, self.buffer.len(), line));
+        }
+        self.section = Section::Libraries;
+
+        self.count_stacks(complete.len())?;
+        self.parse_stacks(complete)
+    }
+
+    /// Advances the state machine over the line at `buffer[start..end]`,
+    /// recording the range of every stack it closes in `complete`.
+    fn scan_line(&mut self, start: usize, end: usize, complete: &mut Vec<(usize, usize, usize)>) -> Result<(), ParseError> {
+        let line = &self.buffer[start..end];
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+
+        match self.section {
+            Section::Header => {
+                if line.trim_ascii().is_empty() || line.starts_with(b"#") {
+                    return Ok(());
+                }
+
+                let Some(text) = str::from_utf8(line).ok().filter(|text| is_header(text)) else {
+                    self.preamble_lines += 1;
+                    if self.preamble_lines > MAX_PREAMBLE_LINES {
+                        return Err(ParseError::UnsupportedFormat);
+                    }
+                    return Ok(());
+                };
+
+                let (_, sampling_rate) = parser::header(text, &self.options)
+                    .map_err(|err| ParseError::from_nom(text, err).on_line(self.lines))?;
+                self.sampling_rate = Some(sampling_rate);
+                self.section = Section::Totals;
+            }
+            Section::Totals => {
+                if line.starts_with(b"@") {
+                    self.stack_start = Some((start, self.lines));
+                    self.section = Section::Stacks;
+                } else if !line.starts_with(b" ") {
+                    return Err(ParseError::Malformed { line: self.lines });
+                }
+            }
+            Section::Stacks => {
+                if line.starts_with(b" ") {
+                    return Ok(());
+                }
+
+                if let Some((stack_start, stack_line)) = self.stack_start.take() {
+                    complete.push((stack_start, start, stack_line));
+                }
+
+                if line.starts_with(b"@") {
+                    self.stack_start = Some((start, self.lines));
+                } else {
+                    self.section = Section::Libraries;
+                }
+            }
+            Section::Libraries => {}
+        }
+
+        Ok(())
+    }
+
+    fn count_stacks(&mut self, complete: usize) -> Result<(), ParseError> {
+        self.stacks += complete;
+        match self.options.max_stacks {
+            Some(max) if self.stacks > max => Err(ParseError::LimitExceeded { option: "max_stacks", max }),
+            _ => Ok(()),
+        }
+    }
+
+    fn parse_stacks(&self, complete: Vec<(usize, usize, usize)>) -> Result<Vec<Stack<'_>>, ParseError> {
+        complete
+            .into_iter()
+            .map(|(start, end, line)| {
+                let text = str::from_utf8(&self.buffer[start..end]).map_err(|_| ParseError::Malformed { line })?;
+                let (rest, stack) =
+                    parser::stack(text, &self.options).map_err(|err| ParseError::from_nom(text, err).on_line(line))?;
+
+                if !rest.is_empty() {
+                    return Err(ParseError::Malformed { line: line_at(text, rest) + line - 1 });
+                }
+
+                Ok(stack)
+            })
+            .collect()
+    }
+}
+
+/// A stack reduced to its addresses and the value it was ranked by, see
+/// [`Profile::stream_top`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OwnedStackSummary {
+    pub addrs: Vec<u64>,
+    /// The `t*` value of the metric the stack was ranked by.
+    pub value: u64,
+}
+
+impl Profile<'static> {
+    /// The `n` stacks of `input` with the largest `t*` `metric`, largest
+    /// first and ties broken by address, as [`Profile::top`] would rank
+    /// them.
+    ///
+    /// Stacks are ranked as they are parsed, keeping a heap of the best `n`
+    /// so far, so no more than `n + 1` stacks are held at a time however
+    /// large the dump is. The maps section is not parsed.
+    pub fn stream_top(input: &str, n: usize, metric: Metric) -> Result<Vec<OwnedStackSummary>, ParseError> {
+        let input = strip_bom(input);
+        let profile = skip_preamble(input);
+        if !is_header(profile) {
+            return Err(ParseError::UnsupportedFormat);
+        }
+
+        // A min-heap on (value, reversed addrs), so the root is the stack
+        // that ranks last.
+        let mut heap: BinaryHeap<Reverse<(u64, Reverse<Vec<u64>>)>> = BinaryHeap::new();
+        parser::visit_stacks(profile, &ParseOptions::default(), |stack| {
+            let Some(thread) = stack.thread(ThreadId::Total) else {
+                return;
+            };
+
+            heap.push(Reverse((thread.metrics().get(metric), Reverse(stack.addrs))));
+            if heap.len() > n {
+                heap.pop();
+            }
+        })
+        .map_err(|err| ParseError::from_nom(input, err))?;
+
+        let top = heap
+            .into_sorted_vec()
+            .into_iter()
+            .map(|Reverse((value, Reverse(addrs)))| OwnedStackSummary { addrs, value })
+            .collect();
+        Ok(top)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROFILE: &str = "heap_v2/524288
+  t*: 3: 48 [0: 0]
+@ 0x1 0x2
+  t*: 1: 16 [0: 0]
+  t0: 1: 16 [0: 0]
+@ 0x3
+  t*: 2: 32 [0: 0]
+
+MAPPED_LIBRARIES:
+00001000-00002000 r-xp 00000000 103:02 5000 /usr/lib/liba.so
+";
+
+    #[test]
+    fn test_stream_top() {
+        let data = "heap_v2/524288
+  t*: 6: 112 [0: 0]
+@ 0x1
+  t*: 1: 16 [0: 0]
+@ 0x2
+  t*: 2: 48 [0: 0]
+@ 0x3
+  t*: 1: 16 [0: 0]
+@ 0x4
+  t*: 2: 32 [0: 0]
+";
+        let top = Profile::stream_top(data, 3, Metric::InuseSpace).unwrap();
+        assert_eq!(
+            vec![
+                OwnedStackSummary { addrs: vec![2], value: 48 },
+                OwnedStackSummary { addrs: vec![4], value: 32 },
+                OwnedStackSummary { addrs: vec![1], value: 16 },
+            ],
+            top
+        );
+
+        let profile = Profile::parse(data).unwrap();
+        let expected = profile.top(3, Metric::InuseSpace, ThreadId::Total);
+        let expected: Vec<Vec<u64>> = expected.iter().map(|(stack, _)| stack.addrs.clone()).collect();
+        assert_eq!(expected, top.iter().map(|stack| stack.addrs.clone()).collect::<Vec<_>>());
+
+        assert!(Profile::stream_top(data, 0, Metric::InuseSpace).unwrap().is_empty());
+        assert_eq!(Err(ParseError::UnsupportedFormat), Profile::stream_top("nope", 1, Metric::InuseSpace));
+    }
+
+    fn addrs(stacks: &[Stack]) -> Vec<Vec<u64>> {
+        stacks.iter().map(|stack| stack.addrs.clone()).collect()
+    }
+
+    #[test]
+    fn test_stream_parser_any_chunking() {
+        for size in 1..PROFILE.len() {
+            let mut parser = StreamParser::new();
+            let mut seen = Vec::new();
+
+            for chunk in PROFILE.as_bytes().chunks(size) {
+                seen.extend(addrs(&parser.feed(chunk).unwrap()));
+            }
+            seen.extend(addrs(&parser.finish().unwrap()));
+
+            assert_eq!(vec![vec![1, 2], vec![3]], seen, "chunk size {}", size);
+            assert_eq!(Some(524288), parser.sampling_rate());
+        }
+    }
+
+    #[test]
+    fn test_stream_parser_holds_back_partial_stack() {
+        let mut parser = StreamParser::new();
+
+        assert!(parser.feed(b"heap profile: 3: 48 [0: 0] @ heap_v2/524288\n").unwrap().is_empty());
+        assert!(parser.feed(b"@heap_v2/524288\n  t*: 3: 48 [0: 0]\n@ 0x1 0x2\n  t*: 1: 16 [0: 0]\n").unwrap().is_empty());
+
+        let stacks = parser.feed(b"  t0: 1: 16 [0: 0]\n@ 0x3\n  t*: 2: 32 [0: 0]").unwrap();
+        assert_eq!(1, stacks.len());
+        assert_eq!(2, stacks[0].threads.len());
+
+        let stacks = parser.finish().unwrap();
+        assert_eq!(vec![vec![3]], addrs(&stacks));
+        assert_eq!(32, stacks[0].threads[0].insuse_space);
+    }
+
+    #[test]
+    fn test_stream_parser_errors() {
+        let mut parser = StreamParser::new();
+        assert!(parser.feed(b"heap_v1/1\n").unwrap().is_empty());
+        assert_eq!(Err(ParseError::UnsupportedFormat), parser.finish().map(|stacks| stacks.len()));
+
+        let mut parser = StreamParser::new();
+        assert_eq!(Err(ParseError::UnsupportedFormat), parser.feed(b"a\nb\nc\nd\ne\n").map(|stacks| stacks.len()));
+
+        let mut parser = StreamParser::new();
+        parser.feed(b"heap_v2/1\n  t*: 1: 16 [0: 0]\n@ 0x1\n  t*: x\n").unwrap();
+        assert_eq!(Err(ParseError::Malformed { line: 4 }), parser.finish().map(|stacks| stacks.len()));
+    }
+
+    #[test]
+    fn test_stream_parser_limits() {
+        let limited = |max_input_bytes, max_stacks| {
+            StreamParser::with_options(ParseOptions { max_input_bytes, max_stacks, ..Default::default() })
+        };
+
+        let mut parser = limited(Some(PROFILE.len()), Some(2));
+        assert_eq!(2, parser.feed(PROFILE.as_bytes()).unwrap().len());
+        assert!(parser.finish().unwrap().is_empty());
+
+        let mut parser = limited(Some(PROFILE.len()), None);
+        parser.feed(PROFILE.as_bytes()).unwrap();
+        let limit = ParseError::LimitExceeded { option: "max_input_bytes", max: PROFILE.len() };
+        assert_eq!(Err(limit), parser.feed(b"\n").map(|stacks| stacks.len()));
+
+        let mut parser = limited(None, Some(1));
+        let limit = ParseError::LimitExceeded { option: "max_stacks", max: 1 };
+        assert_eq!(Err(limit), parser.feed(PROFILE.as_bytes()).map(|stacks| stacks.len()));
+    }
+}
@@ -0,0 +1,959 @@
+//! Resolving profile addresses to function names.
+//!
+//! Addresses are attributed to the mapped library containing them, the library
+//! is opened from disk, and the address is translated back to the virtual
+//! address the file was linked at before looking it up in the DWARF line and
+//! function info. Libraries without DWARF fall back to their symbol table.
+
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use addr2line::object::{self, Object, ObjectSegment};
+use addr2line::ObjectContext;
+
+use crate::folded::{folded_value, TRUNCATED_FRAME};
+use crate::{FilterOptions, MappedLibrary, Metric, Profile, RangeIndex, Stack};
+
+/// Knobs for [`Profile::symbolize_with`], configured builder style.
+///
+/// ```
+/// use jeprof_rs::SymbolizeOptions;
+///
+/// let options = SymbolizeOptions::default().demangle(true).inline_frames(true);
+/// ```
+#[derive(Debug, Clone)]
+pub struct SymbolizeOptions {
+    demangle: bool,
+    inline_frames: bool,
+    path_remaps: Vec<(PathBuf, PathBuf)>,
+    debuginfod: bool,
+}
+
+impl Default for SymbolizeOptions {
+    fn default() -> Self {
+        SymbolizeOptions {
+            demangle: true,
+            inline_frames: false,
+            path_remaps: Vec::new(),
+            debuginfod: false,
+        }
+    }
+}
+
+impl SymbolizeOptions {
+    /// Demangle Rust and C++ function names. On by default.
+    pub fn demangle(mut self, demangle: bool) -> Self {
+        self.demangle = demangle;
+        self
+    }
+
+    /// Report functions inlined at an address as frames of their own,
+    /// innermost first. Off by default, in which case only the function the
+    /// code was inlined into is reported.
+    pub fn inline_frames(mut self, inline_frames: bool) -> Self {
+        self.inline_frames = inline_frames;
+        self
+    }
+
+    /// Open libraries mapped from under `from` at the same relative path
+    /// under `to`, for symbolizing a dump on a different machine than the one
+    /// that produced it. Remaps are tried in the order they were added.
+    pub fn remap_path(mut self, from: impl Into<PathBuf>, to: impl Into<PathBuf>) -> Self {
+        self.path_remaps.push((from.into(), to.into()));
+        self
+    }
+
+    /// For libraries shipped without DWARF, look for separate debug info by
+    /// build id in the local debuginfod client cache
+    /// (`$DEBUGINFOD_CACHE_PATH`, or `~/.cache/debuginfod_client`) and in
+    /// `/usr/lib/debug/.build-id`. Nothing is downloaded; populate the cache
+    /// with `debuginfod-find` beforehand. Off by default.
+    pub fn debuginfod(mut self, debuginfod: bool) -> Self {
+        self.debuginfod = debuginfod;
+        self
+    }
+
+    fn is_remapped(&self, path: &str) -> bool {
+        self.path_remaps.iter().any(|(from, _)| Path::new(path).starts_with(from))
+    }
+
+    fn remap(&self, path: &str) -> PathBuf {
+        let path = Path::new(path);
+        for (from, to) in &self.path_remaps {
+            if let Ok(rest) = path.strip_prefix(from) {
+                return to.join(rest);
+            }
+        }
+
+        path.to_path_buf()
+    }
+}
+
+/// A function an address resolved to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub function: String,
+    pub file: Option<String>,
+    pub line: Option<u32>,
+}
+
+/// A [`Frame`] held by a [`SymbolizedProfile`], with its function name and
+/// file as ids into [`SymbolizedProfile::names`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InternedFrame {
+    pub function: u32,
+    pub file: Option<u32>,
+    pub line: Option<u32>,
+}
+
+/// Whether a mapped library can be symbolized, see
+/// [`Profile::symbolization_readiness`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Readiness {
+    /// The file cannot be read, or the library is marked deleted and no
+    /// path remap points elsewhere.
+    Missing,
+    /// The file is there but neither it nor a separate debug file found by
+    /// build id has DWARF. Addresses may still resolve through the symbol
+    /// table, without inlined frames, files or lines.
+    NoDebugInfo,
+    /// The file, or a separate debug file found by build id, has DWARF.
+    Ready,
+}
+
+/// An address [`Profile::symbolize`] left unresolved, rendered as hex in
+/// place of a function name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SymbolWarning {
+    /// The address as it appears in the stack.
+    pub addr: u64,
+    pub reason: WarningReason,
+    /// The path of the mapped library containing `addr`, `None` outside
+    /// every library.
+    pub library: Option<String>,
+}
+
+/// Why an address was left unresolved, see [`SymbolWarning`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum WarningReason {
+    /// The address lies outside every mapped library, as in JIT code or a
+    /// profile without a maps section.
+    NoLibrary,
+    /// The library file cannot be read, or is marked deleted and no path
+    /// remap points elsewhere.
+    LibraryMissing,
+    /// The address looked up, one byte before a return address, falls
+    /// outside the library, see [`SymbolizedProfile::out_of_range`].
+    OutOfRange,
+    /// The library was read but neither its debug info nor its symbol table
+    /// covers the address.
+    Unresolved,
+}
+
+impl fmt::Display for WarningReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WarningReason::NoLibrary => write!(f, "outside every mapped library"),
+            WarningReason::LibraryMissing => write!(f, "library missing"),
+            WarningReason::OutOfRange => write!(f, "out of the library's range"),
+            WarningReason::Unresolved => write!(f, "no symbol found"),
+        }
+    }
+}
+
+impl fmt::Display for SymbolWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.library {
+            Some(library) => write!(f, "{:#x} in {}: {}", self.addr, library, self.reason),
+            None => write!(f, "{:#x}: {}", self.addr, self.reason),
+        }
+    }
+}
+
+/// A source of symbols for [`Profile::symbolize_with_resolver`], such as a
+/// remote symbol service or a table shipped with a build, for environments
+/// where [`Symbolizer`] cannot open the libraries itself.
+pub trait SymbolResolver {
+    /// Resolves `addr` to the function containing it. `lib` is the mapped
+    /// library holding the address, `None` outside every library. Return
+    /// addresses have already been moved back one byte into the call, see
+    /// [`SymbolizedProfile::frames`].
+    fn resolve(&mut self, addr: u64, lib: Option<&MappedLibrary>) -> Option<Frame>;
+}
+
+/// Resolves addresses inside mapped libraries, caching every library it
+/// opens.
+pub struct Symbolizer {
+    options: SymbolizeOptions,
+    /// Opened libraries by path and deleted flag, `None` when unreadable.
+    objects: HashMap<(String, bool), Option<LoadedObject>>,
+}
+
+struct LoadedObject {
+    /// The file range of each loadable segment, with its file offset and
+    /// virtual address.
+    segments: RangeIndex<(u64, u64)>,
+    context: Option<ObjectContext>,
+    /// Symbol table entries sorted by address.
+    symbols: Vec<(u64, String)>,
+}
+
+impl Symbolizer {
+    pub fn new(options: SymbolizeOptions) -> Self {
+        Symbolizer {
+            options,
+            objects: HashMap::new(),
+        }
+    }
+
+    /// Resolves the runtime address `addr`, which lies inside `library`, to
+    /// its frames, innermost first. Returns nothing when `addr` is outside
+    /// the library after all, or the library cannot be read or has no
+    /// information about the address.
+    ///
+    /// A library marked deleted is only opened through a path remap: its
+    /// file is gone, and whatever now sits at the same path may be a
+    /// different build whose symbols would be wrong.
+    pub fn resolve(&mut self, addr: u64, library: &MappedLibrary) -> Vec<Frame> {
+        self.try_resolve(addr, library).unwrap_or_default()
+    }
+
+    /// Like [`Symbolizer::resolve`], saying why nothing was found.
+    fn try_resolve(&mut self, addr: u64, library: &MappedLibrary) -> Result<Vec<Frame>, WarningReason> {
+        let options = &self.options;
+        let object = self
+            .objects
+            .entry((library.path.to_string(), library.deleted))
+            .or_insert_with(|| {
+                if library.deleted && !options.is_remapped(&library.path) {
+                    return None;
+                }
+                LoadedObject::load(&options.remap(&library.path), options)
+            });
+
+        let Some(object) = object else {
+            return Err(WarningReason::LibraryMissing);
+        };
+
+        let Some(file_offset) = library.file_offset(addr) else {
+            return Err(WarningReason::OutOfRange);
+        };
+        let Some(svma) = object.svma(file_offset) else {
+            return Err(WarningReason::Unresolved);
+        };
+
+        let mut frames = object.find_frames(svma, &self.options);
+        if frames.is_empty() {
+            frames.extend(object.find_symbol(svma, &self.options));
+        }
+
+        if frames.is_empty() {
+            Err(WarningReason::Unresolved)
+        } else {
+            Ok(frames)
+        }
+    }
+}
+
+/// Resolves to the function the code at an address was inlined into, since a
+/// resolver only has room for one frame. Addresses outside every library
+/// stay unresolved.
+impl SymbolResolver for Symbolizer {
+    fn resolve(&mut self, addr: u64, lib: Option<&MappedLibrary>) -> Option<Frame> {
+        Symbolizer::resolve(self, addr, lib?).pop()
+    }
+}
+
+impl LoadedObject {
+    fn load(path: &Path, options: &SymbolizeOptions) -> Option<LoadedObject> {
+        let data = fs::read(path).ok()?;
+        let file = object::File::parse(&*data).ok()?;
+
+        let segments = RangeIndex::new(file.segments().map(|segment| {
+            let (offset, size) = segment.file_range();
+            (offset, offset.saturating_add(size), (offset, segment.address()))
+        }));
+
+        let mut symbols: Vec<(u64, String)> = file
+            .symbol_map()
+            .symbols()
+            .iter()
+            .map(|symbol| (symbol.address(), symbol.name().to_string()))
+            .collect();
+        symbols.sort_by_key(|(address, _)| *address);
+
+        let context = if file.section_by_name(".debug_info").is_some() {
+            ObjectContext::new(&file).ok()
+        } else if options.debuginfod {
+            separate_debug_context(&file)
+        } else {
+            None
+        };
+
+        Some(LoadedObject {
+            segments,
+            context,
+            symbols,
+        })
+    }
+
+    fn svma(&self, file_offset: u64) -> Option<u64> {
+        self.segments
+            .lookup(file_offset)
+            .map(|(offset, address)| file_offset - offset + address)
+    }
+
+    fn find_frames(&self, svma: u64, options: &SymbolizeOptions) -> Vec<Frame> {
+        let Some(context) = &self.context else {
+            return Vec::new();
+        };
+        let Ok(mut iter) = context.find_frames(svma).skip_all_loads() else {
+            return Vec::new();
+        };
+
+        let mut frames = Vec::new();
+        while let Ok(Some(frame)) = iter.next() {
+            let Some(function) = frame.function else {
+                continue;
+            };
+            let name = if options.demangle { function.demangle() } else { function.raw_name() };
+            let Ok(name) = name else {
+                continue;
+            };
+
+            frames.push(Frame {
+                function: name.into_owned(),
+                file: frame.location.as_ref().and_then(|location| location.file).map(str::to_string),
+                line: frame.location.as_ref().and_then(|location| location.line),
+            });
+        }
+
+        if !options.inline_frames && frames.len() > 1 {
+            frames.drain(..frames.len() - 1);
+        }
+
+        frames
+    }
+
+    fn find_symbol(&self, svma: u64, options: &SymbolizeOptions) -> Option<Frame> {
+        let index = self.symbols.partition_point(|(address, _)| *address <= svma);
+        let (_, name) = self.symbols.get(index.checked_sub(1)?)?;
+
+        let function = if options.demangle {
+            addr2line::demangle_auto(Cow::from(name.as_str()), None).into_owned()
+        } else {
+            name.clone()
+        };
+
+        Some(Frame {
+            function,
+            file: None,
+            line: None,
+        })
+    }
+}
+
+fn readiness(library: &MappedLibrary, options: &SymbolizeOptions) -> Readiness {
+    if library.deleted && !options.is_remapped(&library.path) {
+        return Readiness::Missing;
+    }
+    let Ok(data) = fs::read(options.remap(&library.path)) else {
+        return Readiness::Missing;
+    };
+    let Ok(file) = object::File::parse(&*data) else {
+        return Readiness::NoDebugInfo;
+    };
+
+    let separate = || options.debuginfod && separate_debug_candidates(&file).iter().any(|path| path.is_file());
+    if file.section_by_name(".debug_info").is_some() || separate() {
+        Readiness::Ready
+    } else {
+        Readiness::NoDebugInfo
+    }
+}
+
+fn separate_debug_context(file: &object::File) -> Option<ObjectContext> {
+    separate_debug_candidates(file).iter().find_map(|path| {
+        let data = fs::read(path).ok()?;
+        let debug_file = object::File::parse(&*data).ok()?;
+        ObjectContext::new(&debug_file).ok()
+    })
+}
+
+/// Where a separate debug file for `file` may be found by its build id, in
+/// the order they are tried.
+fn separate_debug_candidates(file: &object::File) -> Vec<PathBuf> {
+    let Some(build_id) = file.build_id().ok().flatten() else {
+        return Vec::new();
+    };
+    let hex: String = build_id.iter().map(|byte| format!("{:02x}", byte)).collect();
+    if hex.len() < 3 {
+        return Vec::new();
+    }
+
+    let mut candidates = Vec::new();
+    if let Some(cache) = env::var_os("DEBUGINFOD_CACHE_PATH") {
+        candidates.push(PathBuf::from(cache));
+    } else if let Some(cache) = env::var_os("XDG_CACHE_HOME") {
+        candidates.push(PathBuf::from(cache).join("debuginfod_client"));
+    } else if let Some(home) = env::var_os("HOME") {
+        candidates.push(PathBuf::from(home).join(".cache").join("debuginfod_client"));
+    }
+    let mut candidates: Vec<PathBuf> = candidates.into_iter().map(|cache| cache.join(&hex).join("debuginfo")).collect();
+    candidates.push(Path::new("/usr/lib/debug/.build-id").join(&hex[..2]).join(format!("{}.debug", &hex[2..])));
+    candidates
+}
+
+/// A profile together with the frames its addresses resolved to.
+///
+/// The same function shows up in many stacks, so every distinct function
+/// name and file path is stored once in [`SymbolizedProfile::names`] and
+/// frames refer to it by id. Exporters building their own string tables can
+/// use the ids directly.
+pub struct SymbolizedProfile<'p, 'a> {
+    profile: &'p Profile<'a>,
+    names: Vec<String>,
+    frames: HashMap<u64, Vec<InternedFrame>>,
+    out_of_range: Vec<u64>,
+}
+
+impl<'p, 'a> SymbolizedProfile<'p, 'a> {
+    pub fn profile(&self) -> &'p Profile<'a> {
+        self.profile
+    }
+
+    /// Every distinct function name and file path, indexed by the ids in
+    /// [`InternedFrame`].
+    pub fn names(&self) -> &[String] {
+        &self.names
+    }
+
+    /// The string interned as `id`.
+    pub fn name(&self, id: u32) -> &str {
+        &self.names[id as usize]
+    }
+
+    /// Return addresses that were left unresolved because the address looked
+    /// up for them, one byte before the return address, falls outside the
+    /// library containing it. This happens when a return address sits at the
+    /// very start of a mapping, and usually means the maps section and the
+    /// stacks do not quite agree.
+    pub fn out_of_range(&self) -> &[u64] {
+        &self.out_of_range
+    }
+
+    /// The frames each address of `stack` resolved to, leaf to root. An
+    /// unresolved address yields an empty slice.
+    pub fn frames(&self, stack: &Stack) -> Vec<&[InternedFrame]> {
+        stack
+            .addrs
+            .iter()
+            .enumerate()
+            .map(|(i, addr)| self.frames.get(&probe(i, *addr)).map_or(&[][..], Vec::as_slice))
+            .collect()
+    }
+
+    /// Every address looked up, with the frames it resolved to.
+    pub(crate) fn resolved(&self) -> impl Iterator<Item = (u64, &[InternedFrame])> {
+        self.frames.iter().map(|(addr, frames)| (*addr, frames.as_slice()))
+    }
+
+    /// The function containing `addr`, leaving out any frames inlined into
+    /// it. `leaf` says whether `addr` is the allocation site rather than a
+    /// return address.
+    pub(crate) fn function_at(&self, addr: u64, leaf: bool) -> Option<&str> {
+        let frames = self.frames.get(&probe(if leaf { 0 } else { 1 }, addr))?;
+        frames.last().map(|frame| self.name(frame.function))
+    }
+
+    /// A name for every frame of `stack`, leaf to root, with inlined frames
+    /// expanded innermost first. Unresolved addresses are rendered as
+    /// `0x`-prefixed hex.
+    pub fn frame_names(&self, stack: &Stack) -> Vec<Cow<'_, str>> {
+        let mut names = Vec::new();
+        for (addr, frames) in stack.addrs.iter().zip(self.frames(stack)) {
+            if frames.is_empty() {
+                names.push(Cow::Owned(format!("{:#x}", addr)));
+            } else {
+                names.extend(frames.iter().map(|frame| Cow::Borrowed(self.name(frame.function))));
+            }
+        }
+
+        names
+    }
+
+    /// Like [`Profile::write_folded`], with function names instead of
+    /// addresses as frames. Lines come in the same order.
+    pub fn write_folded<W: Write>(&self, w: &mut W, metric: Metric) -> io::Result<()> {
+        self.write_folded_filtered(w, metric, &FilterOptions::default())
+    }
+
+    /// Like [`SymbolizedProfile::write_folded`], trimming and correcting
+    /// every stack as `filter` says first. Frames are counted before inlined frames are
+    /// expanded.
+    pub fn write_folded_filtered<W: Write>(&self, w: &mut W, metric: Metric, filter: &FilterOptions) -> io::Result<()> {
+        for (stack, metrics) in self.profile.export_order(metric, filter.values) {
+            let Some(value) = folded_value(Some(metrics), metric) else {
+                continue;
+            };
+
+            let (addrs, truncated) = filter.frames(stack);
+            let kept = Stack {
+                addrs: addrs.to_vec(),
+                threads: Vec::new(),
+            };
+            let mut names = self.frame_names(&kept);
+            if truncated {
+                names.push(Cow::Borrowed(TRUNCATED_FRAME));
+            }
+            names.reverse();
+            writeln!(w, "{} {}", names.join(";"), value)?;
+        }
+
+        Ok(())
+    }
+
+    /// Collects [`SymbolizedProfile::write_folded`] into a `String`.
+    pub fn to_folded(&self, metric: Metric) -> String {
+        self.to_folded_filtered(metric, &FilterOptions::default())
+    }
+
+    /// Collects [`SymbolizedProfile::write_folded_filtered`] into a
+    /// `String`.
+    pub fn to_folded_filtered(&self, metric: Metric, filter: &FilterOptions) -> String {
+        let mut out = Vec::new();
+        self.write_folded_filtered(&mut out, metric, filter).expect("writing to a Vec cannot fail");
+        String::from_utf8_lossy(&out).into_owned()
+    }
+}
+
+impl<'a> Profile<'a> {
+    /// Symbolizes every address with [`SymbolizeOptions::default`].
+    pub fn symbolize(&self) -> (SymbolizedProfile<'_, 'a>, Vec<SymbolWarning>) {
+        self.symbolize_with(SymbolizeOptions::default())
+    }
+
+    /// Symbolizes every address of every stack, opening each mapped library
+    /// at most once.
+    ///
+    /// Addresses that do not resolve are rendered as hex as usual, and each
+    /// distinct one is also reported with a [`SymbolWarning`] saying why, so
+    /// that gaps such as a missing library can be surfaced. Warnings come in
+    /// the order the addresses were looked up, those out of range last.
+    pub fn symbolize_with(&self, options: SymbolizeOptions) -> (SymbolizedProfile<'_, 'a>, Vec<SymbolWarning>) {
+        let mut symbolizer = Symbolizer::new(options);
+        let mut warnings = Vec::new();
+        let symbolized = self.symbolize_by(|addr, probe, library| {
+            let resolved = match library {
+                Some(library) => symbolizer.try_resolve(probe, library),
+                None => Err(WarningReason::NoLibrary),
+            };
+            resolved.unwrap_or_else(|reason| {
+                warnings.push(SymbolWarning {
+                    addr,
+                    reason,
+                    library: library.map(|library| library.path.to_string()),
+                });
+                Vec::new()
+            })
+        });
+
+        warnings.extend(symbolized.out_of_range.iter().map(|addr| SymbolWarning {
+            addr: *addr,
+            reason: WarningReason::OutOfRange,
+            library: self.library_for_addr(*addr).map(|library| library.path.to_string()),
+        }));
+
+        (symbolized, warnings)
+    }
+
+    /// Symbolizes every address of every stack through `resolver`, asking
+    /// it about each distinct address once. Addresses outside every library
+    /// are offered too, with no library, while those that fall out of their
+    /// library's range are reported in [`SymbolizedProfile::out_of_range`]
+    /// without asking.
+    pub fn symbolize_with_resolver(&self, resolver: &mut dyn SymbolResolver) -> SymbolizedProfile<'_, 'a> {
+        self.symbolize_by(|_, probe, library| resolver.resolve(probe, library).into_iter().collect())
+    }
+
+    /// Resolves each distinct probe address of the profile with `resolve`,
+    /// given the stack address it was derived from and the library
+    /// containing it.
+    fn symbolize_by(
+        &self,
+        mut resolve: impl FnMut(u64, u64, Option<&MappedLibrary>) -> Vec<Frame>,
+    ) -> SymbolizedProfile<'_, 'a> {
+        let mut interner = Interner::default();
+        let mut frames = HashMap::new();
+        let mut out_of_range = Vec::new();
+        let libraries = self.library_ranges();
+
+        for stack in &self.stacks {
+            for (i, addr) in stack.addrs.iter().enumerate() {
+                let probe = probe(i, *addr);
+                if frames.contains_key(&probe) {
+                    continue;
+                }
+
+                let resolved = match self.library_in(&libraries, *addr) {
+                    Some(library) if library.file_offset(probe).is_none() => {
+                        out_of_range.push(*addr);
+                        Vec::new()
+                    }
+                    library => resolve(*addr, probe, library),
+                };
+                let interned = resolved
+                    .into_iter()
+                    .map(|frame| InternedFrame {
+                        function: interner.intern(frame.function),
+                        file: frame.file.map(|file| interner.intern(file)),
+                        line: frame.line,
+                    })
+                    .collect();
+                frames.insert(probe, interned);
+            }
+        }
+
+        SymbolizedProfile {
+            profile: self,
+            names: interner.names,
+            frames,
+            out_of_range,
+        }
+    }
+
+    /// Checks every mapped library, in order, for what symbolizing would
+    /// find: whether its file is on disk and whether it has debug info,
+    /// either itself or in a separate debug file located by build id in the
+    /// places [`SymbolizeOptions::debuginfod`] searches. Each distinct file
+    /// is read once.
+    ///
+    /// This reads the files but does not parse their DWARF, so it is much
+    /// cheaper than [`Profile::symbolize`] and suits warning about the
+    /// libraries that will not symbolize before starting.
+    pub fn symbolization_readiness(&self) -> Vec<(&MappedLibrary<'a>, Readiness)> {
+        self.symbolization_readiness_with(&SymbolizeOptions::default().debuginfod(true))
+    }
+
+    /// Like [`Profile::symbolization_readiness`], applying the path remaps
+    /// of `options` and only looking for separate debug files if it enables
+    /// [`SymbolizeOptions::debuginfod`], as [`Profile::symbolize_with`]
+    /// would.
+    pub fn symbolization_readiness_with(&self, options: &SymbolizeOptions) -> Vec<(&MappedLibrary<'a>, Readiness)> {
+        let mut seen: HashMap<(&str, bool), Readiness> = HashMap::new();
+        self.mapped_libraries
+            .iter()
+            .map(|library| {
+                let readiness = *seen
+                    .entry((&library.path, library.deleted))
+                    .or_insert_with(|| readiness(library, options));
+                (library, readiness)
+            })
+            .collect()
+    }
+
+    /// Resolves the frames of one stack at a time, as the iterator is
+    /// advanced, instead of symbolizing the whole profile up front.
+    /// Libraries opened by `symbolizer` stay cached across stacks and
+    /// across calls.
+    ///
+    /// Each stack comes with a name per frame as in
+    /// [`SymbolizedProfile::frame_names`]. The names are owned, since the
+    /// symbolizer stays borrowed while the iterator lives, so only those of
+    /// the current stack are held; an address shared by many stacks is
+    /// resolved again for each.
+    pub fn symbolized_frames<'s>(
+        &'s self,
+        symbolizer: &'s mut Symbolizer,
+    ) -> impl Iterator<Item = (&'s Stack<'a>, Vec<String>)> + 's {
+        let libraries = self.library_ranges();
+        self.stacks.iter().map(move |stack| {
+            let mut names = Vec::new();
+            for (i, addr) in stack.addrs.iter().enumerate() {
+                let frames = match self.library_in(&libraries, *addr) {
+                    Some(library) => symbolizer.resolve(probe(i, *addr), library),
+                    None => Vec::new(),
+                };
+
+                if frames.is_empty() {
+                    names.push(format!("{:#x}", addr));
+                } else {
+                    names.extend(frames.into_iter().map(|frame| frame.function));
+                }
+            }
+
+            (stack, names)
+        })
+    }
+
+    /// Symbolizes every address from a symbol table produced elsewhere, such
+    /// as one shipped alongside a stripped binary, without opening any
+    /// library. `map` holds the start address of every function, and an
+    /// address resolves to the function with the greatest start at or below
+    /// it. Addresses below every start stay unresolved.
+    pub fn apply_symbol_map(&self, map: &BTreeMap<u64, String>) -> SymbolizedProfile<'_, 'a> {
+        let mut interner = Interner::default();
+        let mut frames = HashMap::new();
+
+        for stack in &self.stacks {
+            for (i, addr) in stack.addrs.iter().enumerate() {
+                let probe = probe(i, *addr);
+                frames.entry(probe).or_insert_with(|| match map.range(..=probe).next_back() {
+                    Some((_, name)) => vec![InternedFrame {
+                        function: interner.intern(name.clone()),
+                        file: None,
+                        line: None,
+                    }],
+                    None => Vec::new(),
+                });
+            }
+        }
+
+        SymbolizedProfile {
+            profile: self,
+            names: interner.names,
+            frames,
+            out_of_range: Vec::new(),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Interner {
+    names: Vec<String>,
+    ids: HashMap<String, u32>,
+}
+
+impl Interner {
+    fn intern(&mut self, name: String) -> u32 {
+        if let Some(id) = self.ids.get(&name) {
+            return *id;
+        }
+
+        let id = self.names.len() as u32;
+        self.names.push(name.clone());
+        self.ids.insert(name, id);
+        id
+    }
+}
+
+/// The address to look up for the `index`-th frame of a stack.
+///
+/// Every frame but the leaf holds a return address, which points just past
+/// the call instruction and may already belong to the next line or function,
+/// so jeprof looks those up one byte earlier. We do the same.
+fn probe(index: usize, addr: u64) -> u64 {
+    if index == 0 {
+        addr
+    } else {
+        addr.wrapping_sub(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::folded::basename;
+    use crate::parser::parse_mapped_library;
+
+    #[inline(never)]
+    fn symbolize_marker() -> usize {
+        symbolize_marker as *const () as usize
+    }
+
+    #[test]
+    fn test_symbolize_own_executable() {
+        let exe = env::current_exe().unwrap();
+        let maps = fs::read_to_string("/proc/self/maps").unwrap();
+        let libraries: Vec<&str> = maps
+            .lines()
+            .filter(|line| parse_mapped_library(line).is_ok_and(|(_, lib)| Path::new(lib.path.as_ref()) == exe))
+            .collect();
+        assert!(!libraries.is_empty());
+
+        let addr = symbolize_marker();
+        let data = format!(
+            "heap_v2/524288\n  t*: 1: 16 [0: 0]\n@ {:#x} 0x1\n  t*: 1: 16 [0: 0]\n\nMAPPED_LIBRARIES:\n{}\n",
+            addr,
+            libraries.join("\n")
+        );
+        let profile = Profile::parse(&data).unwrap();
+        let (symbolized, warnings) = profile.symbolize();
+        assert_eq!(vec![SymbolWarning { addr: 0x1, reason: WarningReason::NoLibrary, library: None }], warnings);
+
+        let frames = symbolized.frames(&profile.stacks[0]);
+        assert_eq!(2, frames.len());
+        assert!(symbolized.name(frames[0][0].function).ends_with("symbolize_marker"), "{:?}", frames[0]);
+        assert!(frames[0][0].file.is_some_and(|file| symbolized.name(file).ends_with("symbolize.rs")));
+        assert_eq!(2, symbolized.names().len());
+        assert!(frames[1].is_empty());
+
+        let folded = symbolized.to_folded(Metric::InuseSpace);
+        assert!(folded.starts_with("0x1;"), "{}", folded);
+        assert!(folded.ends_with("symbolize_marker 16\n"), "{}", folded);
+
+        let callgrind = symbolized.to_callgrind(Metric::InuseSpace);
+        assert!(callgrind.contains("\nfn=0x1\n0 0\ncfn="), "{}", callgrind);
+        assert!(callgrind.contains("symbolize_marker\n0 16\n"), "{}", callgrind);
+
+        let mut symbolizer = Symbolizer::new(SymbolizeOptions::default());
+        let lazy: Vec<(&Stack, Vec<String>)> = profile.symbolized_frames(&mut symbolizer).collect();
+        assert_eq!(1, lazy.len());
+        assert_eq!(symbolized.frame_names(&profile.stacks[0]), lazy[0].1);
+
+        let (raw, _) = profile.symbolize_with(SymbolizeOptions::default().demangle(false));
+        let frames = raw.frames(&profile.stacks[0]);
+        assert!(raw.name(frames[0][0].function).starts_with("_ZN"), "{:?}", frames[0]);
+    }
+
+    #[test]
+    fn test_symbolization_readiness() {
+        let exe = env::current_exe().unwrap();
+        let manifest = concat!(env!("CARGO_MANIFEST_DIR"), "/Cargo.toml");
+        let data = format!(
+            "heap_v2/524288
+  t*: 1: 16 [0: 0]
+@ 0x1010
+  t*: 1: 16 [0: 0]
+
+MAPPED_LIBRARIES:
+00001000-00002000 r-xp 00000000 103:02 5000 {exe}
+00002000-00003000 r-xp 00000000 103:02 5001 /nonexistent/libfoo.so
+00003000-00004000 r--p 00000000 103:02 5002 {manifest}
+00004000-00005000 r-xp 00000000 103:02 5000 {exe} (deleted)
+",
+            exe = exe.display()
+        );
+        let profile = Profile::parse(&data).unwrap();
+
+        let readiness: Vec<Readiness> = profile.symbolization_readiness().into_iter().map(|(_, r)| r).collect();
+        assert_eq!(
+            vec![Readiness::Ready, Readiness::Missing, Readiness::NoDebugInfo, Readiness::Missing],
+            readiness
+        );
+    }
+
+    #[test]
+    fn test_symbolize_out_of_range() {
+        let data = "heap_v2/524288
+  t*: 1: 16 [0: 0]
+@ 0x1010 0x2000
+  t*: 1: 16 [0: 0]
+
+MAPPED_LIBRARIES:
+00002000-00003000 r-xp 00000000 103:02 5000 /nonexistent/libfoo.so
+";
+        let profile = Profile::parse(data).unwrap();
+        let (symbolized, warnings) = profile.symbolize();
+        assert_eq!(&[0x2000], symbolized.out_of_range());
+        assert!(symbolized.frames(&profile.stacks[0])[1].is_empty());
+
+        let libfoo = Some("/nonexistent/libfoo.so".to_string());
+        assert_eq!(
+            vec![
+                SymbolWarning { addr: 0x1010, reason: WarningReason::NoLibrary, library: None },
+                SymbolWarning { addr: 0x2000, reason: WarningReason::OutOfRange, library: libfoo },
+            ],
+            warnings
+        );
+        assert_eq!("0x2000 in /nonexistent/libfoo.so: out of the library's range", warnings[1].to_string());
+    }
+
+    #[test]
+    fn test_symbolize_warnings_missing_library() {
+        let data = "heap_v2/524288
+  t*: 2: 32 [0: 0]
+@ 0x2010 0x2020
+  t*: 1: 16 [0: 0]
+@ 0x2010
+  t*: 1: 16 [0: 0]
+
+MAPPED_LIBRARIES:
+00002000-00003000 r-xp 00000000 103:02 5000 /nonexistent/libfoo.so
+";
+        let profile = Profile::parse(data).unwrap();
+        let (symbolized, warnings) = profile.symbolize();
+        assert_eq!(vec!["0x2010", "0x2020"], symbolized.frame_names(&profile.stacks[0]));
+
+        let addrs: Vec<u64> = warnings.iter().map(|warning| warning.addr).collect();
+        assert_eq!(vec![0x2010, 0x2020], addrs);
+        assert!(warnings.iter().all(|warning| warning.reason == WarningReason::LibraryMissing
+            && warning.library.as_deref() == Some("/nonexistent/libfoo.so")));
+    }
+
+    #[test]
+    fn test_symbolize_with_resolver() {
+        struct Service(Vec<(u64, Option<String>)>);
+
+        impl SymbolResolver for Service {
+            fn resolve(&mut self, addr: u64, lib: Option<&MappedLibrary>) -> Option<Frame> {
+                self.0.push((addr, lib.map(|lib| lib.path.to_string())));
+                let function = match lib {
+                    Some(lib) => format!("{}+{:#x}", basename(&lib.path), lib.file_offset(addr)?),
+                    None if addr == 0x10 => return None,
+                    None => "jit".to_string(),
+                };
+                Some(Frame { function, file: None, line: None })
+            }
+        }
+
+        let data = "heap_v2/524288
+  t*: 2: 32 [0: 0]
+@ 0x2010 0x9000 0x2000
+  t*: 1: 16 [0: 0]
+@ 0x10 0x2011
+  t*: 1: 16 [0: 0]
+
+MAPPED_LIBRARIES:
+00002000-00003000 r-xp 00000000 103:02 5000 /usr/lib/libfoo.so
+";
+        let profile = Profile::parse(data).unwrap();
+        let mut service = Service(Vec::new());
+        let symbolized = profile.symbolize_with_resolver(&mut service);
+
+        let libfoo = Some("/usr/lib/libfoo.so".to_string());
+        assert_eq!(vec![(0x2010, libfoo.clone()), (0x8fff, None), (0x10, None)], service.0);
+        assert_eq!(vec!["libfoo.so+0x10", "jit", "0x2000"], symbolized.frame_names(&profile.stacks[0]));
+        assert_eq!(vec!["0x10", "libfoo.so+0x10"], symbolized.frame_names(&profile.stacks[1]));
+        assert_eq!(&[0x2000], symbolized.out_of_range());
+    }
+
+    #[test]
+    fn test_apply_symbol_map() {
+        let data = "heap_v2/524288
+  t*: 1: 16 [0: 0]
+@ 0x1010 0x2000 0x10
+  t*: 1: 16 [0: 0]
+";
+        let profile = Profile::parse(data).unwrap();
+        let map = BTreeMap::from([(0x1000, "malloc".to_string()), (0x2000, "main".to_string())]);
+        let symbolized = profile.apply_symbol_map(&map);
+
+        // 0x2000 is a return address, so the call was made from 0x1fff.
+        let names = symbolized.frame_names(&profile.stacks[0]);
+        assert_eq!(vec!["malloc", "malloc", "0x10"], names);
+        assert_eq!(1, symbolized.names().len());
+    }
+
+    #[test]
+    fn test_symbolize_options() {
+        let options = SymbolizeOptions::default();
+        assert!(options.demangle);
+        assert!(!options.inline_frames);
+        assert!(!options.debuginfod);
+
+        let options = options
+            .inline_frames(true)
+            .remap_path("/app", "/home/me/build")
+            .remap_path("/", "/sysroot");
+        assert!(options.inline_frames);
+        assert_eq!(PathBuf::from("/home/me/build/bin/server"), options.remap("/app/bin/server"));
+        assert_eq!(PathBuf::from("/sysroot/usr/lib/libc.so.6"), options.remap("/usr/lib/libc.so.6"));
+    }
+}
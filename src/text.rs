@@ -0,0 +1,295 @@
+//! The ranked flat listing printed by `jeprof --text`, and the annotated
+//! source printed by `jeprof --list`.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::Write;
+use std::fs;
+
+use crate::callgrind::LibraryOffsetNamer;
+use crate::{Metric, Metrics, Profile, SymbolizedProfile, ValueMode};
+
+impl<'a> Profile<'a> {
+    /// Lists the `n` functions with the largest flat `metric`, in the
+    /// columns of `jeprof --text`, flat, flat%, sum%, cum and cum%, followed
+    /// by the average allocation size and the function name.
+    ///
+    /// Flat is the `t*` value of the stacks allocating directly in a
+    /// function, cum that of every stack passing through it, counting a
+    /// recursive stack once. Percentages are of the sum over all stacks, and
+    /// sum% is the running total of flat%. The average is the flat
+    /// `inuse_space` over the flat `inuse_count` whatever `metric` is, or
+    /// `.` where the function holds no live objects directly, and tells one
+    /// large allocation from many small ones. The header line gives the
+    /// total, the metric and whether `values` are sampled or unsampled.
+    /// Functions are named as in [`Profile::to_callgrind`]; use
+    /// [`SymbolizedProfile::to_text_report`] for symbols.
+    pub fn to_text_report(&self, metric: Metric, n: usize, values: ValueMode) -> String {
+        let namer = LibraryOffsetNamer::new(self);
+        text_report(self, metric, n, values, |addr, _| namer.name(addr))
+    }
+}
+
+impl<'p, 'a> SymbolizedProfile<'p, 'a> {
+    /// Like [`Profile::to_text_report`], naming functions by symbol where the
+    /// address resolved and by `library+offset` otherwise.
+    pub fn to_text_report(&self, metric: Metric, n: usize, values: ValueMode) -> String {
+        let profile = self.profile();
+        let namer = LibraryOffsetNamer::new(profile);
+        text_report(profile, metric, n, values, |addr, leaf| match self.function_at(addr, leaf) {
+            Some(function) => function.to_string(),
+            None => namer.name(addr),
+        })
+    }
+
+    /// Prints the source of `func` with each line prefixed by the sampled
+    /// `t*` `inuse_space` of the stacks passing through it, as
+    /// `jeprof --list` does, to find which line of a hot function holds the
+    /// memory.
+    ///
+    /// Lines come from the file and line the symbolizer found for each
+    /// frame named exactly `func`, inlined frames included, so this needs
+    /// DWARF. A stack passing through a line several times counts once.
+    /// Every file `func` spans gets its own block, from its first to its
+    /// last line holding memory, with `.` for lines holding none. Source
+    /// files that cannot be read are listed by line number alone. Empty
+    /// when no frame of `func` has line info.
+    pub fn list(&self, func: &str) -> String {
+        let Some(function) = self.names().iter().position(|name| name == func) else {
+            return String::new();
+        };
+
+        let profile = self.profile();
+        let mut files: BTreeMap<u32, BTreeMap<u32, u64>> = BTreeMap::new();
+        for stack in &profile.stacks {
+            let value = profile.stack_values(stack, ValueMode::Sampled).map_or(0, |metrics| metrics.inuse_space);
+            if value == 0 {
+                continue;
+            }
+
+            let mut seen = HashSet::new();
+            for frame in self.frames(stack).into_iter().flatten() {
+                let (Some(file), Some(line)) = (frame.file, frame.line) else {
+                    continue;
+                };
+                if frame.function as usize != function || !seen.insert((file, line)) {
+                    continue;
+                }
+
+                let bytes = files.entry(file).or_default().entry(line).or_default();
+                *bytes = bytes.saturating_add(value);
+            }
+        }
+
+        let mut out = String::new();
+        for (file, lines) in files {
+            let path = self.name(file);
+            let source = fs::read_to_string(path).ok();
+            let source: Vec<&str> = source.as_deref().map_or_else(Vec::new, |source| source.lines().collect());
+            let (Some(first), Some(last)) = (lines.keys().next(), lines.keys().next_back()) else {
+                continue;
+            };
+
+            let _ = writeln!(out, "ROUTINE ======================== {} in {}", func, path);
+            for line in *first..=*last {
+                let bytes = lines.get(&line).map_or_else(|| ".".to_string(), u64::to_string);
+                let text = (line as usize).checked_sub(1).and_then(|i| source.get(i)).copied().unwrap_or("");
+                let _ = writeln!(out, "{}", format!("{:>10} {:>6}: {}", bytes, line, text).trim_end());
+            }
+        }
+
+        out
+    }
+}
+
+fn text_report(
+    profile: &Profile,
+    metric: Metric,
+    n: usize,
+    values: ValueMode,
+    name: impl Fn(u64, bool) -> String,
+) -> String {
+    let mut functions: HashMap<String, (u64, u64, Metrics)> = HashMap::new();
+    let mut total = 0u64;
+
+    for stack in &profile.stacks {
+        let metrics = profile.stack_values(stack, values).unwrap_or_default();
+        let value = metrics.get(metric);
+        if value == 0 {
+            continue;
+        }
+        total = total.saturating_add(value);
+
+        let mut seen = HashSet::new();
+        for (i, addr) in stack.addrs.iter().enumerate() {
+            let name = name(*addr, i == 0);
+            if !seen.insert(name.clone()) {
+                continue;
+            }
+
+            let function = functions.entry(name).or_default();
+            if i == 0 {
+                function.0 = function.0.saturating_add(value);
+                function.2 = function.2 + metrics;
+            }
+            function.1 = function.1.saturating_add(value);
+        }
+    }
+
+    let mut functions: Vec<(String, (u64, u64, Metrics))> = functions.into_iter().collect();
+    functions.sort_by(|(a_name, a), (b_name, b)| (b.0, b.1).cmp(&(a.0, a.1)).then_with(|| a_name.cmp(b_name)));
+
+    let percent = |value: u64| if total == 0 { 0.0 } else { value as f64 * 100.0 / total as f64 };
+    let mut out = format!("Total: {} {} ({})\n", total, metric, values.name());
+    let mut sum = 0u64;
+    for (name, (flat, cum, flat_metrics)) in functions.into_iter().take(n) {
+        sum = sum.saturating_add(flat);
+        let avg = flat_metrics.avg_alloc_size().map_or_else(|| ".".to_string(), |avg| avg.to_string());
+        let _ = writeln!(
+            out,
+            "{:>10} {:>5.1}% {:>5.1}% {:>10} {:>5.1}% {:>10} {}",
+            flat,
+            percent(flat),
+            percent(sum),
+            cum,
+            percent(cum),
+            avg,
+            name
+        );
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{Frame, MappedLibrary, SymbolResolver};
+
+    #[test]
+    fn test_to_text_report() {
+        let data = "heap_v2/524288
+  t*: 4: 64 [0: 0]
+@ 0x1010 0x2010 0x9000
+  t*: 1: 16 [0: 0]
+@ 0x1020 0x2010 0x9000
+  t*: 2: 32 [0: 0]
+@ 0x2010 0x2010 0x9000
+  t*: 1: 16 [0: 0]
+
+MAPPED_LIBRARIES:
+00001000-00002000 r-xp 00000000 103:02 5000 /usr/lib/liba.so
+00002000-00003000 r-xp 00004000 103:02 5001 /usr/bin/server
+";
+        let profile = Profile::parse(data).unwrap();
+
+        assert_eq!(
+            "Total: 64 inuse_space (sampled)
+        32  50.0%  50.0%         32  50.0%         16 liba.so+0x20
+        16  25.0%  75.0%         64 100.0%         16 server+0x4010
+        16  25.0% 100.0%         16  25.0%         16 liba.so+0x10
+         0   0.0% 100.0%         64 100.0%          . 0x9000
+",
+            profile.to_text_report(Metric::InuseSpace, 4, ValueMode::Sampled)
+        );
+        assert_eq!(2, profile.to_text_report(Metric::InuseSpace, 1, ValueMode::Sampled).lines().count());
+
+        let unsampled = profile.to_text_report(Metric::InuseSpace, 1, ValueMode::Unsampled);
+        assert_eq!("Total: 2097184 inuse_space (unsampled)", unsampled.lines().next().unwrap());
+    }
+
+    #[test]
+    fn test_to_text_report_avg_alloc_size() {
+        struct Names;
+
+        impl SymbolResolver for Names {
+            fn resolve(&mut self, addr: u64, _: Option<&MappedLibrary>) -> Option<Frame> {
+                let function = if addr < 0x8 { "alloc" } else { "main" };
+                Some(Frame { function: function.to_string(), file: None, line: None })
+            }
+        }
+
+        let data = "heap_v2/524288
+  t*: 65: 8192 [0: 0]
+@ 0x1 0x9
+  t*: 1: 4096 [0: 0]
+@ 0x2 0x9
+  t*: 64: 4096 [0: 0]
+";
+        let profile = Profile::parse(data).unwrap();
+
+        assert_eq!(
+            "Total: 8192 inuse_space (sampled)
+      4096  50.0%  50.0%       4096  50.0%       4096 0x1
+      4096  50.0% 100.0%       4096  50.0%         64 0x2
+         0   0.0% 100.0%       8192 100.0%          . 0x9
+",
+            profile.to_text_report(Metric::InuseSpace, 3, ValueMode::Sampled)
+        );
+        assert!(profile.to_text_report(Metric::InuseCount, 1, ValueMode::Sampled).ends_with(" 64 0x2\n"));
+
+        let symbolized = profile.symbolize_with_resolver(&mut Names);
+        assert_eq!(
+            "Total: 8192 inuse_space (sampled)
+      8192 100.0% 100.0%       8192 100.0%        126 alloc
+         0   0.0% 100.0%       8192 100.0%          . main
+",
+            symbolized.to_text_report(Metric::InuseSpace, 2, ValueMode::Sampled)
+        );
+    }
+
+    #[test]
+    fn test_to_text_report_saturates() {
+        let data = "heap_v2/524288
+  t*: 2: 18446744073709551615 [0: 0]
+@ 0x1
+  t*: 1: 18446744073709551615 [0: 0]
+@ 0x2
+  t*: 1: 18446744073709551615 [0: 0]
+";
+        let report = Profile::parse(data).unwrap().to_text_report(Metric::InuseSpace, 2, ValueMode::Sampled);
+        assert_eq!(3, report.lines().count());
+        assert!(report.starts_with("Total: 18446744073709551615 inuse_space"), "{}", report);
+    }
+
+    #[test]
+    fn test_list() {
+        struct Lines;
+
+        impl SymbolResolver for Lines {
+            fn resolve(&mut self, addr: u64, _: Option<&MappedLibrary>) -> Option<Frame> {
+                let (function, line) = match addr {
+                    0x1010 => ("alloc", 1),
+                    0x100f => ("alloc", 3),
+                    _ => ("main", 2),
+                };
+                let file = concat!(env!("CARGO_MANIFEST_DIR"), "/Cargo.toml").to_string();
+                Some(Frame { function: function.to_string(), file: Some(file), line: Some(line) })
+            }
+        }
+
+        let data = "heap_v2/524288
+  t*: 3: 48 [0: 0]
+@ 0x1010 0x2000
+  t*: 1: 16 [0: 0]
+@ 0x1010 0x1010 0x2000
+  t*: 2: 32 [0: 0]
+";
+        let profile = Profile::parse(data).unwrap();
+        let symbolized = profile.symbolize_with_resolver(&mut Lines);
+
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/Cargo.toml");
+        assert_eq!(
+            format!(
+                "ROUTINE ======================== alloc in {}
+        48      1: [package]
+         .      2: name = \"jeprof-rs\"
+        32      3: version = \"0.1.0\"
+",
+                path
+            ),
+            symbolized.list("alloc")
+        );
+        assert_eq!("", symbolized.list("free"));
+    }
+}